@@ -1,21 +1,12 @@
 use bitvec::prelude::Lsb0;
-use bitvec::prelude::Msb0;
-use bitvec::vec::BitVec;
 use bitvec::view::AsBits;
-use indexmap::IndexMap;
 use zipng::font::Font;
 use zipng::font::Mini5pt;
-use zipng::font::FONTS;
 use zipng::generic::panic;
-use zipng::png::write_png;
 use zipng::png::BitDepth::EightBit;
-use zipng::png::BitDepth::OneBit;
 use zipng::png::ColorMode::Indexed;
-use zipng::png::ColorMode::RedGreenBlue;
 use zipng::png::PALLETTE_8_BIT_DATA;
-use zipng::zip;
-use zipng::PngOptions;
-use zipng::ZipngOptions;
+use zipng::png_with;
 
 fn main() -> Result<(), panic> {
     let mut data = Vec::new();
@@ -23,7 +14,7 @@ fn main() -> Result<(), panic> {
     let font = Mini5pt;
     let width = font.width();
     let bits = font.width() * font.height();
-    for (character, bitmap) in font.glyphs() {
+    for (_character, bitmap) in font.glyphs() {
         // if *character != 'A' {
         //     continue;
         // }
@@ -40,48 +31,21 @@ fn main() -> Result<(), panic> {
         data.extend(vec![0; width]);
     }
 
-    let mut buffer = Vec::new();
-
-    let ZipngOptions {
-        png:
-            PngOptions {
-                bit_depth,
-                color_mode,
-                color_palette,
-                // width,
-                ..
-            },
-        ..
-    } = ZipngOptions::default_for_data(&data);
-    let color_palette = color_palette.as_deref();
-
-    let bit_depth = EightBit;
-    let color_mode = Indexed;
-    let color_palette = Some(PALLETTE_8_BIT_DATA.as_slice());
-
-    // let bit_depth = EightBit;
-    // let color_mode = RedGreenBlue;
-    // let color_palette = None::<&[u8]>;
-
-    // let bit_depth = OneBit;
-    // let color_mode = Indexed;
-    // let color_palette = [0xFF_u8, 0xFF, 0xEE, 0x11, 0x11, 0x33];
-    // let color_palette = Some(&color_palette[..]);
-
-    let bits_per_pixel = bit_depth.bits_per_sample() * color_mode.samples_per_pixel();
-    let pixels = data.len() * 8 / bits_per_pixel;
-
-    let height = (pixels / width).max(1);
-
-    write_png(
-        &mut buffer,
-        &data,
-        width as u32,
-        height as u32,
-        bit_depth,
-        color_mode,
-        color_palette,
-    );
+    let buffer = png_with(&data, |opts| {
+        opts.width = Mini5pt.width();
+        opts.max_height = 0;
+        opts.bit_depth = EightBit;
+        opts.color_mode = Indexed;
+        opts.color_palette = Some(PALLETTE_8_BIT_DATA.to_vec());
+
+        // opts.bit_depth = EightBit;
+        // opts.color_mode = RedGreenBlue;
+        // opts.color_palette = None;
+
+        // opts.bit_depth = OneBit;
+        // opts.color_mode = Indexed;
+        // opts.color_palette = Some(vec![0xFF, 0xFF, 0xEE, 0x11, 0x11, 0x33]);
+    });
 
     std::fs::write("target/test.png", buffer)?;
 
@@ -1,8 +1,4 @@
-use brotli::enc::BrotliEncoderParams;
-use bstr::BStr;
-use bstr::BString;
 use bstr::ByteSlice;
-use bstr::Bytes;
 
 use crate::generic::default;
 
@@ -11,12 +7,12 @@ pub fn compress(bytes: &[u8]) -> Vec<u8> {
     let mut buffer = Vec::<u8>::new();
     brotli::BrotliCompress(&mut bytes, &mut buffer, &default())
         .expect("brotli compression must not fail");
-    buffer.into()
+    buffer
 }
 
 pub fn decompress(bytes: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let mut bytes = bytes.as_bytes();
     let mut buffer = Vec::<u8>::new();
     brotli::BrotliDecompress(&mut bytes, &mut buffer)?;
-    Ok(buffer.into())
+    Ok(buffer)
 }
@@ -1,14 +1,64 @@
 use crc::Crc;
+use crc::Digest;
+
+static CRC_32_ISO_HDLC: Crc<u32> = Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
 
 /// CRC-32 configured as per ISO 3309
 pub fn crc32(bytes: &[u8]) -> u32 {
-    const CRC_32_ISO_HDLC: Crc<u32> = Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
     let mut hasher = CRC_32_ISO_HDLC.digest();
     hasher.update(bytes);
     hasher.finalize()
 }
 
+/// A CRC-32 computed incrementally, for data that isn't all available at
+/// once.
+pub struct Crc32(Digest<'static, u32>);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self(CRC_32_ISO_HDLC.digest())
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    pub fn finalize(self) -> u32 {
+        self.0.finalize()
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // Adler-32
 pub fn adler32(bytes: &[u8]) -> u32 {
     simd_adler32::adler32(&bytes)
 }
+
+/// An Adler-32 computed incrementally, for data that isn't all available at
+/// once.
+pub struct Adler32(simd_adler32::Adler32);
+
+impl Adler32 {
+    pub fn new() -> Self {
+        Self(simd_adler32::Adler32::new())
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+    }
+
+    pub fn finalize(self) -> u32 {
+        self.0.finish()
+    }
+}
+
+impl Default for Adler32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1 +1,1454 @@
+//! DEFLATE ([RFC 1951](https://www.rfc-editor.org/rfc/rfc1951)) compression
+//! and decompression.
 
+use alloc::borrow::Cow;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::Error;
+
+/// Compression level, from 0 (stored blocks only) to 9 (slowest, smallest).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Level(u8);
+
+impl Level {
+    /// No compression, only stored blocks.
+    pub const NONE: Level = Level(0);
+    /// The fastest compression.
+    pub const FASTEST: Level = Level(1);
+    /// A balance of speed and size, equivalent to zlib's default.
+    pub const DEFAULT: Level = Level(6);
+    /// The smallest output.
+    pub const BEST: Level = Level(9);
+
+    /// Returns the given level, clamped to the range `0..=9`.
+    pub fn new(level: u8) -> Level {
+        Level(level.min(9))
+    }
+
+    pub fn u8(&self) -> u8 {
+        self.0
+    }
+
+    fn params(&self) -> Params {
+        // (maximum hash chain length, length considered good enough, lazy matching)
+        let (max_chain, nice_length, lazy) = [
+            (0, 0, false),
+            (4, 8, false),
+            (8, 16, false),
+            (16, 32, false),
+            (16, 16, true),
+            (32, 32, true),
+            (128, 128, true),
+            (256, MAX_MATCH, true),
+            (1024, MAX_MATCH, true),
+            (4096, MAX_MATCH, true),
+        ][usize::from(self.0)];
+        Params {
+            max_chain,
+            nice_length,
+            lazy,
+        }
+    }
+}
+
+impl Default for Level {
+    fn default() -> Self {
+        Level::DEFAULT
+    }
+}
+
+impl From<Level> for u8 {
+    fn from(level: Level) -> Self {
+        level.0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Params {
+    max_chain: usize,
+    nice_length: usize,
+    lazy: bool,
+}
+
+/// Compresses `data` as a raw DEFLATE stream at the default level, with no
+/// zlib or gzip wrapper.
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    deflate_with(data, Level::DEFAULT)
+}
+
+/// Compresses `data` as a raw DEFLATE stream at the given level, with no zlib
+/// or gzip wrapper.
+pub fn deflate_with(data: &[u8], level: Level) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    write_deflated(&mut buffer, data, level);
+    buffer
+}
+
+/// Writes `data` to `buffer` as a raw DEFLATE stream at the given level.
+/// Returns the range of `buffer` that the stream was written to.
+pub fn write_deflated(buffer: &mut Vec<u8>, data: &[u8], level: Level) -> Range<usize> {
+    write_deflated_with_dictionary(buffer, data, &[], level)
+}
+
+/// Writes `data` to `buffer` as a raw DEFLATE stream at the given level,
+/// allowing matches to refer back into the end of a preset `dictionary` which
+/// the decoder must also be given. Returns the range of `buffer` that the
+/// stream was written to.
+pub fn write_deflated_with_dictionary(
+    buffer: &mut Vec<u8>,
+    data: &[u8],
+    dictionary: &[u8],
+    level: Level,
+) -> Range<usize> {
+    let before = buffer.len();
+    let mut writer = BitWriter::new(buffer);
+    write_blocks(&mut writer, data, dictionary, level, true);
+    writer.finish();
+    let after = buffer.len();
+    before..after
+}
+
+/// Writes `data` as a series of blocks, the last of which is final if
+/// `is_final`, with matches allowed to refer back into `dictionary`.
+fn write_blocks(
+    writer: &mut BitWriter,
+    data: &[u8],
+    dictionary: &[u8],
+    level: Level,
+    is_final: bool,
+) {
+    if level == Level::NONE {
+        write_stored_blocks(writer, data, is_final);
+    } else {
+        let dictionary = &dictionary[dictionary.len().saturating_sub(WINDOW_SIZE)..];
+        let primed: Cow<[u8]> = if dictionary.is_empty() {
+            Cow::Borrowed(data)
+        } else {
+            Cow::Owned([dictionary, data].concat())
+        };
+        let symbols = find_symbols(&primed, dictionary.len(), level.params());
+
+        let mut blocks: Vec<&[Symbol]> = symbols.chunks(BLOCK_SYMBOLS).collect();
+        if blocks.is_empty() {
+            blocks.push(&[]);
+        }
+
+        let mut start = 0;
+        let count = blocks.len();
+        for (index, block) in blocks.into_iter().enumerate() {
+            let is_last = is_final && index + 1 == count;
+            let length: usize = block.iter().map(Symbol::len).sum();
+            write_block(writer, block, &data[start..][..length], is_last);
+            start += length;
+        }
+    }
+}
+
+/// Compresses a raw DEFLATE stream incrementally, for data that isn't all
+/// available at once.
+///
+/// Input is buffered until there's [`Deflater::CHUNK_SIZE`] of it, which is
+/// then compressed, with matches reaching back into the previous chunk, and
+/// followed by an empty stored block so the output so far ends on a byte
+/// boundary.
+#[derive(Debug, Clone)]
+pub struct Deflater {
+    level: Level,
+    /// The end of the data already compressed, which matches may refer to.
+    window: Vec<u8>,
+    pending: Vec<u8>,
+}
+
+impl Deflater {
+    /// The amount of input buffered before it's compressed.
+    pub const CHUNK_SIZE: usize = 1 << 18;
+
+    pub fn new(level: Level) -> Self {
+        Self {
+            level,
+            window: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Compresses `input`, appending any output that's ready to `output`.
+    pub fn write(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        self.pending.extend_from_slice(input);
+        if self.pending.len() >= Self::CHUNK_SIZE {
+            let mut writer = BitWriter::new(output);
+            write_blocks(&mut writer, &self.pending, &self.window, self.level, false);
+            if self.level != Level::NONE {
+                write_stored_blocks(&mut writer, &[], false);
+            }
+            writer.finish();
+
+            let keep = WINDOW_SIZE.min(self.pending.len());
+            self.window.clear();
+            self.window
+                .extend_from_slice(&self.pending[self.pending.len() - keep..]);
+            self.pending.clear();
+        }
+    }
+
+    /// Compresses any buffered input and ends the stream, appending the rest
+    /// of the output to `output`.
+    pub fn finish(self, output: &mut Vec<u8>) {
+        write_deflated_with_dictionary(output, &self.pending, &self.window, self.level);
+    }
+}
+
+/// The maximum number of symbols encoded in each block.
+const BLOCK_SYMBOLS: usize = 1 << 14;
+
+/// The maximum length of a stored block.
+pub(crate) const MAX_STORED: usize = 0xFFFF;
+
+/// The maximum distance back that a match can refer to.
+pub(crate) const WINDOW_SIZE: usize = 1 << 15;
+
+pub(crate) const MIN_MATCH: usize = 3;
+pub(crate) const MAX_MATCH: usize = 258;
+
+/// The end-of-block symbol in the literal/length alphabet.
+pub(crate) const END_OF_BLOCK: usize = 256;
+
+/// Base lengths for the length symbols starting at 257.
+pub(crate) const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+
+/// Extra bits for the length symbols starting at 257.
+pub(crate) const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distances for the distance symbols.
+pub(crate) const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+
+/// Extra bits for the distance symbols.
+pub(crate) const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// The order in which code length code lengths are written in a dynamic
+/// block header.
+pub(crate) const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Code lengths of the fixed literal/length code.
+pub(crate) fn fixed_literal_lengths() -> [u8; 288] {
+    let mut lengths = [0; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    lengths
+}
+
+/// Code lengths of the fixed distance code.
+pub(crate) fn fixed_distance_lengths() -> [u8; 30] {
+    [5; 30]
+}
+
+/// A literal byte, or a match copying bytes from earlier in the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Symbol {
+    Literal(u8),
+    Match { length: u16, distance: u16 },
+}
+
+impl Symbol {
+    /// The number of bytes of data this symbol represents.
+    fn len(&self) -> usize {
+        match self {
+            Symbol::Literal(_) => 1,
+            Symbol::Match { length, .. } => usize::from(*length),
+        }
+    }
+
+    /// The literal/length symbol, with the number and value of its extra bits.
+    fn literal_code(&self) -> (usize, u8, u16) {
+        match *self {
+            Symbol::Literal(byte) => (usize::from(byte), 0, 0),
+            Symbol::Match { length, .. } => {
+                let index = LENGTH_BASE.partition_point(|&base| base <= length) - 1;
+                (
+                    257 + index,
+                    LENGTH_EXTRA[index],
+                    length - LENGTH_BASE[index],
+                )
+            },
+        }
+    }
+
+    /// The distance symbol, with the number and value of its extra bits.
+    fn distance_code(&self) -> Option<(usize, u8, u16)> {
+        match *self {
+            Symbol::Literal(_) => None,
+            Symbol::Match { distance, .. } => {
+                let index = DISTANCE_BASE.partition_point(|&base| base <= distance) - 1;
+                Some((
+                    index,
+                    DISTANCE_EXTRA[index],
+                    distance - DISTANCE_BASE[index],
+                ))
+            },
+        }
+    }
+}
+
+/// Finds the LZ77 literals and matches that make up `data[start..]`, using
+/// hash chains of the positions of each three-byte prefix. Matches may refer
+/// back into `data[..start]`.
+fn find_symbols(data: &[u8], start: usize, params: Params) -> Vec<Symbol> {
+    const HASH_BITS: u32 = 15;
+
+    let hash = |index: usize| -> usize {
+        let bytes = u32::from(data[index])
+            | u32::from(data[index + 1]) << 8
+            | u32::from(data[index + 2]) << 16;
+        (bytes.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+    };
+
+    // The most recent position + 1 with each hash, or 0 for none.
+    let mut head = vec![0_usize; 1 << HASH_BITS];
+    // The previous position + 1 with the same hash as each position in the
+    // window, or 0 for none.
+    let mut previous = vec![0_usize; WINDOW_SIZE];
+    let mut inserted = 0;
+
+    let mut insert_until = |end: usize, head: &mut Vec<usize>, previous: &mut Vec<usize>| {
+        while inserted < end && inserted + MIN_MATCH <= data.len() {
+            let hash = hash(inserted);
+            previous[inserted % WINDOW_SIZE] = head[hash];
+            head[hash] = inserted + 1;
+            inserted += 1;
+        }
+    };
+
+    let longest_match = |index: usize, head: &Vec<usize>, previous: &Vec<usize>| {
+        let mut best = (0, 0);
+        if index + MIN_MATCH > data.len() {
+            return best;
+        }
+        let max_length = MAX_MATCH.min(data.len() - index);
+        let mut candidate = head[hash(index)];
+        let mut chain = params.max_chain;
+        while candidate != 0 && chain > 0 {
+            let position = candidate - 1;
+            if position >= index || index - position > WINDOW_SIZE {
+                break;
+            }
+            let length = data[position..]
+                .iter()
+                .zip(&data[index..][..max_length])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length > best.0 {
+                best = (length, index - position);
+                if length >= params.nice_length.max(MIN_MATCH) {
+                    break;
+                }
+            }
+            let next = previous[position % WINDOW_SIZE];
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+            chain -= 1;
+        }
+        if best.0 < MIN_MATCH {
+            (0, 0)
+        } else {
+            best
+        }
+    };
+
+    let mut symbols = Vec::new();
+    let mut index = start;
+    let mut pending = None;
+    while index < data.len() {
+        insert_until(index, &mut head, &mut previous);
+        let (length, distance) = pending
+            .take()
+            .unwrap_or_else(|| longest_match(index, &head, &previous));
+
+        if length == 0 {
+            symbols.push(Symbol::Literal(data[index]));
+            index += 1;
+            continue;
+        }
+
+        if params.lazy && length < params.nice_length {
+            // If there's a longer match starting at the next byte, we'd rather
+            // emit this byte as a literal and use that match instead.
+            insert_until(index + 1, &mut head, &mut previous);
+            let next = longest_match(index + 1, &head, &previous);
+            if next.0 > length {
+                symbols.push(Symbol::Literal(data[index]));
+                index += 1;
+                pending = Some(next);
+                continue;
+            }
+        }
+
+        symbols.push(Symbol::Match {
+            length: length as u16,
+            distance: distance as u16,
+        });
+        index += length;
+    }
+
+    symbols
+}
+
+/// Writes a block encoding `symbols`, which represent `data`, as whichever of
+/// stored, fixed-Huffman or dynamic-Huffman blocks is smallest.
+fn write_block(writer: &mut BitWriter, symbols: &[Symbol], data: &[u8], is_final: bool) {
+    let mut literal_frequencies = [0_u32; 286];
+    let mut distance_frequencies = [0_u32; 30];
+    literal_frequencies[END_OF_BLOCK] = 1;
+    for symbol in symbols {
+        literal_frequencies[symbol.literal_code().0] += 1;
+        if let Some((code, ..)) = symbol.distance_code() {
+            distance_frequencies[code] += 1;
+        }
+    }
+
+    let literal_lengths = huffman_lengths(&literal_frequencies, 15);
+    let distance_lengths = huffman_lengths(&distance_frequencies, 15);
+    let header = DynamicHeader::new(&literal_lengths, &distance_lengths);
+
+    let fixed_literal_lengths = fixed_literal_lengths();
+    let fixed_distance_lengths = fixed_distance_lengths();
+
+    let data_bits = |literal_lengths: &[u8], distance_lengths: &[u8]| -> usize {
+        symbols
+            .iter()
+            .map(|symbol| {
+                let (code, extra, _) = symbol.literal_code();
+                let mut bits = usize::from(literal_lengths[code]) + usize::from(extra);
+                if let Some((code, extra, _)) = symbol.distance_code() {
+                    bits += usize::from(distance_lengths[code]) + usize::from(extra);
+                }
+                bits
+            })
+            .sum::<usize>()
+            + usize::from(literal_lengths[END_OF_BLOCK])
+    };
+
+    let stored_bits = (data.len() / MAX_STORED + 1) * (3 + 7 + 32) + data.len() * 8;
+    let fixed_bits = 3 + data_bits(&fixed_literal_lengths, &fixed_distance_lengths);
+    let dynamic_bits = 3 + header.bits() + data_bits(&literal_lengths, &distance_lengths);
+
+    if stored_bits <= fixed_bits && stored_bits <= dynamic_bits {
+        write_stored_blocks(writer, data, is_final);
+    } else if fixed_bits <= dynamic_bits {
+        writer.write_bits(u32::from(is_final), 1);
+        writer.write_bits(0b01, 2);
+        write_symbols(
+            writer,
+            symbols,
+            &canonical_codes(&fixed_literal_lengths),
+            &canonical_codes(&fixed_distance_lengths),
+        );
+    } else {
+        writer.write_bits(u32::from(is_final), 1);
+        writer.write_bits(0b10, 2);
+        header.write(writer);
+        write_symbols(
+            writer,
+            symbols,
+            &canonical_codes(&literal_lengths),
+            &canonical_codes(&distance_lengths),
+        );
+    }
+}
+
+/// Writes `data` as a series of stored blocks, the last of which is final if
+/// `is_final`.
+fn write_stored_blocks(writer: &mut BitWriter, data: &[u8], is_final: bool) {
+    let mut chunks: Vec<&[u8]> = data.chunks(MAX_STORED).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    let count = chunks.len();
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        let is_last = is_final && index + 1 == count;
+        writer.write_bits(u32::from(is_last), 1);
+        writer.write_bits(0b00, 2);
+        writer.align();
+        let length = chunk.len() as u16;
+        writer.write_bytes(&length.to_le_bytes());
+        writer.write_bytes(&(!length).to_le_bytes());
+        writer.write_bytes(chunk);
+    }
+}
+
+/// Writes the Huffman-coded symbols of a block, followed by the end-of-block
+/// symbol.
+fn write_symbols(
+    writer: &mut BitWriter,
+    symbols: &[Symbol],
+    literal_codes: &[(u16, u8)],
+    distance_codes: &[(u16, u8)],
+) {
+    for symbol in symbols {
+        let (code, extra, value) = symbol.literal_code();
+        writer.write_code(literal_codes[code]);
+        writer.write_bits(value.into(), extra.into());
+        if let Some((code, extra, value)) = symbol.distance_code() {
+            writer.write_code(distance_codes[code]);
+            writer.write_bits(value.into(), extra.into());
+        }
+    }
+    writer.write_code(literal_codes[END_OF_BLOCK]);
+}
+
+/// The header of a dynamic-Huffman block, describing its codes.
+struct DynamicHeader {
+    literal_count: usize,
+    distance_count: usize,
+    /// The run-length encoded code lengths, as code length symbols with the
+    /// number and value of their extra bits.
+    code_length_symbols: Vec<(usize, u8, u8)>,
+    code_length_lengths: Vec<u8>,
+    code_length_count: usize,
+}
+
+impl DynamicHeader {
+    fn new(literal_lengths: &[u8], distance_lengths: &[u8]) -> Self {
+        let literal_count = 257.max(
+            literal_lengths
+                .iter()
+                .rposition(|&length| length != 0)
+                .map_or(0, |index| index + 1),
+        );
+        let distance_count = 1.max(
+            distance_lengths
+                .iter()
+                .rposition(|&length| length != 0)
+                .map_or(0, |index| index + 1),
+        );
+
+        let lengths: Vec<u8> = literal_lengths[..literal_count]
+            .iter()
+            .chain(&distance_lengths[..distance_count])
+            .copied()
+            .collect();
+
+        let mut code_length_symbols = Vec::new();
+        let mut index = 0;
+        while index < lengths.len() {
+            let length = lengths[index];
+            let run = lengths[index..]
+                .iter()
+                .take_while(|&&other| other == length)
+                .count();
+            if length == 0 && run >= 11 {
+                let run = run.min(138);
+                code_length_symbols.push((18, 7, (run - 11) as u8));
+                index += run;
+            } else if length == 0 && run >= 3 {
+                code_length_symbols.push((17, 3, (run - 3) as u8));
+                index += run;
+            } else if length != 0 && run >= 4 {
+                code_length_symbols.push((usize::from(length), 0, 0));
+                let run = (run - 1).min(6);
+                code_length_symbols.push((16, 2, (run - 3) as u8));
+                index += 1 + run;
+            } else {
+                code_length_symbols.push((usize::from(length), 0, 0));
+                index += 1;
+            }
+        }
+
+        let mut frequencies = [0_u32; 19];
+        for (symbol, ..) in &code_length_symbols {
+            frequencies[*symbol] += 1;
+        }
+        let code_length_lengths = huffman_lengths(&frequencies, 7);
+        let code_length_count = 4.max(
+            CODE_LENGTH_ORDER
+                .iter()
+                .rposition(|&symbol| code_length_lengths[symbol] != 0)
+                .map_or(0, |index| index + 1),
+        );
+
+        Self {
+            literal_count,
+            distance_count,
+            code_length_symbols,
+            code_length_lengths,
+            code_length_count,
+        }
+    }
+
+    /// The size of the header in bits, excluding the three-bit block header.
+    fn bits(&self) -> usize {
+        5 + 5
+            + 4
+            + 3 * self.code_length_count
+            + self
+                .code_length_symbols
+                .iter()
+                .map(|&(symbol, extra, _)| {
+                    usize::from(self.code_length_lengths[symbol]) + usize::from(extra)
+                })
+                .sum::<usize>()
+    }
+
+    fn write(&self, writer: &mut BitWriter) {
+        writer.write_bits((self.literal_count - 257) as u32, 5);
+        writer.write_bits((self.distance_count - 1) as u32, 5);
+        writer.write_bits((self.code_length_count - 4) as u32, 4);
+        for &symbol in &CODE_LENGTH_ORDER[..self.code_length_count] {
+            writer.write_bits(self.code_length_lengths[symbol].into(), 3);
+        }
+        let codes = canonical_codes(&self.code_length_lengths);
+        for &(symbol, extra, value) in &self.code_length_symbols {
+            writer.write_code(codes[symbol]);
+            writer.write_bits(value.into(), extra.into());
+        }
+    }
+}
+
+/// Computes Huffman code lengths for symbols with the given frequencies, none
+/// longer than `max_length`, using the package-merge algorithm.
+///
+/// At least two symbols are always given codes, so that the code is complete.
+fn huffman_lengths(frequencies: &[u32], max_length: usize) -> Vec<u8> {
+    let mut leaves: Vec<(u64, usize)> = frequencies
+        .iter()
+        .enumerate()
+        .filter(|(_, &frequency)| frequency > 0)
+        .map(|(symbol, &frequency)| (u64::from(frequency), symbol))
+        .collect();
+    let mut unused = (0..frequencies.len()).filter(|&symbol| frequencies[symbol] == 0);
+    while leaves.len() < 2 {
+        match unused.next() {
+            Some(symbol) => leaves.push((1, symbol)),
+            None => break,
+        }
+    }
+    leaves.sort_unstable();
+
+    // Each list holds the weights of leaves and of packages of pairs from the
+    // previous list, in order, so we only need to record which items are
+    // packages to work out which leaves the chosen items are made of.
+    let leaf_weights: Vec<u64> = leaves.iter().map(|&(weight, _)| weight).collect();
+    let mut weights = leaf_weights.clone();
+    let mut lists_are_packages: Vec<Vec<bool>> = Vec::with_capacity(max_length);
+    for _ in 1..max_length {
+        let packages = weights.chunks_exact(2).map(|pair| pair[0] + pair[1]);
+        let mut merged = Vec::with_capacity(leaf_weights.len() * 2);
+        let mut are_packages = Vec::with_capacity(leaf_weights.len() * 2);
+        let mut leaf_weights = leaf_weights.iter().copied().peekable();
+        for package in packages {
+            while let Some(leaf) = leaf_weights.next_if(|&leaf| leaf <= package) {
+                merged.push(leaf);
+                are_packages.push(false);
+            }
+            merged.push(package);
+            are_packages.push(true);
+        }
+        for leaf in leaf_weights {
+            merged.push(leaf);
+            are_packages.push(false);
+        }
+        lists_are_packages.push(are_packages);
+        weights = merged;
+    }
+
+    // Each leaf's code length is the number of chosen items it's part of. The
+    // leaves chosen from each list are always the lightest, and the packages
+    // chosen are made of the first items of the previous list.
+    let mut lengths = vec![0_u8; frequencies.len()];
+    let mut chosen = 2 * leaves.len() - 2;
+    for are_packages in lists_are_packages.iter().rev() {
+        let leaf_count = are_packages[..chosen]
+            .iter()
+            .filter(|&&is_package| !is_package)
+            .count();
+        for &(_, symbol) in &leaves[..leaf_count] {
+            lengths[symbol] += 1;
+        }
+        chosen = 2 * (chosen - leaf_count);
+    }
+    for &(_, symbol) in &leaves[..chosen] {
+        lengths[symbol] += 1;
+    }
+    lengths
+}
+
+/// Computes the canonical Huffman codes for the given code lengths, as
+/// `(bit-reversed code, length)` pairs ready to be written.
+pub(crate) fn canonical_codes(lengths: &[u8]) -> Vec<(u16, u8)> {
+    let mut length_counts = [0_u16; 16];
+    for &length in lengths {
+        length_counts[usize::from(length)] += 1;
+    }
+    length_counts[0] = 0;
+
+    let mut next_code = [0_u16; 16];
+    let mut code = 0;
+    for length in 1..16 {
+        code = (code + length_counts[length - 1]) << 1;
+        next_code[length] = code;
+    }
+
+    lengths
+        .iter()
+        .map(|&length| {
+            if length == 0 {
+                return (0, 0);
+            }
+            let code = next_code[usize::from(length)];
+            next_code[usize::from(length)] += 1;
+            (code.reverse_bits() >> (16 - length), length)
+        })
+        .collect()
+}
+
+/// Writes bits to a buffer, least-significant bit first.
+struct BitWriter<'buffer> {
+    buffer: &'buffer mut Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl<'buffer> BitWriter<'buffer> {
+    fn new(buffer: &'buffer mut Vec<u8>) -> Self {
+        Self {
+            buffer,
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.bits |= u64::from(value) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.buffer.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn write_code(&mut self, (code, length): (u16, u8)) {
+        self.write_bits(code.into(), length.into());
+    }
+
+    /// Pads with zero bits to the next byte boundary.
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.write_bits(0, 8 - self.count);
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        debug_assert_eq!(self.count, 0);
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    fn finish(mut self) {
+        self.align();
+    }
+}
+
+/// Decompresses a raw DEFLATE stream, with no zlib or gzip wrapper. Any data
+/// following the end of the stream is ignored.
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, Error> {
+    inflate_with_limit(data, usize::MAX)
+}
+
+/// Decompresses a raw DEFLATE stream, failing with
+/// [`Error::OutputLimitExceeded`] rather than producing more than `limit`
+/// bytes.
+pub fn inflate_with_limit(data: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+    let mut inflater = Inflater::with_limit(limit);
+    let mut output = Vec::new();
+    inflater.write(data, &mut output)?;
+    inflater.finish()?;
+    Ok(output)
+}
+
+/// A streaming DEFLATE decoder, which can be given its input in pieces of any
+/// size and returns output as soon as it has been decoded.
+///
+/// After an error has been returned the decoder must not be used further.
+#[derive(Debug, Clone)]
+pub struct Inflater {
+    /// Input that hasn't been entirely consumed yet.
+    input: Vec<u8>,
+    /// The offset of the next unread bit in `input`.
+    position: usize,
+    /// The offset in bits of the start of `input` in the stream.
+    input_offset: usize,
+    /// The most recent output, kept for matches to refer back to. Anything
+    /// more than a window back is dropped once this reaches twice the window.
+    history: Vec<u8>,
+    /// The length of the prefix of `history` already returned.
+    flushed: usize,
+    /// The length of the preset dictionary that matches may also refer to.
+    preset: usize,
+    total_output: usize,
+    limit: usize,
+    is_final_block: bool,
+    state: State,
+}
+
+/// What the decoder expects to read next.
+#[derive(Debug, Clone)]
+enum State {
+    BlockHeader,
+    Stored {
+        remaining: usize,
+    },
+    Huffman {
+        literals: Huffman,
+        distances: Huffman,
+    },
+    Done,
+}
+
+/// Why decoding stopped before the end of the stream.
+enum Stall {
+    /// More input is needed to decode the next element.
+    Input,
+    Error(Error),
+}
+
+impl From<Error> for Stall {
+    fn from(error: Error) -> Self {
+        Stall::Error(error)
+    }
+}
+
+fn invalid(bit_offset: usize, reason: &'static str) -> Stall {
+    Stall::Error(Error::InvalidDeflate { bit_offset, reason })
+}
+
+impl Default for Inflater {
+    fn default() -> Self {
+        Self::with_limit(usize::MAX)
+    }
+}
+
+impl Inflater {
+    /// Creates a decoder with no limit on its output size.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a decoder that fails with [`Error::OutputLimitExceeded`] rather
+    /// than producing more than `limit` bytes in total.
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            input: Vec::new(),
+            position: 0,
+            input_offset: 0,
+            history: Vec::new(),
+            flushed: 0,
+            preset: 0,
+            total_output: 0,
+            limit,
+            is_final_block: false,
+            state: State::BlockHeader,
+        }
+    }
+
+    /// Creates a decoder for a stream compressed with a preset dictionary,
+    /// which matches may refer back into, and with the given output limit.
+    pub fn with_dictionary(dictionary: &[u8], limit: usize) -> Self {
+        let dictionary = &dictionary[dictionary.len().saturating_sub(WINDOW_SIZE)..];
+        let mut inflater = Self::with_limit(limit);
+        inflater.history.extend_from_slice(dictionary);
+        inflater.flushed = dictionary.len();
+        inflater.preset = dictionary.len();
+        inflater
+    }
+
+    /// Decodes as much of the stream as possible from `input` and any input
+    /// left over from previous calls, appending the output to `output`.
+    /// Returns whether the end of the stream has been reached.
+    pub fn write(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<bool, Error> {
+        self.input.extend_from_slice(input);
+        let result = self.decode(output);
+        self.flush(output);
+
+        let consumed = self.position / 8;
+        self.input.drain(..consumed);
+        self.position -= consumed * 8;
+        self.input_offset += consumed * 8;
+
+        result.map(|()| self.is_done())
+    }
+
+    /// Checks that the end of the stream has been reached, failing with
+    /// [`Error::TruncatedDeflate`] if it hasn't.
+    pub fn finish(&self) -> Result<(), Error> {
+        if self.is_done() {
+            Ok(())
+        } else {
+            Err(Error::TruncatedDeflate {
+                bit_offset: self.input_offset + self.input.len() * 8,
+            })
+        }
+    }
+
+    /// Whether the end of the final block has been reached.
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    /// The input following the end of the stream, from the byte boundary
+    /// after its final block. Empty until the end has been reached.
+    pub fn remaining(&self) -> &[u8] {
+        if self.is_done() {
+            &self.input[self.position / 8..]
+        } else {
+            &[]
+        }
+    }
+
+    /// The number of bits of input consumed so far.
+    pub fn bit_offset(&self) -> usize {
+        self.input_offset + self.position
+    }
+
+    /// The number of bytes of output produced so far.
+    pub fn total_output(&self) -> usize {
+        self.total_output
+    }
+
+    fn decode(&mut self, output: &mut Vec<u8>) -> Result<(), Error> {
+        while !self.is_done() {
+            let start = self.position;
+            match self.step() {
+                Ok(()) =>
+                    if self.history.len() > 2 * WINDOW_SIZE {
+                        self.flush(output);
+                    },
+                Err(Stall::Input) => {
+                    self.position = start;
+                    break;
+                },
+                Err(Stall::Error(error)) => {
+                    self.position = start;
+                    return Err(error);
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends the output that hasn't been returned yet to `output`, and drops
+    /// history that matches can no longer refer to.
+    fn flush(&mut self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&self.history[self.flushed..]);
+        if self.history.len() > 2 * WINDOW_SIZE {
+            self.history.drain(..self.history.len() - WINDOW_SIZE);
+        }
+        self.flushed = self.history.len();
+    }
+
+    fn reader(&self) -> BitReader<'_> {
+        BitReader {
+            input: &self.input,
+            position: self.position,
+            offset: self.input_offset,
+        }
+    }
+
+    /// Decodes the next block header, or a run of stored bytes, or a single
+    /// Huffman-coded symbol. Nothing is changed if more input is needed.
+    fn step(&mut self) -> Result<(), Stall> {
+        match &self.state {
+            State::BlockHeader => {
+                let mut reader = self.reader();
+                let is_final = reader.read_bits(1)? == 1;
+                let block_type_offset = reader.bit_offset();
+                let state = match reader.read_bits(2)? {
+                    0b00 => {
+                        reader.align();
+                        let length_offset = reader.bit_offset();
+                        let length = reader.read_bits(16)?;
+                        let complement = reader.read_bits(16)?;
+                        if length ^ complement != 0xFFFF {
+                            return Err(invalid(
+                                length_offset,
+                                "stored block length doesn't match its complement",
+                            ));
+                        }
+                        State::Stored {
+                            remaining: length as usize,
+                        }
+                    },
+                    0b01 => State::Huffman {
+                        literals: Huffman::new(&fixed_literal_lengths()).0,
+                        distances: Huffman::new(&fixed_distance_lengths()).0,
+                    },
+                    0b10 => read_dynamic_header(&mut reader)?,
+                    _ => return Err(invalid(block_type_offset, "reserved block type")),
+                };
+                self.position = reader.position;
+                self.is_final_block = is_final;
+                self.state = state;
+            },
+            &State::Stored { remaining } => {
+                if remaining == 0 {
+                    self.end_block();
+                    return Ok(());
+                }
+                let start = self.position / 8;
+                // copied at most a window at a time, to keep the history bounded
+                let count = remaining.min(self.input.len() - start).min(WINDOW_SIZE);
+                if count == 0 {
+                    return Err(Stall::Input);
+                }
+                self.reserve_output(count)?;
+                self.history
+                    .extend_from_slice(&self.input[start..start + count]);
+                self.position += count * 8;
+                self.state = State::Stored {
+                    remaining: remaining - count,
+                };
+            },
+            State::Huffman {
+                literals,
+                distances,
+            } => {
+                let mut reader = self.reader();
+                let symbol_offset = reader.bit_offset();
+                let symbol = literals.decode(&mut reader)?;
+                let copy = match symbol {
+                    0..=255 => None,
+                    END_OF_BLOCK => {
+                        self.position = reader.position;
+                        self.end_block();
+                        return Ok(());
+                    },
+                    257..=285 => {
+                        let index = symbol - 257;
+                        let length = usize::from(LENGTH_BASE[index])
+                            + reader.read_bits(LENGTH_EXTRA[index])? as usize;
+                        let distance_offset = reader.bit_offset();
+                        let symbol = distances.decode(&mut reader)?;
+                        if symbol >= DISTANCE_BASE.len() {
+                            return Err(invalid(distance_offset, "invalid distance symbol"));
+                        }
+                        let distance = usize::from(DISTANCE_BASE[symbol])
+                            + reader.read_bits(DISTANCE_EXTRA[symbol])? as usize;
+                        if distance > self.total_output + self.preset {
+                            return Err(invalid(distance_offset, "distance too far back"));
+                        }
+                        Some((length, distance))
+                    },
+                    _ => return Err(invalid(symbol_offset, "invalid literal/length symbol")),
+                };
+                self.position = reader.position;
+
+                match copy {
+                    None => {
+                        self.reserve_output(1)?;
+                        self.history.push(symbol as u8);
+                    },
+                    Some((length, distance)) => {
+                        self.reserve_output(length)?;
+                        let start = self.history.len() - distance;
+                        for index in start..start + length {
+                            self.history.push(self.history[index]);
+                        }
+                    },
+                }
+            },
+            State::Done => {},
+        }
+        Ok(())
+    }
+
+    fn end_block(&mut self) {
+        if self.is_final_block {
+            self.position = (self.position + 7) & !7;
+            self.state = State::Done;
+        } else {
+            self.state = State::BlockHeader;
+        }
+    }
+
+    fn reserve_output(&mut self, count: usize) -> Result<(), Error> {
+        if count > self.limit - self.total_output {
+            return Err(Error::OutputLimitExceeded { limit: self.limit });
+        }
+        self.total_output += count;
+        Ok(())
+    }
+}
+
+/// Reads the code lengths at the start of a dynamic-Huffman block and builds
+/// its codes.
+fn read_dynamic_header(reader: &mut BitReader) -> Result<State, Stall> {
+    let offset = reader.bit_offset();
+    let literal_count = reader.read_bits(5)? as usize + 257;
+    let distance_count = reader.read_bits(5)? as usize + 1;
+    let code_length_count = reader.read_bits(4)? as usize + 4;
+    if literal_count > 286 || distance_count > DISTANCE_BASE.len() {
+        return Err(invalid(offset, "too many literal/length or distance codes"));
+    }
+
+    let mut code_length_lengths = [0; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_length_lengths[symbol] = reader.read_bits(3)? as u8;
+    }
+    let (code_length_code, unused) = Huffman::new(&code_length_lengths);
+    if unused != 0 {
+        return Err(invalid(
+            offset + 14,
+            "incomplete or over-subscribed code length code",
+        ));
+    }
+
+    let mut lengths = vec![0; literal_count + distance_count];
+    let mut index = 0;
+    while index < lengths.len() {
+        let symbol_offset = reader.bit_offset();
+        let (length, repeat) = match code_length_code.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                if index == 0 {
+                    return Err(invalid(
+                        symbol_offset,
+                        "repeated code length with none before it",
+                    ));
+                }
+                (lengths[index - 1], 3 + reader.read_bits(2)? as usize)
+            },
+            17 => (0, 3 + reader.read_bits(3)? as usize),
+            _ => (0, 11 + reader.read_bits(7)? as usize),
+        };
+        if index + repeat > lengths.len() {
+            return Err(invalid(symbol_offset, "code lengths repeated past the end"));
+        }
+        lengths[index..index + repeat].fill(length);
+        index += repeat;
+    }
+
+    if lengths[END_OF_BLOCK] == 0 {
+        return Err(invalid(offset, "missing end-of-block code"));
+    }
+    let (literals, distances) = lengths.split_at(literal_count);
+    let literals = Huffman::new_incomplete(literals)
+        .ok_or_else(|| invalid(offset, "invalid literal/length code lengths"))?;
+    let distances = Huffman::new_incomplete(distances)
+        .ok_or_else(|| invalid(offset, "invalid distance code lengths"))?;
+    Ok(State::Huffman {
+        literals,
+        distances,
+    })
+}
+
+/// A canonical Huffman code, decoded a bit at a time.
+#[derive(Debug, Clone)]
+struct Huffman {
+    /// The number of symbols with codes of each length.
+    counts: [u16; 16],
+    /// The symbols ordered by their codes.
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// Builds the code with the given lengths, returning it along with the
+    /// number of unused codes of the maximum length, which is zero if the code
+    /// is complete and negative if it is over-subscribed.
+    fn new(lengths: &[u8]) -> (Self, isize) {
+        let mut counts = [0_u16; 16];
+        for &length in lengths {
+            counts[usize::from(length)] += 1;
+        }
+
+        let mut unused: isize = 1;
+        for &count in &counts[1..] {
+            unused = (unused << 1) - isize::from(count as i16);
+        }
+
+        let mut offsets = [0_usize; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + usize::from(counts[length]);
+        }
+        let mut symbols = vec![0; lengths.len() - usize::from(counts[0])];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[usize::from(length)]] = symbol as u16;
+                offsets[usize::from(length)] += 1;
+            }
+        }
+
+        (Self { counts, symbols }, unused)
+    }
+
+    /// Builds the code with the given lengths, which may be incomplete only if
+    /// it has a single one-bit code, as for a block that uses one symbol.
+    fn new_incomplete(lengths: &[u8]) -> Option<Self> {
+        let (code, unused) = Huffman::new(lengths);
+        let is_single = code.symbols.len() == usize::from(code.counts[1]);
+        (unused == 0 || (unused > 0 && is_single)).then_some(code)
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<usize, Stall> {
+        let offset = reader.bit_offset();
+        let mut code = 0;
+        let mut first = 0;
+        let mut index = 0;
+        for &count in &self.counts[1..] {
+            let count = usize::from(count);
+            code |= reader.read_bits(1)? as usize;
+            if code - first < count {
+                return Ok(self.symbols[index + code - first].into());
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid(offset, "invalid Huffman code"))
+    }
+}
+
+/// Reads bits from a buffer, least-significant bit first.
+struct BitReader<'input> {
+    input: &'input [u8],
+    position: usize,
+    /// The offset in bits of the start of `input` in the stream.
+    offset: usize,
+}
+
+impl BitReader<'_> {
+    fn bit_offset(&self) -> usize {
+        self.offset + self.position
+    }
+
+    fn read_bits(&mut self, count: u8) -> Result<u32, Stall> {
+        let count = usize::from(count);
+        if self.position + count > self.input.len() * 8 {
+            return Err(Stall::Input);
+        }
+        let mut value = 0;
+        for bit in 0..count {
+            let position = self.position + bit;
+            let byte = self.input[position / 8];
+            value |= u32::from((byte >> (position % 8)) & 1) << bit;
+        }
+        self.position += count;
+        Ok(value)
+    }
+
+    /// Skips to the next byte boundary.
+    fn align(&mut self) {
+        self.position = (self.position + 7) & !7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::write_non_deflated;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&text[index..index + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn inflates_stored_blocks() {
+        let stream = [
+            &[0x00, 0x03, 0x00, 0xFC, 0xFF][..],
+            b"hel",
+            &[0x01, 0x02, 0x00, 0xFD, 0xFF],
+            b"lo",
+        ]
+        .concat();
+        assert_eq!(inflate(&stream).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn inflates_fixed_blocks() {
+        // zlib's output with Z_FIXED, the second including a match
+        assert_eq!(inflate(&[0x4B, 0x04, 0x00]).unwrap(), b"a");
+        assert_eq!(inflate(&hex("4b4c4a4e842100")).unwrap(), b"abcabcabcabc");
+    }
+
+    #[test]
+    fn inflates_dynamic_blocks() {
+        // zlib's output for two runs of bytes 201..=255, which needs 9-bit
+        // fixed codes so is smaller with dynamic ones
+        let stream = hex(concat!(
+            "9dcbc50180200000c0fd4743b15bb00bc1eed61dbcff018e878228c98aaae986",
+            "69d98e8bb0e707611427699617a4a4acaa9bb6eb87719a9775db8ff3ba9f17fc",
+            "6b1f"
+        ));
+        assert_eq!((stream[0] >> 1) & 0b11, 0b10);
+        let expected: Vec<u8> = (201..=255).chain(201..=255).collect();
+        assert_eq!(inflate(&stream).unwrap(), expected);
+    }
+
+    #[test]
+    fn inflates_non_deflated_pngs() {
+        for len in [0, 1, 0xFFFF, 0x10000, 0x30001] {
+            let data: Vec<u8> = (0..len).map(|i| (i * 31 % 251) as u8).collect();
+            let mut zlib = Vec::new();
+            write_non_deflated(&mut zlib, &data);
+            // skip the zlib header, and check that the trailer follows the stream
+            let mut inflater = Inflater::new();
+            let mut output = Vec::new();
+            assert!(inflater.write(&zlib[2..], &mut output).unwrap());
+            assert_eq!(output, data);
+            assert_eq!(inflater.remaining().len(), 4);
+        }
+    }
+
+    #[test]
+    fn streams_in_pieces() {
+        let stream = hex("4b4c4a4e842100");
+        let mut inflater = Inflater::new();
+        let mut output = Vec::new();
+        for byte in &stream {
+            inflater.write(&[*byte], &mut output).unwrap();
+        }
+        inflater.finish().unwrap();
+        assert_eq!(output, b"abcabcabcabc");
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let data: Vec<u8> = (0..0x40000).map(|i| (i * 31 % 251) as u8).collect();
+        let mut zlib = Vec::new();
+        write_non_deflated(&mut zlib, &data);
+        let mut inflater = Inflater::new();
+        let mut output = Vec::new();
+        inflater.write(&zlib[2..], &mut output).unwrap();
+        assert_eq!(output, data);
+        assert!(inflater.history.len() <= 3 * WINDOW_SIZE);
+    }
+
+    #[test]
+    fn limits_output() {
+        let stream = [&[0x01, 0x05, 0x00, 0xFA, 0xFF][..], b"hello"].concat();
+        assert_eq!(inflate_with_limit(&stream, 5).unwrap(), b"hello");
+        assert_eq!(
+            inflate_with_limit(&stream, 4),
+            Err(Error::OutputLimitExceeded { limit: 4 })
+        );
+        assert_eq!(
+            inflate_with_limit(&hex("4b4c4a4e842100"), 11),
+            Err(Error::OutputLimitExceeded { limit: 11 })
+        );
+    }
+
+    #[test]
+    fn reports_error_offsets() {
+        let invalid = |bit_offset, reason| Err(Error::InvalidDeflate { bit_offset, reason });
+        // the reserved block type follows the final block bit
+        assert_eq!(inflate(&[0b111]), invalid(1, "reserved block type"));
+        // stored lengths start at the next byte boundary
+        assert_eq!(
+            inflate(&[0x01, 0x05, 0x00, 0x00, 0x00]),
+            invalid(8, "stored block length doesn't match its complement")
+        );
+        // a fixed block starting with a length 3 code, whose distance code
+        // follows at bit 10
+        assert_eq!(inflate(&[0x03, 0x02]), invalid(10, "distance too far back"));
+        // a stored block cut off after a byte of its body
+        assert_eq!(
+            inflate(&[0x01, 0x05, 0x00, 0xFA, 0xFF, b'h']),
+            Err(Error::TruncatedDeflate { bit_offset: 48 })
+        );
+    }
+
+    /// Text, runs, incompressible noise and long-distance repeats, spanning
+    /// several blocks.
+    fn mixed_data() -> Vec<u8> {
+        let mut data = Vec::new();
+        for index in 0..2000 {
+            data.extend_from_slice(format!("line {index} of some repetitive text\n").as_bytes());
+        }
+        data.extend(core::iter::repeat(b'z').take(5000));
+        let mut seed = 1_u32;
+        data.extend((0..40_000).map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as u8
+        }));
+        data.extend_from_within(1000..30_000);
+        data
+    }
+
+    #[test]
+    fn every_level_round_trips() {
+        let data = mixed_data();
+        for level in 0..=9 {
+            let level = Level::new(level);
+            let deflated = deflate_with(&data, level);
+            assert_eq!(inflate(&deflated).unwrap(), data, "{level:?}");
+            assert_eq!(
+                inflate(&deflate_with(&[], level)).unwrap(),
+                b"",
+                "{level:?}"
+            );
+
+            let mut deflater = Deflater::new(level);
+            let mut streamed = Vec::new();
+            for chunk in data.chunks(100_000) {
+                deflater.write(chunk, &mut streamed);
+            }
+            deflater.finish(&mut streamed);
+            assert_eq!(inflate(&streamed).unwrap(), data, "{level:?}");
+        }
+    }
+
+    #[test]
+    fn huffman_lengths_are_limited() {
+        // Fibonacci frequencies would need codes as long as there are symbols
+        let mut frequencies = vec![1_u32, 1];
+        while frequencies.len() < 30 {
+            frequencies
+                .push(frequencies[frequencies.len() - 1] + frequencies[frequencies.len() - 2]);
+        }
+        for max_length in [7, 15] {
+            let lengths = huffman_lengths(&frequencies, max_length);
+            assert_eq!(lengths.iter().max(), Some(&(max_length as u8)));
+            // the code is complete
+            let (_, unused) = Huffman::new(&lengths);
+            assert_eq!(unused, 0);
+        }
+
+        // and the encoder relies on that, with symbols following the same pattern
+        let mut data = Vec::new();
+        for (symbol, &frequency) in frequencies.iter().take(21).enumerate() {
+            data.extend(core::iter::repeat(symbol as u8 * 7).take(frequency as usize));
+        }
+        let mut seed = 1_u32;
+        for index in (1..data.len()).rev() {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            data.swap(index, (seed >> 8) as usize % (index + 1));
+        }
+        for level in [Level::FASTEST, Level::BEST] {
+            assert_eq!(inflate(&deflate_with(&data, level)).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn huffman_lengths_give_two_codes() {
+        assert_eq!(huffman_lengths(&[0, 5, 0], 15), [1, 1, 0]);
+        assert_eq!(huffman_lengths(&[0, 0, 0], 15), [1, 1, 0]);
+    }
+}
@@ -1,9 +1,18 @@
-#![feature(doc_cfg, doc_auto_cfg)]
+#![feature(doc_cfg, doc_auto_cfg, int_roundings)]
 #![doc = include_str!("../README.md")]
 //!
 //! ## Feature flags
 #![doc = document_features!()]
 
+extern crate alloc;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::Path;
+
+use bstr::BString;
+use derive_more::Display;
+use derive_more::Error;
 use derive_more::From;
 use derive_more::Into;
 use document_features::document_features;
@@ -11,21 +20,37 @@ use indexmap::IndexMap;
 use tap::Tap;
 use tracing::warn;
 
+pub use self::FileKind::*;
 #[doc(hidden)]
 use crate as zipng;
+use crate::deflate::Level;
 use crate::generic::default;
 use crate::generic::noop_mut;
 use crate::png::BitDepth;
+use crate::png::Chromaticities;
 use crate::png::ColorMode;
 use crate::png::EightBit;
-use crate::png::FourBit;
+use crate::png::Filter;
+use crate::png::FilterType;
+use crate::png::Gamma;
+use crate::png::IccProfile;
 use crate::png::Indexed;
 use crate::png::Lightness;
 use crate::png::OneBit;
+use crate::png::PhysicalDimensions;
 use crate::png::RedGreenBlue;
 use crate::png::RedGreenBlueAlpha;
+use crate::png::RenderingIntent;
+use crate::png::Text;
+use crate::png::Transparency;
 use crate::png::TwoBit;
 use crate::png::PALLETTE_8_BIT_DATA;
+use crate::zip::Auto;
+use crate::zip::Compression;
+use crate::zip::FileOrder;
+use crate::zip::Given;
+use crate::zip::Sorted;
+use crate::zip::Stored;
 #[doc(hidden)]
 pub use crate::zipng::r#impl::*;
 
@@ -48,6 +73,7 @@ pub mod r#impl {
     pub mod padding;
     pub mod png;
     pub mod text;
+    pub mod walk;
     pub mod zip;
     pub mod zlib;
 }
@@ -59,8 +85,20 @@ pub fn zip(files: &Files) -> Vec<u8> {
 
 /// Creates a zip file using custom options.
 pub fn zip_with(files: &Files, opts: Opts<ZipOptions>) -> Vec<u8> {
-    let _opts = ZipOptions::default().tap_mut(opts);
-    zip::zip(files.files.iter().map(|(k, v)| (k.as_ref(), v.as_ref())))
+    try_zip_with(files, opts).unwrap_or_else(|error| panic!("{error}"))
+}
+
+/// Creates a zip file, or returns an error if the files exceed the format's
+/// limits.
+pub fn try_zip(files: &Files) -> Result<Vec<u8>, Error> {
+    try_zip_with(files, noop_mut)
+}
+
+/// Creates a zip file using custom options, or returns an error if the files
+/// exceed the format's limits.
+pub fn try_zip_with(files: &Files, opts: Opts<ZipOptions>) -> Result<Vec<u8>, Error> {
+    let opts = ZipOptions::default().tap_mut(opts);
+    zip::zip(files.iter(), &opts)
 }
 
 /// Creates a "transparent zipng" zip file with the given files, in the given
@@ -72,11 +110,135 @@ pub fn zipng(files: &Files) -> Vec<u8> {
 type Opts<Options> = fn(&mut Options);
 
 /// Creates a "transparent zipng" zip file using custom options, with the
-/// given files, in the given order.1
+/// given files, in the given order.
+///
+/// The archive is the image's pixel data, which is never filtered,
+/// compressed or interlaced, so that its bytes are in the file as they are and
+/// its offsets can point into the image data. Each scanline is a stored block
+/// of its own, and the framing between scanlines would corrupt any record
+/// spanning it, so each record is moved to the next scanline where it
+/// wouldn't fit. The image is widened as needed for the longest record, which
+/// is each file's local header and body, or the central directory, to fit in
+/// a scanline. Scanlines can be at most 65534 bytes, so larger files can't
+/// be stored, and [`Error::ScanlineTooLong`] is returned for them.
 pub fn zipng_with(files: &Files, opts: Opts<ZipngOptions>) -> Vec<u8> {
-    let opts = ZipngOptions::default_for_data(&[]).tap_mut(opts);
-    todo!()
+    try_zipng_with(files, opts).unwrap_or_else(|error| panic!("{error}"))
+}
+
+/// Creates a "transparent zipng" zip file with the given files, in the given
+/// order, or returns an error if the files exceed the formats' limits.
+pub fn try_zipng(files: &Files) -> Result<Vec<u8>, Error> {
+    try_zipng_with(files, noop_mut)
 }
+
+/// Creates a "transparent zipng" zip file using custom options, with the
+/// given files, in the given order, or returns an error if the files exceed
+/// the formats' limits.
+pub fn try_zipng_with(files: &Files, opts: Opts<ZipngOptions>) -> Result<Vec<u8>, Error> {
+    zipng_with_fn(files, opts)
+}
+
+fn zipng_with_fn(files: &Files, opts: impl Fn(&mut ZipngOptions)) -> Result<Vec<u8>, Error> {
+    // The zip options don't depend on the data, but the PNG options do, so we
+    // apply the options twice: once here for the archive, and once below for the
+    // image.
+    let mut zip_opts = ZipngOptions::default().tap_mut(&opts).zip;
+    // the archive needs to be visible in the pixel data, with its records
+    // placed between the scanlines' framing rather than aligned
+    zip_opts.compression = Stored;
+    zip_opts.alignment = 1;
+    let files: Vec<_> = files.iter_stored().collect();
+    let mut files: Vec<(&[u8], &FileEntry)> = files
+        .iter()
+        .map(|(name, entry)| (*name, entry.as_ref()))
+        .collect();
+    zip::sort(&mut files, &zip_opts);
+
+    // Each record, whether a file's local header and body or the central
+    // directory and terminator, has to be contiguous in the file, so it has to
+    // fit in a scanline. A first pass finds their lengths.
+    let mut unplaced = zip::ZipWriter::new(Vec::new(), zip_opts.clone());
+    let mut lengths = Vec::new();
+    for &(name, entry) in &files {
+        let start = unplaced.offset();
+        unplaced.add(name, entry)?;
+        lengths.push((unplaced.offset() - start) as usize);
+    }
+    let directory_start = unplaced.offset() as usize;
+    let unplaced = unplaced.finish(b"")?;
+    let directory_length = unplaced.len() - directory_start;
+    let longest = lengths.iter().copied().fold(directory_length, usize::max);
+
+    let mut opts = ZipngOptions::default_for_data(&unplaced).tap_mut(&opts);
+    // the archive needs to be in the image data as it is
+    opts.png.filter = Filter::Fixed(FilterType::None);
+    opts.png.compression_level = Level::NONE;
+    opts.png.interlace = false;
+    // the archive needs to end the file
+    for text in &mut opts.png.text {
+        text.after_image = false;
+    }
+
+    let bits_per_pixel =
+        opts.png.bit_depth.bits_per_sample() * opts.png.color_mode.samples_per_pixel();
+    opts.png.width = opts.png.width.max((longest * 8).div_ceil(bits_per_pixel));
+    let bytes_per_line = opts.png.width.saturating_mul(bits_per_pixel).div_ceil(8);
+    if bytes_per_line > png::MAX_STORED_SCANLINE {
+        return Err(Error::ScanlineTooLong {
+            len: bytes_per_line,
+        });
+    }
+
+    // Records follow each other, except where one would run into the framing
+    // before the next scanline, when it starts that scanline instead. The
+    // central directory and terminator end the last scanline, so the archive
+    // comment only has to cover what follows the image data.
+    let stride = bytes_per_line + png::STORED_SCANLINE_GAP;
+    let fits = |position: usize, length: usize| position % stride + length <= bytes_per_line;
+    let mut positions = Vec::new();
+    let mut position = 0;
+    for length in lengths {
+        if !fits(position, length) {
+            position = position.div_ceil(stride) * stride;
+        }
+        positions.push(position);
+        position += length;
+    }
+    let mut last_line = position / stride;
+    if !fits(position, directory_length) {
+        last_line += 1;
+    }
+    let directory_position = last_line * stride + bytes_per_line - directory_length;
+    let (width, height) = opts.png.dimensions_for((last_line + 1) * bytes_per_line)?;
+
+    // The first scanline follows the IDAT chunk's length and type, the zlib
+    // header, and its own framing, and the archive's offsets are from the
+    // start of the file.
+    let mut writer = png::PngWriter::new(Vec::new(), width, height, &opts.png)?;
+    let start = writer.offset() + 8 + 2 + png::STORED_SCANLINE_GAP as u64;
+    let mut archive = zip::ZipWriter::with_offset(Vec::new(), zip_opts, start);
+    for (&(name, entry), position) in files.iter().zip(positions) {
+        archive.skip(start + position as u64 - archive.offset())?;
+        archive.add(name, entry)?;
+    }
+    archive.skip(start + directory_position as u64 - archive.offset())?;
+    // The archive comment covers the zlib stream's and IDAT chunk's checksums
+    // and the PNG footer, so that the archive still ends at the end of the
+    // file.
+    let trailer_length = 4 + 4 + 12;
+    let mut archive = archive.finish(&vec![0x00; trailer_length])?;
+    archive.truncate(archive.len() - trailer_length);
+
+    // the skipped bytes between scanlines are replaced by their framing
+    let pixels: Vec<u8> = archive
+        .chunks(stride)
+        .flat_map(|line| &line[..line.len().min(bytes_per_line)])
+        .copied()
+        .collect();
+    writer.write_stored(&pixels)?;
+    writer.finish()
+}
+
 /// Creates a zip file wherein all files are stored un-compressed, directly in
 /// the zip file as-is.
 pub fn sliceable_zip(files: &Files) -> Vec<u8> {
@@ -85,9 +247,32 @@ pub fn sliceable_zip(files: &Files) -> Vec<u8> {
 
 /// Creates a zip file using custom options wherein all files are stored
 /// un-compressed, directly in the zip file as-is.
+///
+/// Each non-empty file body is a contiguous byte range starting at a multiple
+/// of [`ZipOptions::alignment`], so it can be served directly out of the
+/// archive by byte range.
 pub fn sliceable_zip_with(files: &Files, opts: Opts<ZipOptions>) -> Vec<u8> {
-    let opts = ZipOptions::default().tap_mut(opts);
-    todo!()
+    try_sliceable_zip_with(files, opts).unwrap_or_else(|error| panic!("{error}"))
+}
+
+/// Creates a zip file wherein all files are stored un-compressed, directly in
+/// the zip file as-is, or returns an error if the files exceed the format's
+/// limits.
+pub fn try_sliceable_zip(files: &Files) -> Result<Vec<u8>, Error> {
+    try_sliceable_zip_with(files, noop_mut)
+}
+
+/// Creates a zip file using custom options wherein all files are stored
+/// un-compressed, directly in the zip file as-is, or returns an error if the
+/// files exceed the format's limits.
+pub fn try_sliceable_zip_with(files: &Files, opts: Opts<ZipOptions>) -> Result<Vec<u8>, Error> {
+    let mut opts = ZipOptions::default().tap_mut(opts);
+    opts.compression = Stored;
+    let files: Vec<_> = files.iter_stored().collect();
+    zip::zip(
+        files.iter().map(|(name, entry)| (*name, entry.as_ref())),
+        &opts,
+    )
 }
 
 /// Creates a PNG file with the given image data.
@@ -100,9 +285,30 @@ pub fn png(body: &[u8]) -> Vec<u8> {
 }
 
 /// Creates a PNG file with the given image data and options.
+///
+/// The height of the image is derived from the length of the data, with the
+/// last scanline padded with zeroes if necessary.
 pub fn png_with(body: &[u8], opts: Opts<PngOptions>) -> Vec<u8> {
-    let opts = PngOptions::default().tap_mut(opts);
-    todo!()
+    try_png_with(body, opts).unwrap_or_else(|error| panic!("{error}"))
+}
+
+/// Creates a PNG file with the given image data, or returns an error if the
+/// data exceeds the format's limits.
+pub fn try_png(body: &[u8]) -> Result<Vec<u8>, Error> {
+    try_png_with(body, noop_mut)
+}
+
+/// Creates a PNG file with the given image data and options, or returns an
+/// error if the data exceeds the format's limits, or
+/// [`Error::ImageTooTall`] if the image would be taller than
+/// [`PngOptions::max_height`].
+pub fn try_png_with(body: &[u8], opts: Opts<PngOptions>) -> Result<Vec<u8>, Error> {
+    let opts = ZipngOptions::default_for_data(body).png.tap_mut(opts);
+    let (width, height) = opts.dimensions_for(body.len())?;
+
+    let mut writer = png::PngWriter::new(Vec::new(), width, height, &opts)?;
+    writer.write_padded(body)?;
+    writer.finish()
 }
 
 #[cfg(feature = "brotli")]
@@ -116,30 +322,567 @@ pub fn zipngbr(files: &Files) -> Vec<u8> {
 /// Creates a "transparent zipng" zip file using custom options, with the
 /// given files, in the given order, and then compresses it with `brotli`.
 pub fn zipngbr_with(files: &Files, opts: Opts<ZipngBrOptions>) -> Vec<u8> {
-    let _opts = ZipngBrOptions::default().tap_mut(opts);
-    brotli::compress(zipng(files).as_slice()).to_vec()
+    try_zipngbr_with(files, opts).unwrap_or_else(|error| panic!("{error}"))
+}
+
+#[cfg(feature = "brotli")]
+/// Creates a "transparent zipng" zip file with the given files, in the given
+/// order, and then compresses it with `brotli`, or returns an error if the
+/// files exceed the formats' limits.
+pub fn try_zipngbr(files: &Files) -> Result<Vec<u8>, Error> {
+    try_zipngbr_with(files, noop_mut)
+}
+
+#[cfg(feature = "brotli")]
+/// Creates a "transparent zipng" zip file using custom options, with the
+/// given files, in the given order, and then compresses it with `brotli`, or
+/// returns an error if the files exceed the formats' limits.
+pub fn try_zipngbr_with(files: &Files, opts: Opts<ZipngBrOptions>) -> Result<Vec<u8>, Error> {
+    let zipng = zipng_with_fn(files, |zipng_opts| {
+        let mut zipngbr_opts = ZipngBrOptions {
+            png: zipng_opts.png.clone(),
+            zip: zipng_opts.zip.clone(),
+            br: default(),
+        };
+        opts(&mut zipngbr_opts);
+        zipng_opts.png = zipngbr_opts.png;
+        zipng_opts.zip = zipngbr_opts.zip;
+    })?;
+    Ok(brotli::compress(&zipng))
+}
+
+/// Recovers the zip archive stored in the pixels of an image created by
+/// [`zipng`], even if the image has since been losslessly re-encoded in a way
+/// that changed its bytes or color type.
+///
+/// The pixels are mapped back to bytes with each of the formats that
+/// [`ZipngOptions::default_for_data`] may choose, so this won't find archives
+/// in images created with other PNG options. The archive's offsets are into
+/// the original file, so it's returned laid out as it was there, with
+/// everything but the image data zeroed.
+pub fn extract_from_pixels(png: &[u8]) -> Result<Vec<u8>, Error> {
+    let image = png::read_png(png)?;
+    let pixels = image.to_rgba8();
+
+    let formats = [
+        (OneBit, Lightness),
+        (TwoBit, Lightness),
+        (EightBit, Indexed),
+        (EightBit, RedGreenBlue),
+        (EightBit, RedGreenBlueAlpha),
+    ];
+    for (bit_depth, color_mode) in formats {
+        let Some(data) = pixels_to_samples(&pixels, image.width, bit_depth, color_mode) else {
+            continue;
+        };
+        let bytes_per_line = data.len() / image.height as usize;
+        if let Some(archive) = rebuild_archive(&data, bytes_per_line) {
+            return Ok(archive);
+        }
+    }
+
+    Err(Error::NoArchiveInPixels)
+}
+
+/// Lays out scanlines of samples as [`zipng_with`] did in the file, if they
+/// hold a valid zip archive ending with the last scanline.
+fn rebuild_archive(data: &[u8], bytes_per_line: usize) -> Option<Vec<u8>> {
+    let stride = bytes_per_line + png::STORED_SCANLINE_GAP;
+    let in_file = |position: usize| position / bytes_per_line * stride + position % bytes_per_line;
+
+    // the terminator ends the last scanline, with a comment covering the file's
+    // trailer
+    let terminator = data.len().checked_sub(22)?;
+    let field = |offset: usize| {
+        let bytes = &data[terminator + offset..terminator + offset + 4];
+        u32::from_le_bytes(bytes.try_into().unwrap()) as usize
+    };
+    if !data[terminator..].starts_with(b"PK\x05\x06") || data[terminator + 20..] != [20, 0] {
+        return None;
+    }
+    // the central directory is in the same scanline as the terminator, and
+    // its offset in the file tells us where the image data started
+    let directory = terminator.checked_sub(field(12))?;
+    let start = field(16).checked_sub(in_file(directory))?;
+
+    let mut archive = vec![0x00; start];
+    for line in data.chunks(bytes_per_line) {
+        if archive.len() > start {
+            archive.extend_from_slice(&[0x00; png::STORED_SCANLINE_GAP]);
+        }
+        archive.extend_from_slice(line);
+    }
+    archive.extend_from_slice(&[0x00; 20]);
+
+    zip::Archive::new(&archive).ok()?.verify().ok()?;
+    Some(archive)
+}
+
+/// Packs pixel colors into scanlines of samples of the given format, or
+/// returns `None` if any of the colors can't be represented in it.
+fn pixels_to_samples(
+    pixels: &[[u8; 4]],
+    width: u32,
+    bit_depth: BitDepth,
+    color_mode: ColorMode,
+) -> Option<Vec<u8>> {
+    let bits = bit_depth.bits_per_sample();
+    let max = (1_u32 << bits) - 1;
+    let palette: HashMap<&[u8], u8> = PALLETTE_8_BIT_DATA
+        .chunks(3)
+        .enumerate()
+        .map(|(index, color)| (color, index as u8))
+        .collect();
+
+    let mut data = Vec::new();
+    for line in pixels.chunks(width as usize) {
+        let mut samples = Vec::new();
+        for &[red, green, blue, alpha] in line {
+            match color_mode {
+                Lightness => {
+                    let sample = u32::from(red) * max / 255;
+                    if red != green || green != blue || sample * 255 / max != red.into() {
+                        return None;
+                    }
+                    samples.push(sample as u8);
+                },
+                Indexed => samples.push(*palette.get(&[red, green, blue][..])?),
+                RedGreenBlue => samples.extend_from_slice(&[red, green, blue]),
+                _ => samples.extend_from_slice(&[red, green, blue, alpha]),
+            }
+        }
+
+        if bits == 8 {
+            data.extend_from_slice(&samples);
+        } else {
+            let per_byte = 8 / bits;
+            data.extend(samples.chunks(per_byte).map(|chunk| {
+                chunk.iter().enumerate().fold(0, |byte, (index, sample)| {
+                    byte | sample << (8 - bits * (index + 1))
+                })
+            }));
+        }
+    }
+    Some(data)
+}
+
+/// Errors that can occur while encoding, when the input exceeds the limits of
+/// the output format, or while decoding malformed input.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Display, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// A file name is too long to be stored in a zip archive.
+    #[display(fmt = "file name {name:?} is {len} bytes, larger than 64KiB")]
+    NameTooLong { name: BString, len: usize },
+    /// The zip archive comment (suffix), or a file comment, is too long to be
+    /// stored.
+    #[display(fmt = "zip comment is {len} bytes, larger than 64KiB")]
+    CommentTooLong { len: usize },
+    /// The zip archive comment (suffix) contains the signature of the
+    /// archive terminator, which would confuse readers looking for it.
+    #[display(fmt = "zip archive comment must not contain terminator signature PK\\x05\\x06")]
+    CommentContainsTerminator,
+    /// A PNG chunk is too large to be stored.
+    #[display(fmt = "png {chunk_type:?} chunk is {size} bytes, larger than 2GiB")]
+    ChunkTooLarge { chunk_type: BString, size: usize },
+    /// The image is too wide for its width to be stored.
+    #[display(fmt = "image width of {width} pixels exceeds maximum of 2Gi")]
+    ImageTooWide { width: usize },
+    /// The data doesn't fit in an image of the given width without exceeding
+    /// [`PngOptions::max_height`], or the format's own limit.
+    #[display(fmt = "image height of {height} pixels exceeds maximum of {max_height}")]
+    ImageTooTall { height: usize, max_height: usize },
+    /// A scanline of an image made by [`zipng_with`] would be longer than the
+    /// largest stored block, because of the image's width or the length of a
+    /// record that has to fit in it.
+    #[display(fmt = "zipng scanline of {len} bytes is larger than the maximum of 65534")]
+    ScanlineTooLong { len: usize },
+    /// A DEFLATE stream ended before its final block did.
+    #[display(fmt = "deflate stream truncated at bit {bit_offset}")]
+    TruncatedDeflate { bit_offset: usize },
+    /// A DEFLATE stream is malformed. The offset is of the first bit of the
+    /// offending field, from the start of the stream.
+    #[display(fmt = "invalid deflate stream at bit {bit_offset}: {reason}")]
+    InvalidDeflate {
+        bit_offset: usize,
+        reason: &'static str,
+    },
+    /// Decoding would produce more output than the given limit allows.
+    #[display(fmt = "decoded output exceeds limit of {limit} bytes")]
+    OutputLimitExceeded { limit: usize },
+    /// A zlib stream's header or trailer is malformed.
+    #[display(fmt = "invalid zlib stream: {reason}")]
+    InvalidZlib { reason: &'static str },
+    /// A zlib stream was compressed with a preset dictionary that wasn't
+    /// given.
+    #[display(fmt = "zlib stream requires preset dictionary with Adler-32 {dictionary_id:08X}")]
+    ZlibDictionaryRequired { dictionary_id: u32 },
+    /// A zip archive is malformed. The offset is from the start of the buffer
+    /// it was read from.
+    #[display(fmt = "invalid zip archive at byte {offset}: {reason}")]
+    InvalidZip { offset: usize, reason: &'static str },
+    /// A zip archive uses a feature that isn't supported.
+    #[display(fmt = "unsupported zip archive feature: {reason}")]
+    UnsupportedZip { reason: &'static str },
+    /// A zip entry is compressed with a method that isn't supported.
+    #[display(fmt = "unsupported zip compression method {method}")]
+    UnsupportedCompression { method: u16 },
+    /// A PNG file is malformed. The offset is from the start of the file.
+    #[display(fmt = "invalid png file at byte {offset}: {reason}")]
+    InvalidPng { offset: usize, reason: &'static str },
+    /// A PNG file uses a feature that isn't supported.
+    #[display(fmt = "unsupported png feature: {reason}")]
+    UnsupportedPng { reason: &'static str },
+    /// A PNG chunk to be written has contents the format doesn't allow.
+    #[display(fmt = "invalid png {chunk_type:?} chunk: {reason}")]
+    InvalidPngChunk {
+        chunk_type: BString,
+        reason: &'static str,
+    },
+    /// No zip archive could be found in an image's pixels.
+    #[display(fmt = "no zip archive found in image pixels")]
+    NoArchiveInPixels,
+    /// Decoded data doesn't match the checksum stored alongside it.
+    #[display(fmt = "{checksum} checksum mismatch: stored {stored:08X}, computed {computed:08X}")]
+    ChecksumMismatch {
+        checksum: &'static str,
+        stored: u32,
+        computed: u32,
+    },
+    /// An I/O operation failed, on the file at `path` if it isn't empty.
+    #[display(
+        fmt = "{}{message}",
+        r#"if path.is_empty() { String::new() } else { format!("{path}: ") }"#
+    )]
+    Io {
+        path: BString,
+        kind: std::io::ErrorKind,
+        message: String,
+    },
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io {
+            path: BString::default(),
+            kind: error.kind(),
+            message: error.to_string(),
+        }
+    }
 }
 
 /// Files to be included in a zip archive.
-#[derive(Debug, Default, Clone, PartialEq, Eq, From, Into)]
+///
+/// This can be built up with [`Files::add`] and the other builder methods, or
+/// converted from a map of names to bodies or to [`FileEntry`]s.
+#[derive(Debug, Default, Clone)]
 pub struct Files {
-    files: IndexMap<Vec<u8>, Vec<u8>>,
+    files: IndexMap<Vec<u8>, FileEntry>,
+    /// The index of the file added most recently, which may have replaced one
+    /// earlier in the order.
+    last_added: Option<usize>,
 }
 
-/// Zip archive options.
+impl Files {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the files in the directory at `path`, named by their paths
+    /// relative to it, in the order that [`zip::sort`] puts them in by
+    /// default.
+    pub fn from_dir(path: impl AsRef<Path>, opts: &DirOptions) -> Result<Self, Error> {
+        let mut files = walk::walk(path.as_ref(), opts)?;
+        let zip_opts = ZipOptions::default();
+        files.sort_by(|(a_name, a), (b_name, b)| {
+            zip::sort_key(a_name, a, &zip_opts).cmp(&zip::sort_key(b_name, b, &zip_opts))
+        });
+        Ok(Self::from(files.into_iter().collect::<IndexMap<_, _>>()))
+    }
+
+    /// Iterates over the names and entries of the files, in insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &FileEntry)> {
+        self.files.iter().map(|(k, v)| (k.as_ref(), v))
+    }
+
+    /// Iterates over the files with any per-file compression cleared, for
+    /// archives whose bodies must all be stored.
+    fn iter_stored(&self) -> impl Iterator<Item = (&[u8], Cow<'_, FileEntry>)> {
+        self.iter().map(|(name, entry)| {
+            let entry = if entry.compression.is_some() {
+                Cow::Owned(FileEntry {
+                    compression: None,
+                    ..entry.clone()
+                })
+            } else {
+                Cow::Borrowed(entry)
+            };
+            (name, entry)
+        })
+    }
+
+    /// Adds a file with the given entry, replacing any existing file with the
+    /// same name but keeping its position.
+    pub fn add_entry(mut self, name: impl Into<Vec<u8>>, entry: FileEntry) -> Self {
+        let (index, _) = self.files.insert_full(name.into(), entry);
+        self.last_added = Some(index);
+        self
+    }
+
+    /// Adds a regular file with the given body.
+    pub fn add(self, name: impl Into<Vec<u8>>, body: impl Into<Vec<u8>>) -> Self {
+        self.add_entry(name, FileEntry::from(body.into()))
+    }
+
+    /// Adds a directory, appending a slash to its name if it doesn't already
+    /// end with one.
+    pub fn add_dir(self, name: impl Into<Vec<u8>>) -> Self {
+        let mut name = name.into();
+        if !name.ends_with(b"/") {
+            name.push(b'/');
+        }
+        self.add_entry(name, FileEntry {
+            kind: Directory,
+            ..default()
+        })
+    }
+
+    /// Adds a symbolic link pointing to `target`.
+    pub fn add_symlink(self, name: impl Into<Vec<u8>>, target: impl Into<Vec<u8>>) -> Self {
+        self.add_entry(name, FileEntry {
+            body: target.into(),
+            kind: Symlink,
+            ..default()
+        })
+    }
+
+    /// Sets the Unix permission bits of the file added most recently.
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        if let Some((_, entry)) = self
+            .last_added
+            .and_then(|index| self.files.get_index_mut(index))
+        {
+            entry.mode = Some(mode);
+        }
+        self
+    }
+}
+
+/// Files are equal if they have the same entries in the same order, however
+/// they were added.
+impl PartialEq for Files {
+    fn eq(&self, other: &Self) -> bool {
+        self.files == other.files
+    }
+}
+
+impl Eq for Files {}
+
+impl From<IndexMap<Vec<u8>, FileEntry>> for Files {
+    fn from(files: IndexMap<Vec<u8>, FileEntry>) -> Self {
+        Self {
+            files,
+            last_added: None,
+        }
+    }
+}
+
+impl From<Files> for IndexMap<Vec<u8>, FileEntry> {
+    fn from(files: Files) -> Self {
+        files.files
+    }
+}
+
+impl From<IndexMap<Vec<u8>, Vec<u8>>> for Files {
+    fn from(files: IndexMap<Vec<u8>, Vec<u8>>) -> Self {
+        let files: IndexMap<_, _> = files
+            .into_iter()
+            .map(|(name, body)| (name, FileEntry::from(body)))
+            .collect();
+        Self::from(files)
+    }
+}
+
+/// A file to be included in a zip archive, with its metadata.
 #[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileEntry {
+    /// The contents of a regular file, or the target of a symbolic link.
+    pub body: Vec<u8>,
+    pub kind: FileKind,
+    /// The modification time, in seconds since the Unix epoch, or `None` to
+    /// use [`ZipOptions::mtime`].
+    pub mtime: Option<i64>,
+    /// The Unix permission bits, such as `0o755`, or `None` for the default
+    /// for its kind.
+    pub mode: Option<u32>,
+    pub comment: Vec<u8>,
+    /// How the body is compressed, or `None` to use
+    /// [`ZipOptions::compression`]. This is ignored by
+    /// [`sliceable_zip_with`] and [`zipng_with`], which always store bodies.
+    pub compression: Option<Compression>,
+}
+
+impl From<Vec<u8>> for FileEntry {
+    fn from(body: Vec<u8>) -> Self {
+        Self { body, ..default() }
+    }
+}
+
+/// The kind of a [`FileEntry`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum FileKind {
+    #[default]
+    File,
+    Directory,
+    Symlink,
+}
+
+/// Zip archive options.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub struct ZipOptions {
+    /// The byte alignment of non-empty file bodies and of the central
+    /// directory, relative to the start of the output. This is ignored by
+    /// [`zipng_with`], which places records to fit in scanlines instead.
+    pub alignment: usize,
+    /// The order in which files are written.
+    pub order: FileOrder,
+    /// Whether a file named `mimetype` is written first, unaligned and
+    /// uncompressed, as required by package formats including EPUB and ODT.
+    pub mimetype_first: bool,
+    /// How file bodies are compressed. This is ignored by
+    /// [`sliceable_zip_with`] and [`zipng_with`], which always store them.
+    pub compression: Compression,
+    /// The level of compression used for deflated file bodies.
+    pub compression_level: Level,
+    /// The modification time of files that don't have their own, in seconds
+    /// since the Unix epoch. This defaults to [`zip::default_mtime`].
+    pub mtime: i64,
+    /// Whether to include extended timestamp extra fields, which store
+    /// modification times in UTC to the second, rather than only the
+    /// two-second precision and unspecified time zone of the MS-DOS fields.
+    pub extended_timestamps: bool,
+}
+
+impl Default for ZipOptions {
+    fn default() -> Self {
+        Self {
+            alignment: zip::BLOCK_SIZE,
+            order: Sorted,
+            mimetype_first: true,
+            compression: Auto,
+            compression_level: default(),
+            mtime: zip::default_mtime(),
+            extended_timestamps: false,
+        }
+    }
+}
+
+/// Options for reading files from a directory with [`Files::from_dir`].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[non_exhaustive]
-pub struct ZipOptions {}
+pub struct DirOptions {
+    /// `.gitignore`-style patterns of paths to leave out, relative to the
+    /// directory.
+    pub exclude: Vec<String>,
+    /// Whether patterns in any `.gitignore` files in the tree are also
+    /// honoured.
+    pub gitignore: bool,
+    /// Whether symbolic links are followed, adding what they point to, rather
+    /// than being added as links themselves. Dangling links are always added
+    /// as links.
+    pub follow_symlinks: bool,
+    /// Whether directories get their own entries, which preserves empty ones.
+    pub directories: bool,
+    /// Whether files get their modification times from the file system,
+    /// rather than [`ZipOptions::mtime`], which is the default for the sake
+    /// of reproducible output.
+    pub mtimes: bool,
+    /// Whether files get their permission bits from the file system, on Unix.
+    pub modes: bool,
+    /// Whether names that are valid UTF-8 are normalized to NFC, so that those
+    /// read from file systems that decompose them, like macOS's, match those
+    /// read elsewhere.
+    pub normalize_unicode: bool,
+}
+
+impl Default for DirOptions {
+    fn default() -> Self {
+        Self {
+            exclude: Vec::new(),
+            gitignore: false,
+            follow_symlinks: false,
+            directories: true,
+            mtimes: false,
+            modes: true,
+            normalize_unicode: true,
+        }
+    }
+}
 
 /// PNG encoding options.
 #[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[non_exhaustive]
 pub struct PngOptions {
+    /// The width of the image, in pixels. [`zipng_with`] widens it as
+    /// needed for each zip record to fit in a scanline.
     pub width: usize,
+    /// The maximum height of the image, in pixels, or zero for no limit.
     pub max_height: usize,
     pub bit_depth: BitDepth,
     pub color_mode: ColorMode,
     pub color_palette: Option<Vec<u8>>,
+    /// How each scanline's filter type is chosen. This is ignored by
+    /// [`zipng_with`], which never filters them.
+    pub filter: Filter,
+    /// The level of compression used for the image data. This is ignored by
+    /// [`zipng_with`], which always stores it.
+    pub compression_level: Level,
+    /// Whether the image is interlaced with Adam7, so that it can be displayed
+    /// progressively as it loads. This is ignored by [`zipng_with`], whose
+    /// image data needs to be in order.
+    pub interlace: bool,
+    /// Textual metadata. [`zipng_with`] writes it all before the image data,
+    /// as the archive needs to end the file.
+    pub text: Vec<Text>,
+    /// The rendering intent of an image in the sRGB color space, which can't
+    /// be given along with [`PngOptions::icc_profile`].
+    pub srgb: Option<RenderingIntent>,
+    pub gamma: Option<Gamma>,
+    pub chromaticities: Option<Chromaticities>,
+    /// An embedded ICC profile describing the image's color space.
+    pub icc_profile: Option<IccProfile>,
+    /// The colors that are transparent, for color modes without an alpha
+    /// channel. A zipng's padding is all zero bytes, so it's transparent if the
+    /// zero sample or first palette entry is.
+    pub transparency: Option<Transparency>,
+    /// The physical size of the image's pixels, or their aspect ratio.
+    pub physical_dimensions: Option<PhysicalDimensions>,
+}
+
+impl PngOptions {
+    /// Returns the width and height of an image holding `len` bytes of
+    /// data, with the last scanline padded as necessary.
+    pub fn dimensions_for(&self, len: usize) -> Result<(u32, u32), Error> {
+        let width = self.width.max(1);
+        if width > png::MAX_DIMENSION {
+            return Err(Error::ImageTooWide { width });
+        }
+        let width = width as u32;
+
+        let bytes_per_line = png::bytes_per_line(width, self.bit_depth, self.color_mode);
+        let height = len.div_ceil(bytes_per_line).max(1);
+
+        let max_height = match self.max_height {
+            0 => png::MAX_DIMENSION,
+            max_height => max_height.min(png::MAX_DIMENSION),
+        };
+        if height > max_height {
+            return Err(Error::ImageTooTall { height, max_height });
+        }
+
+        Ok((width, height as u32))
+    }
 }
 
 #[cfg(feature = "brotli")]
@@ -148,13 +891,25 @@ pub struct PngOptions {
 #[non_exhaustive]
 pub struct BrotliOptions {}
 
-#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Into, From)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Into, From)]
 #[non_exhaustive]
 pub struct ZipngOptions {
     pub png: PngOptions,
     pub zip: ZipOptions,
 }
 
+impl Default for ZipngOptions {
+    fn default() -> Self {
+        Self {
+            png: default(),
+            zip: ZipOptions {
+                order: Given,
+                ..default()
+            },
+        }
+    }
+}
+
 impl ZipngOptions {
     pub fn default_for_data(data: &[u8]) -> Self {
         let mut opts = Self::default();
@@ -216,10 +971,22 @@ impl ZipngOptions {
 }
 
 #[cfg(feature = "brotli")]
-#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Into, From)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Into, From)]
 #[non_exhaustive]
 pub struct ZipngBrOptions {
     pub png: PngOptions,
     pub zip: ZipOptions,
     pub br: BrotliOptions,
 }
+
+#[cfg(feature = "brotli")]
+impl Default for ZipngBrOptions {
+    fn default() -> Self {
+        let ZipngOptions { png, zip } = default();
+        Self {
+            png,
+            zip,
+            br: default(),
+        }
+    }
+}
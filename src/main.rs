@@ -0,0 +1,966 @@
+//! The `zipng` command-line tool, for creating and checking zip archives,
+//! PNG images, and polyglots of the two.
+
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Read;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::str::FromStr;
+
+use derive_more::Display;
+use derive_more::Error;
+use once_cell::sync::OnceCell;
+use zipng::deflate::Level;
+use zipng::extract_from_pixels;
+use zipng::png;
+use zipng::png::BitDepth;
+use zipng::png::ColorMode;
+use zipng::png::Filter;
+use zipng::png::FilterType;
+use zipng::try_png_with;
+use zipng::try_sliceable_zip_with;
+use zipng::try_zip_with;
+use zipng::try_zipng_with;
+#[cfg(feature = "brotli")]
+use zipng::try_zipngbr_with;
+use zipng::walk;
+use zipng::zip;
+use zipng::zip::Compression;
+use zipng::zip::FileOrder;
+use zipng::DirOptions;
+use zipng::Directory;
+use zipng::FileEntry;
+use zipng::Files;
+use zipng::PngOptions;
+use zipng::ZipOptions;
+
+const USAGE: &str = "\
+Usage: zipng <COMMAND> [OPTIONS] [INPUT]...
+
+Commands:
+  zip        Create a zip archive
+  zipng      Create a zip archive that's also a PNG image of itself
+  sliceable  Create a zip archive with every body stored and aligned
+  png        Create a PNG image whose pixel data is the input's bytes
+  br         Create a zipng image compressed with brotli
+  extract    Extract the files from an archive
+  list       List the files in an archive
+  verify     Check an archive's and image's checksums
+  inspect    Show the structure of an archive or image
+  help       Show this message
+
+Inputs are files and directories, which are added with their paths as given,
+or - for standard input. The extract, list, verify and inspect commands take
+a single input, followed by any names to extract.
+
+Output options:
+  -o, --output <FILE>          Write to FILE rather than standard output
+  -d, --directory <DIR>        Extract into DIR rather than the current directory
+      --stdin-name <NAME>      Name the file read from standard input [default: stdin]
+      --pixels                 Read the archive from an image's pixels
+      --brotli                 Decompress the input with brotli first
+
+Input options:
+      --exclude <PATTERN>      Leave out paths matching a .gitignore-style pattern
+      --gitignore              Honour .gitignore files in input directories
+      --follow-symlinks        Add what symbolic links point to, not the links
+      --no-directories         Don't add entries for directories
+      --mtimes                 Use files' modification times
+      --no-modes               Don't use files' permission bits
+      --no-normalize-unicode   Don't normalize file names to NFC
+
+Zip options:
+      --alignment <BYTES>      Align file bodies to multiples of BYTES
+      --order <ORDER>          Order files `sorted` or as `given`
+      --[no-]mimetype-first    Write a file named mimetype first and unaligned
+      --compression <METHOD>   Compress bodies: `stored`, `deflated` or `auto`
+      --level <0-9>            DEFLATE compression level, of images too
+      --mtime <SECONDS>        Modification time of files without their own
+      --[no-]extended-timestamps
+                               Write modification times to the second, in UTC
+
+PNG options:
+      --width <PIXELS>         Image width
+      --max-height <PIXELS>    Maximum image height, or 0 for no limit
+      --bit-depth <BITS>       Bits per sample: 1, 2, 4, 8 or 16
+      --color-mode <MODE>      `lightness`, `rgb`, `indexed`, `lightness-alpha`
+                               or `rgba`
+      --palette <FILE>         Palette of RGB triples for indexed images
+      --no-palette             Don't write a palette
+      --palette-alpha <ALPHAS> Comma-separated alpha of each palette entry
+      --transparent <SAMPLES>  Lightness or comma-separated red, green and blue
+                               samples of the color to make transparent
+      --filter <FILTER>        Scanline filter: `none`, `sub`, `up`, `average` or
+                               `paeth`, or chosen per scanline by `minimum-sum`
+                               or `brute-force`
+      --interlace              Interlace the image with Adam7
+      --text <KEYWORD=TEXT>    Add text metadata, such as Title=...
+      --compressed-text <KEYWORD=TEXT>
+                               Add text metadata compressed with zlib
+      --text-language <TAG>    Language of the text metadata, such as en-GB
+      --text-after-image       Write text metadata after the image data
+      --srgb <INTENT>          Mark the image as sRGB, with a rendering intent of
+                               `perceptual`, `relative`, `saturation` or
+                               `absolute`
+      --gamma <GAMMA>          Image gamma, such as 0.45455
+      --chromaticities <srgb|WX,WY,RX,RY,GX,GY,BX,BY>
+                               White point and primaries as CIE x,y coordinates
+      --icc-profile <FILE>     Embed an ICC color profile, named after the file
+      --dpi <DPI>              Physical pixel density, in dots per inch
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Zip,
+    Zipng,
+    Sliceable,
+    Png,
+    #[cfg(feature = "brotli")]
+    Br,
+    Extract,
+    List,
+    Verify,
+    Inspect,
+    Help,
+    Version,
+}
+
+/// The options given on the command line. Those for the library are only
+/// set if they were given, so that its own defaults apply otherwise, including
+/// those that depend on the data.
+#[derive(Debug, Default)]
+struct Flags {
+    inputs: Vec<OsString>,
+    output: Option<PathBuf>,
+    directory: Option<PathBuf>,
+    stdin_name: Option<Vec<u8>>,
+    pixels: bool,
+    #[cfg(feature = "brotli")]
+    brotli: bool,
+    dir: DirOptions,
+
+    alignment: Option<usize>,
+    order: Option<FileOrder>,
+    mimetype_first: Option<bool>,
+    compression: Option<Compression>,
+    level: Option<Level>,
+    mtime: Option<i64>,
+    extended_timestamps: Option<bool>,
+
+    width: Option<usize>,
+    max_height: Option<usize>,
+    bit_depth: Option<BitDepth>,
+    color_mode: Option<ColorMode>,
+    color_palette: Option<Option<Vec<u8>>>,
+    filter: Option<Filter>,
+    interlace: Option<bool>,
+    text: Vec<png::Text>,
+    text_language: Option<String>,
+    text_after_image: bool,
+    srgb: Option<png::RenderingIntent>,
+    gamma: Option<png::Gamma>,
+    chromaticities: Option<png::Chromaticities>,
+    icc_profile: Option<png::IccProfile>,
+    transparency: Option<png::Transparency>,
+    physical_dimensions: Option<png::PhysicalDimensions>,
+}
+
+impl Flags {
+    fn apply_zip(&self, opts: &mut ZipOptions) {
+        if let Some(alignment) = self.alignment {
+            opts.alignment = alignment;
+        }
+        if let Some(order) = self.order {
+            opts.order = order;
+        }
+        if let Some(mimetype_first) = self.mimetype_first {
+            opts.mimetype_first = mimetype_first;
+        }
+        if let Some(compression) = self.compression {
+            opts.compression = compression;
+        }
+        if let Some(level) = self.level {
+            opts.compression_level = level;
+        }
+        if let Some(mtime) = self.mtime {
+            opts.mtime = mtime;
+        }
+        if let Some(extended_timestamps) = self.extended_timestamps {
+            opts.extended_timestamps = extended_timestamps;
+        }
+    }
+
+    fn apply_png(&self, opts: &mut PngOptions) {
+        if let Some(width) = self.width {
+            opts.width = width;
+        }
+        if let Some(max_height) = self.max_height {
+            opts.max_height = max_height;
+        }
+        if let Some(bit_depth) = self.bit_depth {
+            opts.bit_depth = bit_depth;
+        }
+        if let Some(color_mode) = self.color_mode {
+            opts.color_mode = color_mode;
+        }
+        if let Some(color_palette) = &self.color_palette {
+            opts.color_palette = color_palette.clone();
+        }
+        if let Some(filter) = self.filter {
+            opts.filter = filter;
+        }
+        if let Some(level) = self.level {
+            opts.compression_level = level;
+        }
+        if let Some(interlace) = self.interlace {
+            opts.interlace = interlace;
+        }
+        for text in &self.text {
+            let mut text = text.clone();
+            if let Some(language) = &self.text_language {
+                text.language = language.clone();
+            }
+            text.after_image = self.text_after_image;
+            opts.text.push(text);
+        }
+        if let Some(srgb) = self.srgb {
+            opts.srgb = Some(srgb);
+        }
+        if let Some(gamma) = self.gamma {
+            opts.gamma = Some(gamma);
+        }
+        if let Some(chromaticities) = self.chromaticities {
+            opts.chromaticities = Some(chromaticities);
+        }
+        if let Some(icc_profile) = &self.icc_profile {
+            opts.icc_profile = Some(icc_profile.clone());
+        }
+        if let Some(transparency) = &self.transparency {
+            opts.transparency = Some(transparency.clone());
+        }
+        if let Some(physical_dimensions) = self.physical_dimensions {
+            opts.physical_dimensions = Some(physical_dimensions);
+        }
+    }
+}
+
+/// The library takes options as functions that can't capture anything, so
+/// they read the flags from here.
+static FLAGS: OnceCell<Flags> = OnceCell::new();
+
+fn flags() -> &'static Flags {
+    FLAGS.get().expect("flags are parsed before they're used")
+}
+
+/// An invalid command line.
+#[derive(Debug, Display, Error)]
+#[display(fmt = "{_0}")]
+struct Usage(#[error(not(source))] String);
+
+type Result<T, E = Box<dyn std::error::Error>> = std::result::Result<T, E>;
+
+fn main() -> ExitCode {
+    let (command, flags) = match parse(std::env::args_os().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(usage) => {
+            eprintln!("zipng: {usage}\nTry 'zipng help' for more information.");
+            return ExitCode::from(2);
+        },
+    };
+    FLAGS.set(flags).expect("flags are only parsed once");
+
+    match run(command) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("zipng: {error}");
+            if error.is::<Usage>() {
+                eprintln!("Try 'zipng help' for more information.");
+                ExitCode::from(2)
+            } else {
+                ExitCode::FAILURE
+            }
+        },
+    }
+}
+
+fn parse(args: impl IntoIterator<Item = OsString>) -> Result<(Command, Flags), Usage> {
+    let mut args = args.into_iter();
+    let command = match args.next().as_ref().and_then(|arg| arg.to_str()) {
+        Some("zip") => Command::Zip,
+        Some("zipng") => Command::Zipng,
+        Some("sliceable") => Command::Sliceable,
+        Some("png") => Command::Png,
+        #[cfg(feature = "brotli")]
+        Some("br") => Command::Br,
+        Some("extract") => Command::Extract,
+        Some("list") => Command::List,
+        Some("verify") => Command::Verify,
+        Some("inspect") => Command::Inspect,
+        Some("help" | "-h" | "--help") | None => Command::Help,
+        Some("-V" | "--version") => Command::Version,
+        Some(other) => return Err(Usage(format!("unknown command {other:?}"))),
+    };
+
+    let mut flags = Flags::default();
+    let mut only_inputs = false;
+    while let Some(arg) = args.next() {
+        let text = match arg.to_str() {
+            Some(text) if !only_inputs && text.starts_with('-') && text != "-" => text,
+            _ => {
+                flags.inputs.push(arg);
+                continue;
+            },
+        };
+        let (name, inline) = match text.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(OsString::from(value))),
+            _ => (text, None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| Usage(format!("{name} requires a value")))
+        };
+        match name {
+            "--" => only_inputs = true,
+            "-h" | "--help" => return Ok((Command::Help, flags)),
+            "-o" | "--output" => flags.output = Some(value()?.into()),
+            "-d" | "--directory" => flags.directory = Some(value()?.into()),
+            "--stdin-name" => flags.stdin_name = Some(utf8(name, value()?)?.into_bytes()),
+            "--pixels" => flags.pixels = true,
+            #[cfg(feature = "brotli")]
+            "--brotli" => flags.brotli = true,
+
+            "--exclude" => flags.dir.exclude.push(utf8(name, value()?)?),
+            "--gitignore" => flags.dir.gitignore = true,
+            "--follow-symlinks" => flags.dir.follow_symlinks = true,
+            "--no-directories" => flags.dir.directories = false,
+            "--mtimes" => flags.dir.mtimes = true,
+            "--no-modes" => flags.dir.modes = false,
+            "--no-normalize-unicode" => flags.dir.normalize_unicode = false,
+
+            "--alignment" => flags.alignment = Some(number(name, value()?)?),
+            "--order" =>
+                flags.order = Some(match utf8(name, value()?)?.as_str() {
+                    "sorted" => zip::Sorted,
+                    "given" => zip::Given,
+                    other => return Err(Usage(format!("unknown file order {other:?}"))),
+                }),
+            "--mimetype-first" => flags.mimetype_first = Some(true),
+            "--no-mimetype-first" => flags.mimetype_first = Some(false),
+            "--compression" =>
+                flags.compression = Some(match utf8(name, value()?)?.as_str() {
+                    "stored" => zip::Stored,
+                    "deflated" => zip::Deflated,
+                    "auto" => zip::Auto,
+                    other => return Err(Usage(format!("unknown compression {other:?}"))),
+                }),
+            "--level" => match number(name, value()?)? {
+                level @ 0..=9 => flags.level = Some(Level::new(level)),
+                level => return Err(Usage(format!("compression level {level} isn't 0 to 9"))),
+            },
+            "--mtime" => flags.mtime = Some(number(name, value()?)?),
+            "--extended-timestamps" => flags.extended_timestamps = Some(true),
+            "--no-extended-timestamps" => flags.extended_timestamps = Some(false),
+
+            "--width" => flags.width = Some(number(name, value()?)?),
+            "--max-height" => flags.max_height = Some(number(name, value()?)?),
+            "--bit-depth" => {
+                let bits = number(name, value()?)?;
+                let bit_depth = BitDepth::from_u8(bits)
+                    .ok_or_else(|| Usage(format!("bit depth {bits} isn't 1, 2, 4, 8 or 16")))?;
+                flags.bit_depth = Some(bit_depth);
+            },
+            "--color-mode" =>
+                flags.color_mode = Some(match utf8(name, value()?)?.as_str() {
+                    "lightness" | "gray" | "0" => png::Lightness,
+                    "rgb" | "2" => png::RedGreenBlue,
+                    "indexed" | "3" => png::Indexed,
+                    "lightness-alpha" | "gray-alpha" | "4" => png::LightnessAlpha,
+                    "rgba" | "6" => png::RedGreenBlueAlpha,
+                    other => return Err(Usage(format!("unknown color mode {other:?}"))),
+                }),
+            "--palette" => {
+                let path = PathBuf::from(value()?);
+                let palette = fs::read(&path)
+                    .map_err(|error| Usage(format!("{}: {error}", path.display())))?;
+                if palette.is_empty() || palette.len() % 3 != 0 || palette.len() > 256 * 3 {
+                    return Err(Usage(format!(
+                        "palette must be 1 to 256 RGB triples, but is {} bytes",
+                        palette.len()
+                    )));
+                }
+                flags.color_palette = Some(Some(palette));
+            },
+            "--no-palette" => flags.color_palette = Some(None),
+            "--palette-alpha" => {
+                let alpha = utf8(name, value()?)?
+                    .split(',')
+                    .map(|alpha| number(name, alpha.into()))
+                    .collect::<Result<_, _>>()?;
+                flags.transparency = Some(png::Transparency::Palette(alpha));
+            },
+            "--transparent" => {
+                let samples = utf8(name, value()?)?
+                    .split(',')
+                    .map(|sample| number(name, sample.into()))
+                    .collect::<Result<Vec<u16>, _>>()?;
+                flags.transparency = Some(match samples[..] {
+                    [lightness] => png::Transparency::Lightness(lightness),
+                    [red, green, blue] => png::Transparency::RedGreenBlue(red, green, blue),
+                    _ => return Err(Usage(format!("{name} must be 1 or 3 samples"))),
+                });
+            },
+            "--filter" =>
+                flags.filter = Some(match utf8(name, value()?)?.as_str() {
+                    "none" | "0" => Filter::Fixed(FilterType::None),
+                    "sub" | "1" => Filter::Fixed(FilterType::Sub),
+                    "up" | "2" => Filter::Fixed(FilterType::Up),
+                    "average" | "3" => Filter::Fixed(FilterType::Average),
+                    "paeth" | "4" => Filter::Fixed(FilterType::Paeth),
+                    "minimum-sum" => Filter::MinimumSum,
+                    "brute-force" => Filter::BruteForce,
+                    other => return Err(Usage(format!("unknown filter {other:?}"))),
+                }),
+            "--interlace" => flags.interlace = Some(true),
+            "--text" | "--compressed-text" => {
+                let value = utf8(name, value()?)?;
+                let (keyword, text) = value
+                    .split_once('=')
+                    .ok_or_else(|| Usage(format!("{name} must be given as KEYWORD=TEXT")))?;
+                let mut text = png::Text::new(keyword, text);
+                text.compressed = name == "--compressed-text";
+                flags.text.push(text);
+            },
+            "--text-language" => flags.text_language = Some(utf8(name, value()?)?),
+            "--text-after-image" => flags.text_after_image = true,
+            "--srgb" =>
+                flags.srgb = Some(match utf8(name, value()?)?.as_str() {
+                    "perceptual" | "0" => png::RenderingIntent::Perceptual,
+                    "relative" | "1" => png::RenderingIntent::RelativeColorimetric,
+                    "saturation" | "2" => png::RenderingIntent::Saturation,
+                    "absolute" | "3" => png::RenderingIntent::AbsoluteColorimetric,
+                    other => return Err(Usage(format!("unknown rendering intent {other:?}"))),
+                }),
+            "--gamma" => flags.gamma = Some(png::Gamma::new(number(name, value()?)?)),
+            "--chromaticities" => {
+                let value = utf8(name, value()?)?;
+                flags.chromaticities = Some(if value == "srgb" {
+                    png::Chromaticities::SRGB
+                } else {
+                    let coordinates = value
+                        .split(',')
+                        .map(|coordinate| number(name, coordinate.into()))
+                        .collect::<Result<Vec<f64>, _>>()?;
+                    let [wx, wy, rx, ry, gx, gy, bx, by] = coordinates[..] else {
+                        return Err(Usage(format!("{name} must be srgb or 8 coordinates")));
+                    };
+                    png::Chromaticities::new((wx, wy), (rx, ry), (gx, gy), (bx, by))
+                });
+            },
+            "--icc-profile" => {
+                let path = PathBuf::from(value()?);
+                let profile = fs::read(&path)
+                    .map_err(|error| Usage(format!("{}: {error}", path.display())))?;
+                let name = path.file_stem().unwrap_or_default().to_string_lossy();
+                flags.icc_profile = Some(png::IccProfile::new(name, profile));
+            },
+            "--dpi" => {
+                let dpi = number(name, value()?)?;
+                flags.physical_dimensions = Some(png::PhysicalDimensions::from_dpi(dpi));
+            },
+
+            "-V" | "--version" => return Ok((Command::Version, flags)),
+            _ => return Err(Usage(format!("unknown option {name:?}"))),
+        }
+    }
+    Ok((command, flags))
+}
+
+fn utf8(name: &str, value: OsString) -> Result<String, Usage> {
+    value
+        .into_string()
+        .map_err(|value| Usage(format!("{name} value {value:?} isn't valid UTF-8")))
+}
+
+fn number<T: FromStr>(name: &str, value: OsString) -> Result<T, Usage> {
+    let value = utf8(name, value)?;
+    value
+        .parse()
+        .map_err(|_| Usage(format!("{name} value {value:?} isn't a valid number")))
+}
+
+fn run(command: Command) -> Result<ExitCode> {
+    match command {
+        Command::Help => print!("{USAGE}"),
+        Command::Version => println!("zipng {}", env!("CARGO_PKG_VERSION")),
+        Command::Zip => write_output(&try_zip_with(&read_files()?, |opts| {
+            flags().apply_zip(opts)
+        })?)?,
+        Command::Zipng => write_output(&try_zipng_with(&read_files()?, |opts| {
+            flags().apply_zip(&mut opts.zip);
+            flags().apply_png(&mut opts.png);
+        })?)?,
+        Command::Sliceable => write_output(&try_sliceable_zip_with(&read_files()?, |opts| {
+            flags().apply_zip(opts)
+        })?)?,
+        Command::Png => {
+            let body = match &flags().inputs[..] {
+                [] => read_input(Path::new("-"))?,
+                [input] => read_input(Path::new(input))?,
+                _ => return Err(Usage("png takes a single input".into()).into()),
+            };
+            write_output(&try_png_with(&body, |opts| flags().apply_png(opts))?)?
+        },
+        #[cfg(feature = "brotli")]
+        Command::Br => write_output(&try_zipngbr_with(&read_files()?, |opts| {
+            flags().apply_zip(&mut opts.zip);
+            flags().apply_png(&mut opts.png);
+        })?)?,
+        Command::Extract => extract()?,
+        Command::List => list()?,
+        Command::Verify => return verify(),
+        Command::Inspect => inspect()?,
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Reads the files, directories and standard input given as inputs.
+fn read_files() -> Result<Files> {
+    let flags = flags();
+    if flags.inputs.is_empty() {
+        return Err(Usage("no inputs given".into()).into());
+    }
+    let mut files = Files::new();
+    for input in &flags.inputs {
+        let path = Path::new(input);
+        if input == "-" {
+            let name = flags.stdin_name.as_deref().unwrap_or(b"stdin");
+            files = files.add(name, read_input(path)?);
+            continue;
+        }
+
+        // Paths are added as given, but relative to the current directory.
+        let name = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(walk::normalize(name, &flags.dir)),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join(&b'/');
+        let metadata = fs::metadata(path).map_err(|error| walk::io_error(path, error))?;
+        let entry = FileEntry {
+            mtime: flags.dir.mtimes.then(|| walk::mtime(&metadata)).flatten(),
+            mode: flags.dir.modes.then(|| walk::mode(&metadata)).flatten(),
+            ..FileEntry::default()
+        };
+
+        if metadata.is_dir() {
+            let prefix = if name.is_empty() {
+                name
+            } else {
+                [&name[..], b"/"].concat()
+            };
+            if !prefix.is_empty() && flags.dir.directories {
+                files = files.add_entry(prefix.clone(), FileEntry {
+                    kind: Directory,
+                    ..entry
+                });
+            }
+            for (name, entry) in Files::from_dir(path, &flags.dir)?.iter() {
+                files = files.add_entry([&prefix[..], name].concat(), entry.clone());
+            }
+        } else {
+            let body = fs::read(path).map_err(|error| walk::io_error(path, error))?;
+            files = files.add_entry(name, FileEntry { body, ..entry });
+        }
+    }
+    Ok(files)
+}
+
+/// Reads a file, or standard input if the path is `-`.
+fn read_input(path: &Path) -> Result<Vec<u8>> {
+    if path == Path::new("-") {
+        let mut data = Vec::new();
+        std::io::stdin().lock().read_to_end(&mut data)?;
+        Ok(data)
+    } else {
+        Ok(fs::read(path).map_err(|error| walk::io_error(path, error))?)
+    }
+}
+
+fn write_output(data: &[u8]) -> Result<()> {
+    match flags().output.as_deref() {
+        Some(path) if path != Path::new("-") =>
+            fs::write(path, data).map_err(|error| walk::io_error(path, error))?,
+        _ => std::io::stdout().lock().write_all(data)?,
+    }
+    Ok(())
+}
+
+/// Reads the single input of a command that reads an archive or image,
+/// decompressing it with brotli if that was asked for.
+fn read_single_input() -> Result<Vec<u8>> {
+    let flags = flags();
+    let input = match flags.inputs.first() {
+        Some(input) => input,
+        None => return Err(Usage("no input given".into()).into()),
+    };
+    let data = read_input(Path::new(input))?;
+    #[cfg(feature = "brotli")]
+    if flags.brotli {
+        return zipng::brotli::decompress(&data);
+    }
+    Ok(data)
+}
+
+/// Reads the archive from the single input, or from its pixels if that was
+/// asked for.
+fn read_archive(data: &[u8]) -> Result<Cow<'_, [u8]>> {
+    if flags().pixels {
+        Ok(Cow::Owned(extract_from_pixels(data)?))
+    } else {
+        Ok(Cow::Borrowed(data))
+    }
+}
+
+fn extract() -> Result<()> {
+    let flags = flags();
+    let data = read_single_input()?;
+    let data = read_archive(&data)?;
+    let archive = zip::Archive::new(&data)?;
+    let directory = flags.directory.as_deref().unwrap_or_else(|| Path::new("."));
+    let names = &flags.inputs[1..];
+    let is_wanted = |name: &[u8]| {
+        names.is_empty()
+            || names.iter().any(|wanted| {
+                let wanted = walk::normalize(wanted, &flags.dir);
+                name == wanted || (wanted.ends_with(b"/") && name.starts_with(&wanted))
+            })
+    };
+
+    // Every path and link target is checked before anything is written, and
+    // symbolic links are created last, so that no other entry can be written
+    // through one.
+    let mut entries = Vec::new();
+    for entry in archive.entries() {
+        if is_wanted(entry.name) {
+            let relative = safe_path(entry.name)?;
+            if entry.is_symlink() {
+                safe_link_target(entry.name, &entry.body()?)?;
+            }
+            entries.push((entry, relative));
+        }
+    }
+    entries.sort_by_key(|(entry, _)| entry.is_symlink());
+    let mut directories = Vec::new();
+    for (entry, relative) in entries {
+        refuse_symlinks(directory, &relative)?;
+        let path = directory.join(relative);
+        let mode = entry.unix_mode().map(|mode| mode & 0o7777);
+        if entry.is_dir() {
+            fs::create_dir_all(&path).map_err(|error| walk::io_error(&path, error))?;
+            directories.push((path, mode));
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|error| walk::io_error(parent, error))?;
+        }
+        let body = entry.body()?;
+        if entry.is_symlink() {
+            #[cfg(unix)]
+            {
+                let target = std::ffi::OsStr::from_bytes(&body);
+                std::os::unix::fs::symlink(target, &path)
+                    .map_err(|error| walk::io_error(&path, error))?;
+                continue;
+            }
+        }
+        fs::write(&path, &body).map_err(|error| walk::io_error(&path, error))?;
+        set_mode(&path, mode)?;
+    }
+    // Directories' modes are set last, in case they don't allow writing.
+    for (path, mode) in directories.into_iter().rev() {
+        set_mode(&path, mode)?;
+    }
+    Ok(())
+}
+
+/// Converts an entry's name to a relative path, refusing any that would be
+/// extracted outside of the output directory.
+fn safe_path(name: &[u8]) -> Result<PathBuf> {
+    let unsafe_name = || format!("refusing to extract {:?}", String::from_utf8_lossy(name));
+    let mut path = PathBuf::new();
+    for component in name.split(|&byte| byte == b'/') {
+        match component {
+            b"" | b"." => continue,
+            b".." => return Err(unsafe_name().into()),
+            _ if component.contains(&b'\\') || component.contains(&b':') =>
+                return Err(unsafe_name().into()),
+            _ => {},
+        }
+        #[cfg(unix)]
+        path.push(std::ffi::OsStr::from_bytes(component));
+        #[cfg(not(unix))]
+        path.push(std::str::from_utf8(component).map_err(|_| unsafe_name())?);
+    }
+    if name.starts_with(b"/") || path.as_os_str().is_empty() {
+        return Err(unsafe_name().into());
+    }
+    Ok(path)
+}
+
+/// Refuses a symbolic link whose target could lead outside of the output
+/// directory. Parent components are only allowed at the start of the target,
+/// where they can't follow another link, and can't climb above the root.
+fn safe_link_target(name: &[u8], target: &[u8]) -> Result<()> {
+    let unsafe_target = || {
+        format!(
+            "refusing to extract {:?} linking to {:?}",
+            String::from_utf8_lossy(name),
+            String::from_utf8_lossy(target)
+        )
+    };
+    if target.is_empty() || target.starts_with(b"/") {
+        return Err(unsafe_target().into());
+    }
+    let mut depth = name
+        .split(|&byte| byte == b'/')
+        .filter(|&component| !matches!(component, b"" | b"."))
+        .count()
+        - 1;
+    let mut is_leading = true;
+    for component in target.split(|&byte| byte == b'/') {
+        match component {
+            b"" | b"." => {},
+            b".." if is_leading && depth > 0 => depth -= 1,
+            b".." => return Err(unsafe_target().into()),
+            _ if component.contains(&b'\\') || component.contains(&b':') =>
+                return Err(unsafe_target().into()),
+            _ => is_leading = false,
+        }
+    }
+    Ok(())
+}
+
+/// Refuses to write to `relative` in `directory` if it, or any directory on
+/// the way to it, is an existing symbolic link.
+fn refuse_symlinks(directory: &Path, relative: &Path) -> Result<()> {
+    let mut path = directory.to_path_buf();
+    for component in relative.components() {
+        path.push(component);
+        if let Ok(metadata) = fs::symlink_metadata(&path) {
+            if metadata.file_type().is_symlink() {
+                return Err(format!(
+                    "refusing to extract through symbolic link {}",
+                    path.display()
+                )
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn set_mode(path: &Path, mode: Option<u32>) -> Result<()> {
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(|error| walk::io_error(path, error))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
+
+fn list() -> Result<()> {
+    let data = read_single_input()?;
+    let data = read_archive(&data)?;
+    let archive = zip::Archive::new(&data)?;
+    let mut stdout = std::io::stdout().lock();
+    for entry in archive.entries() {
+        let method = match entry.method {
+            0 => "stored".into(),
+            8 => "deflated".into(),
+            method => format!("method {method}"),
+        };
+        writeln!(
+            stdout,
+            "{} {:>10} {:>10} {:<8} {} {}",
+            mode_string(entry),
+            entry.uncompressed_size,
+            entry.compressed_size,
+            method,
+            date_string(entry.mtime()),
+            String::from_utf8_lossy(entry.name),
+        )?;
+    }
+    Ok(())
+}
+
+/// Formats an entry's Unix mode the way `ls -l` does.
+fn mode_string(entry: &zip::Entry) -> String {
+    let mode = entry.unix_mode().unwrap_or(0);
+    let kind = if entry.is_symlink() {
+        'l'
+    } else if entry.is_dir() {
+        'd'
+    } else {
+        '-'
+    };
+    let permissions = (0..9).rev().map(|bit| {
+        if mode & 1 << bit == 0 {
+            '-'
+        } else {
+            ['x', 'w', 'r'][bit % 3]
+        }
+    });
+    std::iter::once(kind).chain(permissions).collect()
+}
+
+/// Formats a time in seconds since the Unix epoch as a UTC date, to the
+/// precision of the MS-DOS fields.
+fn date_string(mtime: i64) -> String {
+    let (time, date) = zip::dos_time_date(mtime);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        (date >> 9) + 1980,
+        date >> 5 & 0x0F,
+        date & 0x1F,
+        time >> 11,
+        time >> 5 & 0x3F,
+    )
+}
+
+/// Checks every checksum in the input, as a PNG image, as a zip archive, and
+/// as an archive in an image's pixels, reporting on each. It's an error if
+/// the input is none of these, or if any of them is invalid.
+fn verify() -> Result<ExitCode> {
+    let data = read_single_input()?;
+    let mut found = false;
+    let mut failed = false;
+    let mut report = |what: &str, result: Result<String>| match result {
+        Ok(summary) => {
+            found = true;
+            println!("{what}: ok, {summary}");
+        },
+        Err(error) => {
+            failed = true;
+            println!("{what}: FAILED, {error}");
+        },
+    };
+
+    if data.starts_with(png::SIGNATURE) {
+        report(
+            "png",
+            png::read_png(&data).map_err(Into::into).map(|image| {
+                format!(
+                    "{}x{} pixels, {}-bit {:?}",
+                    image.width,
+                    image.height,
+                    image.bit_depth.u8(),
+                    image.color_mode
+                )
+            }),
+        );
+        match extract_from_pixels(&data) {
+            Err(zipng::Error::NoArchiveInPixels) => println!("pixels: no archive found"),
+            result => report(
+                "pixels",
+                result
+                    .map_err(Into::into)
+                    .map(|archive| format!("archive of {} bytes", archive.len())),
+            ),
+        }
+    }
+    match zip::Archive::new(&data) {
+        Ok(archive) => report(
+            "zip",
+            archive
+                .verify()
+                .map_err(Into::into)
+                .map(|()| format!("{} entries", archive.entries().len())),
+        ),
+        Err(error) if data.starts_with(png::SIGNATURE) => println!("zip: none found, {error}"),
+        Err(error) => report("zip", Err(error.into())),
+    }
+
+    Ok(if failed || !found {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// Prints the chunks of a PNG image, and the records of a zip archive.
+fn inspect() -> Result<()> {
+    let data = read_single_input()?;
+    let mut stdout = std::io::stdout().lock();
+
+    if data.starts_with(png::SIGNATURE) && !flags().pixels {
+        writeln!(stdout, "png chunks:")?;
+        for chunk in png::read_png_chunks(&data)? {
+            writeln!(
+                stdout,
+                "  {:#010x} {} {:>10} bytes",
+                chunk.offset,
+                String::from_utf8_lossy(&chunk.chunk_type),
+                chunk.data.len(),
+            )?;
+        }
+        let image = png::read_png(&data)?;
+        writeln!(
+            stdout,
+            "png image: {}x{} pixels, {}-bit {:?}{}",
+            image.width,
+            image.height,
+            image.bit_depth.u8(),
+            image.color_mode,
+            match &image.palette {
+                Some(palette) => format!(", {} palette entries", palette.len() / 3),
+                None => String::new(),
+            },
+        )?;
+    }
+
+    let data = read_archive(&data)?;
+    let archive = match zip::Archive::new(&data) {
+        Ok(archive) => archive,
+        Err(error) => {
+            writeln!(stdout, "zip archive: none found, {error}")?;
+            return Ok(());
+        },
+    };
+    writeln!(
+        stdout,
+        "zip archive: {} entries, {} bytes of prefix, {} byte comment",
+        archive.entries().len(),
+        archive.prefix().len(),
+        archive.comment().len(),
+    )?;
+    for entry in archive.entries() {
+        writeln!(
+            stdout,
+            "  {:#010x} {} version {:#06x} flags {:#06x} method {} crc {:08x} {:>10} -> {:>10} {}",
+            entry.header_offset,
+            mode_string(entry),
+            entry.version_made_by,
+            entry.flags,
+            entry.method,
+            entry.crc,
+            entry.compressed_size,
+            entry.uncompressed_size,
+            String::from_utf8_lossy(entry.name),
+        )?;
+    }
+    Ok(())
+}
@@ -43,3 +43,15 @@ pub fn write_aligned_pad_start(
     let index_after_data = buffer.len();
     index_before_data..index_after_data
 }
+
+/// The number of zero bytes that [`write_aligned_pad_start`] writes before
+/// `len` bytes at `offset`, for output that isn't held in a buffer.
+pub fn pad_start_len(offset: u64, len: u64, alignment: u64) -> u64 {
+    pad_end_len(offset + len, alignment)
+}
+
+/// The number of zero bytes that [`write_aligned_pad_end`] writes after bytes
+/// ending at `offset`, for output that isn't held in a buffer.
+pub fn pad_end_len(offset: u64, alignment: u64) -> u64 {
+    (alignment - offset % alignment) % alignment
+}
@@ -1,12 +1,44 @@
 #![allow(clippy::unusual_byte_groupings)]
 
+use std::io;
+use std::io::Write;
+use std::mem;
 use std::ops::Not;
 use std::ops::Range;
 
+use bstr::ByteSlice;
+use tracing::warn;
+
 pub use self::BitDepth::*;
 pub use self::ColorMode::*;
-use crate::checksums::adler32;
 use crate::checksums::crc32;
+use crate::checksums::Adler32;
+use crate::checksums::Crc32;
+use crate::deflate::write_deflated_with_dictionary;
+use crate::deflate::Level;
+use crate::deflate::MAX_STORED;
+use crate::zlib;
+use crate::zlib::FLevel;
+use crate::Error;
+use crate::PngOptions;
+
+/// The maximum width or height of an image, and the maximum length of a chunk.
+pub const MAX_DIMENSION: usize = i32::MAX as usize;
+
+/// The signature at the start of every PNG file.
+pub const SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1A\n";
+
+/// The most compressed image data [`PngWriter`] writes in each `IDAT` chunk,
+/// except with [`PngWriter::write_stored`], which writes a single one.
+pub const MAX_IDAT_LENGTH: usize = 1 << 16;
+
+/// The bytes before each scanline written by [`PngWriter::write_stored`]: its
+/// stored block's header and its filter type.
+pub const STORED_SCANLINE_GAP: usize = 5 + 1;
+
+/// The longest scanline [`PngWriter::write_stored`] can write, which fills a
+/// stored block along with its filter type.
+pub const MAX_STORED_SCANLINE: usize = MAX_STORED - 1;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
 #[repr(u8)]
@@ -27,6 +59,19 @@ impl BitDepth {
     pub fn u8(&self) -> u8 {
         (*self).into()
     }
+
+    /// Returns the bit depth with the given number of bits per sample, if
+    /// it's valid.
+    pub fn from_u8(bits: u8) -> Option<Self> {
+        match bits {
+            1 => Some(OneBit),
+            2 => Some(TwoBit),
+            4 => Some(FourBit),
+            8 => Some(EightBit),
+            16 => Some(SixteenBit),
+            _ => None,
+        }
+    }
 }
 
 impl From<BitDepth> for u8 {
@@ -60,6 +105,27 @@ impl ColorMode {
     pub fn u8(&self) -> u8 {
         (*self).into()
     }
+
+    /// Returns the color mode with the given PNG color type, if it's valid.
+    pub fn from_u8(color_type: u8) -> Option<Self> {
+        match color_type {
+            0 => Some(Lightness),
+            2 => Some(RedGreenBlue),
+            3 => Some(Indexed),
+            4 => Some(LightnessAlpha),
+            6 => Some(RedGreenBlueAlpha),
+            _ => None,
+        }
+    }
+
+    /// Whether PNG allows this color mode with the given bit depth.
+    pub fn allows(&self, bit_depth: BitDepth) -> bool {
+        match self {
+            Lightness => true,
+            Indexed => bit_depth != SixteenBit,
+            RedGreenBlue | LightnessAlpha | RedGreenBlueAlpha => bit_depth >= EightBit,
+        }
+    }
 }
 
 impl From<ColorMode> for u8 {
@@ -68,67 +134,487 @@ impl From<ColorMode> for u8 {
     }
 }
 
+/// The filter type of a scanline, which predicts each of its bytes from its
+/// neighbours so that only the difference is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[repr(u8)]
+pub enum FilterType {
+    #[default]
+    None = 0,
+    /// Predicts each byte from the one to its left.
+    Sub = 1,
+    /// Predicts each byte from the one above it.
+    Up = 2,
+    /// Predicts each byte from the average of the ones to its left and above.
+    Average = 3,
+    /// Predicts each byte from whichever of the ones to its left, above and
+    /// above-left is closest to their gradient.
+    Paeth = 4,
+}
+
+impl FilterType {
+    pub const ALL: [FilterType; 5] = [
+        FilterType::None,
+        FilterType::Sub,
+        FilterType::Up,
+        FilterType::Average,
+        FilterType::Paeth,
+    ];
+
+    pub fn u8(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn from_u8(filter_type: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(filter_type)).copied()
+    }
+
+    /// Predicts a byte from its neighbours, which are zero if they're outside
+    /// the image.
+    fn predict(&self, left: u8, up: u8, up_left: u8) -> u8 {
+        match self {
+            FilterType::None => 0,
+            FilterType::Sub => left,
+            FilterType::Up => up,
+            FilterType::Average => ((u16::from(left) + u16::from(up)) / 2) as u8,
+            FilterType::Paeth => paeth(left, up, up_left),
+        }
+    }
+}
+
+/// A textual metadata entry, such as a title, author or license, written as
+/// a `tEXt`, `zTXt` or `iTXt` chunk.
+///
+/// Text that's all Latin-1 is written as `tEXt`, or `zTXt` if it's compressed,
+/// unless it has a language or translated keyword. Other text is written as
+/// `iTXt`, which holds UTF-8.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub struct Text {
+    /// The keyword identifying the text, such as `Title`, `Author`,
+    /// `Copyright` or `Source`. It must be 1 to 79 printable Latin-1
+    /// characters, without leading, trailing or consecutive spaces.
+    pub keyword: String,
+    /// The text, which can't contain null characters.
+    pub text: String,
+    /// Whether the text is compressed with zlib.
+    pub compressed: bool,
+    /// The language of the text as an RFC 3066 tag such as `en-GB`, or empty
+    /// if it's unspecified.
+    pub language: String,
+    /// The keyword translated into the text's language, or empty.
+    pub translated_keyword: String,
+    /// Whether the chunk is written after the image data, rather than before
+    /// it.
+    pub after_image: bool,
+}
+
+impl Text {
+    pub fn new(keyword: impl Into<String>, text: impl Into<String>) -> Self {
+        Self {
+            keyword: keyword.into(),
+            text: text.into(),
+            ..Self::default()
+        }
+    }
+
+    /// Returns the type and data of the chunk the text is written as, or an
+    /// error if any of its fields isn't allowed.
+    pub fn to_chunk(&self) -> Result<([u8; 4], Vec<u8>), Error> {
+        let is_latin_1 = self.text.chars().all(|char| u32::from(char) <= 0xFF);
+        let chunk_type =
+            if !is_latin_1 || !self.language.is_empty() || !self.translated_keyword.is_empty() {
+                *b"iTXt"
+            } else if self.compressed {
+                *b"zTXt"
+            } else {
+                *b"tEXt"
+            };
+        let invalid = |reason| invalid_chunk(&chunk_type, reason);
+
+        check_keyword(&self.keyword).map_err(invalid)?;
+        if self.text.contains('\0') || self.translated_keyword.contains('\0') {
+            return Err(invalid("text must not contain null characters"));
+        }
+        if !self
+            .language
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || char == '-')
+        {
+            return Err(invalid("language must be letters, digits and hyphens"));
+        }
+
+        let mut data = latin_1(&self.keyword);
+        // null separator
+        data.push(0x00);
+        match &chunk_type {
+            b"tEXt" => data.extend_from_slice(&latin_1(&self.text)),
+            b"zTXt" => {
+                // compression method: deflate
+                data.push(0x00);
+                data.extend_from_slice(&zlib::deflate(&latin_1(&self.text)));
+            },
+            _ => {
+                // compression flag, and compression method: deflate
+                data.push(self.compressed.into());
+                data.push(0x00);
+                data.extend_from_slice(self.language.as_bytes());
+                data.push(0x00);
+                data.extend_from_slice(self.translated_keyword.as_bytes());
+                data.push(0x00);
+                if self.compressed {
+                    data.extend_from_slice(&zlib::deflate(self.text.as_bytes()));
+                } else {
+                    data.extend_from_slice(self.text.as_bytes());
+                }
+            },
+        }
+        Ok((chunk_type, data))
+    }
+}
+
+/// Encodes text whose characters are all Latin-1 as Latin-1.
+fn latin_1(text: &str) -> Vec<u8> {
+    text.chars().map(|char| char as u8).collect()
+}
+
+/// The rendering intent of an image in the sRGB color space, written as an
+/// `sRGB` chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[repr(u8)]
+pub enum RenderingIntent {
+    /// For images such as photographs, preserving the relationship between
+    /// colors rather than the colors themselves.
+    #[default]
+    Perceptual = 0,
+    /// For images such as logos, preserving colors that are in gamut.
+    RelativeColorimetric = 1,
+    /// For images such as charts, preserving saturation rather than hue.
+    Saturation = 2,
+    /// For proofs, preserving colors relative to an absolute white point.
+    AbsoluteColorimetric = 3,
+}
+
+impl RenderingIntent {
+    pub fn to_chunk(&self) -> Result<([u8; 4], Vec<u8>), Error> {
+        Ok((*b"sRGB", vec![*self as u8]))
+    }
+}
+
+/// The gamma of an image, times 100,000, written as a `gAMA` chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Gamma(pub u32);
+
+impl Gamma {
+    /// The gamma of the sRGB color space, 1/2.2.
+    pub const SRGB: Gamma = Gamma(45455);
+
+    /// Returns the gamma for an encoding exponent such as `1.0 / 2.2`.
+    pub fn new(gamma: f64) -> Self {
+        Gamma(scale(gamma))
+    }
+
+    pub fn to_chunk(&self) -> Result<([u8; 4], Vec<u8>), Error> {
+        let invalid = |reason| invalid_chunk(b"gAMA", reason);
+        if self.0 == 0 {
+            return Err(invalid("gamma must not be zero"));
+        }
+        Ok((*b"gAMA", png_u32(self.0).map_err(invalid)?.to_vec()))
+    }
+}
+
+/// The CIE 1931 x and y chromaticities of an image's white point and
+/// primaries, times 100,000, written as a `cHRM` chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Chromaticities {
+    pub white: (u32, u32),
+    pub red: (u32, u32),
+    pub green: (u32, u32),
+    pub blue: (u32, u32),
+}
+
+impl Chromaticities {
+    /// The chromaticities of the sRGB color space.
+    pub const SRGB: Chromaticities = Chromaticities {
+        white: (31270, 32900),
+        red: (64000, 33000),
+        green: (30000, 60000),
+        blue: (15000, 6000),
+    };
+
+    /// Returns the chromaticities for the given x and y coordinates.
+    pub fn new(white: (f64, f64), red: (f64, f64), green: (f64, f64), blue: (f64, f64)) -> Self {
+        let scale = |(x, y)| (scale(x), scale(y));
+        Self {
+            white: scale(white),
+            red: scale(red),
+            green: scale(green),
+            blue: scale(blue),
+        }
+    }
+
+    pub fn to_chunk(&self) -> Result<([u8; 4], Vec<u8>), Error> {
+        let mut data = Vec::new();
+        for (x, y) in [self.white, self.red, self.green, self.blue] {
+            for value in [x, y] {
+                data.extend_from_slice(
+                    &png_u32(value).map_err(|reason| invalid_chunk(b"cHRM", reason))?,
+                );
+            }
+        }
+        Ok((*b"cHRM", data))
+    }
+}
+
+/// An embedded ICC color profile, written compressed as an `iCCP` chunk.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IccProfile {
+    /// The name of the profile, which follows the same rules as a [`Text`]
+    /// keyword.
+    pub name: String,
+    pub profile: Vec<u8>,
+}
+
+impl IccProfile {
+    pub fn new(name: impl Into<String>, profile: impl Into<Vec<u8>>) -> Self {
+        Self {
+            name: name.into(),
+            profile: profile.into(),
+        }
+    }
+
+    pub fn to_chunk(&self) -> Result<([u8; 4], Vec<u8>), Error> {
+        check_keyword(&self.name).map_err(|reason| invalid_chunk(b"iCCP", reason))?;
+        let mut data = latin_1(&self.name);
+        // null separator, and compression method: deflate
+        data.extend_from_slice(&[0x00, 0x00]);
+        data.extend_from_slice(&zlib::deflate(&self.profile));
+        Ok((*b"iCCP", data))
+    }
+}
+
+/// The unit of an image's [`PhysicalDimensions`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[repr(u8)]
+pub enum PhysicalUnit {
+    /// Only the aspect ratio of the pixels is given.
+    #[default]
+    Unknown = 0,
+    Metre = 1,
+}
+
+/// The number of pixels per unit in each direction, written as a `pHYs`
+/// chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct PhysicalDimensions {
+    pub x: u32,
+    pub y: u32,
+    pub unit: PhysicalUnit,
+}
+
+impl PhysicalDimensions {
+    /// Returns the dimensions of square pixels at the given number of dots per
+    /// inch.
+    pub fn from_dpi(dpi: u32) -> Self {
+        let per_metre = ((f64::from(dpi) / 0.0254).round()) as u32;
+        Self {
+            x: per_metre,
+            y: per_metre,
+            unit: PhysicalUnit::Metre,
+        }
+    }
+
+    pub fn to_chunk(&self) -> Result<([u8; 4], Vec<u8>), Error> {
+        let invalid = |reason| invalid_chunk(b"pHYs", reason);
+        let mut data = Vec::new();
+        data.extend_from_slice(&png_u32(self.x).map_err(invalid)?);
+        data.extend_from_slice(&png_u32(self.y).map_err(invalid)?);
+        data.push(self.unit as u8);
+        Ok((*b"pHYs", data))
+    }
+}
+
+/// The colors that are transparent in an image without an alpha channel,
+/// written as a `tRNS` chunk.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Transparency {
+    /// The alpha of each palette entry, in order. Any entries past the end are
+    /// opaque.
+    Palette(Vec<u8>),
+    /// The lightness sample, at the image's bit depth, of fully transparent
+    /// pixels.
+    Lightness(u16),
+    /// The red, green and blue samples, at the image's bit depth, of fully
+    /// transparent pixels.
+    RedGreenBlue(u16, u16, u16),
+}
+
+impl Transparency {
+    /// The color mode of the images this can apply to.
+    pub fn color_mode(&self) -> ColorMode {
+        match self {
+            Transparency::Palette(_) => Indexed,
+            Transparency::Lightness(_) => Lightness,
+            Transparency::RedGreenBlue(..) => RedGreenBlue,
+        }
+    }
+
+    pub fn to_chunk(&self) -> Result<([u8; 4], Vec<u8>), Error> {
+        let data = match self {
+            Transparency::Palette(alpha) => alpha.clone(),
+            Transparency::Lightness(lightness) => lightness.to_be_bytes().to_vec(),
+            Transparency::RedGreenBlue(red, green, blue) => [red, green, blue]
+                .into_iter()
+                .flat_map(|sample| sample.to_be_bytes())
+                .collect(),
+        };
+        Ok((*b"tRNS", data))
+    }
+
+    /// Checks that this can apply to an image with the given format.
+    fn check(
+        &self,
+        bit_depth: BitDepth,
+        color_mode: ColorMode,
+        palette: Option<&[u8]>,
+    ) -> Result<(), &'static str> {
+        if self.color_mode() != color_mode {
+            return Err("transparency doesn't match the image's color mode");
+        }
+        let max = (1_u32 << bit_depth.bits_per_sample()) - 1;
+        match self {
+            Transparency::Palette(alpha) =>
+                if alpha.len() > palette.unwrap_or_default().len() / 3 {
+                    return Err("there are more alpha values than palette entries");
+                },
+            Transparency::Lightness(lightness) =>
+                if u32::from(*lightness) > max {
+                    return Err("samples must fit in the image's bit depth");
+                },
+            Transparency::RedGreenBlue(red, green, blue) =>
+                if [red, green, blue]
+                    .into_iter()
+                    .any(|sample| u32::from(*sample) > max)
+                {
+                    return Err("samples must fit in the image's bit depth");
+                },
+        }
+        Ok(())
+    }
+
+    /// Reads a `tRNS` chunk for an image of the given color mode.
+    fn from_chunk(data: &[u8], color_mode: ColorMode) -> Option<Self> {
+        let sample = |index: usize| u16::from_be_bytes([data[index * 2], data[index * 2 + 1]]);
+        match (color_mode, data.len()) {
+            (Indexed, _) => Some(Transparency::Palette(data.to_vec())),
+            (Lightness, 2) => Some(Transparency::Lightness(sample(0))),
+            (RedGreenBlue, 6) => Some(Transparency::RedGreenBlue(sample(0), sample(1), sample(2))),
+            _ => None,
+        }
+    }
+}
+
+/// Scales a value such as a gamma or chromaticity by 100,000, as the chunks
+/// that hold them do.
+fn scale(value: f64) -> u32 {
+    (value * 100_000.0).round() as u32
+}
+
+/// Encodes a value as a PNG four-byte unsigned integer, which must be less
+/// than 2^31.
+fn png_u32(value: u32) -> Result<[u8; 4], &'static str> {
+    if value as usize > MAX_DIMENSION {
+        return Err("values must be less than 2^31");
+    }
+    Ok(value.to_be_bytes())
+}
+
+/// Checks that a [`Text`] keyword or [`IccProfile`] name is allowed,
+/// returning the reason if not.
+fn check_keyword(keyword: &str) -> Result<(), &'static str> {
+    if keyword.is_empty() || keyword.chars().count() > 79 {
+        return Err("keyword must be 1 to 79 characters");
+    }
+    if !keyword
+        .chars()
+        .all(|char| matches!(char, ' '..='~' | '\u{A1}'..='\u{FF}'))
+    {
+        return Err("keyword must be printable Latin-1");
+    }
+    if keyword.starts_with(' ') || keyword.ends_with(' ') || keyword.contains("  ") {
+        return Err("keyword must not have leading, trailing or consecutive spaces");
+    }
+    Ok(())
+}
+
+/// How the filter type of each scanline is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Filter {
+    /// Every scanline uses the same filter type.
+    Fixed(FilterType),
+    /// Each scanline uses the filter type whose output has the smallest sum of
+    /// absolute values, as signed bytes, as the PNG specification suggests.
+    #[default]
+    MinimumSum,
+    /// Each scanline uses the filter type whose output compresses smallest,
+    /// following the previous scanline. This is much slower.
+    BruteForce,
+}
+
 pub fn write_png_header(
     buffer: &mut Vec<u8>,
     width: u32,
     height: u32,
     color_depth: BitDepth,
     color_mode: ColorMode,
-) -> Range<usize> {
+    interlaced: bool,
+) -> Result<Range<usize>, Error> {
     let before = buffer.len();
 
-    buffer.extend_from_slice(b"\x89PNG\r\n\x1A\n");
+    buffer.extend_from_slice(SIGNATURE);
     write_png_chunk(buffer, b"IHDR", &{
         let mut data = Vec::new();
         // pixel width
-        data.extend_from_slice(&u32::from(width).to_be_bytes());
+        data.extend_from_slice(&width.to_be_bytes());
         // pixel height
-        data.extend_from_slice(&u32::from(height).to_be_bytes());
+        data.extend_from_slice(&height.to_be_bytes());
         // color bit depth
-        data.extend_from_slice(&u8::from(color_depth).to_be_bytes());
+        data.extend_from_slice(&color_depth.u8().to_be_bytes());
         // color type: grayscale
-        data.extend_from_slice(&u8::from(color_mode).to_be_bytes());
+        data.extend_from_slice(&color_mode.u8().to_be_bytes());
         // compression method: deflate
-        data.extend_from_slice(&u8::from(0_u8).to_be_bytes());
+        data.extend_from_slice(&0_u8.to_be_bytes());
         // filter method: basic
-        data.extend_from_slice(&u8::from(0_u8).to_be_bytes());
-        // interlace method: none
-        data.extend_from_slice(&u8::from(0_u8).to_be_bytes());
+        data.extend_from_slice(&0_u8.to_be_bytes());
+        // interlace method: none or Adam7
+        data.extend_from_slice(&u8::from(interlaced).to_be_bytes());
 
         data
-    });
+    })?;
 
     let after = buffer.len();
-    before..after
+    Ok(before..after)
 }
 
-pub fn write_png_palette(buffer: &mut Vec<u8>, palette: &[u8]) -> Range<usize> {
+pub fn write_png_palette(buffer: &mut Vec<u8>, palette: &[u8]) -> Result<Range<usize>, Error> {
     write_png_chunk(buffer, b"PLTE", palette)
 }
 
-pub fn write_png_body(buffer: &mut Vec<u8>, data: &[u8]) -> Range<usize> {
-    let mut deflated = Vec::new();
-    write_non_deflated(&mut deflated, data);
-    write_png_chunk(buffer, b"IDAT", &deflated)
-}
-
 pub fn write_non_deflated(buffer: &mut Vec<u8>, data: &[u8]) -> Range<usize> {
-    let chunks = data.chunks(0xFFFF);
+    // an empty stream still needs a final block
+    let chunks = if data.is_empty() {
+        vec![data]
+    } else {
+        data.chunks(0xFFFF).collect()
+    };
 
-    // zlib compression mode: deflate with 32KiB windows
-    let cmf = 0b_0111_1000;
-    buffer.push(cmf);
-    // zlib flag bits: no preset dictionary, compression level 0
-    let mut flg: u8 = 0b_00_0_00000;
-    // zlib flag and check bits
-    flg |= 0b_11111 - ((((cmf as u16) << 8) | (flg as u16)) % 0b_11111) as u8;
-    buffer.push(flg);
+    zlib::write_header(buffer, FLevel::Fastest, None);
 
     let mut before = None;
     let mut after = None;
 
     let count = chunks.len();
-    for (index, chunk) in chunks.enumerate() {
+    for (index, chunk) in chunks.into_iter().enumerate() {
         // deflate flag bits
         let is_last_chunk = index + 1 >= count;
         buffer.push(is_last_chunk.into());
@@ -148,8 +634,7 @@ pub fn write_non_deflated(buffer: &mut Vec<u8>, data: &[u8]) -> Range<usize> {
         after = Some(buffer.len());
     }
 
-    // adler-32 checksum of the uncompressed data
-    buffer.extend_from_slice(&adler32(data).to_le_bytes());
+    zlib::write_trailer(buffer, data);
 
     let after = after.unwrap_or(buffer.len());
     let before = before.unwrap_or(after);
@@ -157,37 +642,46 @@ pub fn write_non_deflated(buffer: &mut Vec<u8>, data: &[u8]) -> Range<usize> {
     before..after
 }
 
-pub fn write_non_png_chunk(buffer: &mut Vec<u8>, data: &[u8]) -> Range<usize> {
-    write_png_chunk(buffer, b"pkPK", data)
-}
-
-pub fn write_png_footer(buffer: &mut Vec<u8>) -> Range<usize> {
+pub fn write_png_footer(buffer: &mut Vec<u8>) -> Result<Range<usize>, Error> {
     write_png_chunk(buffer, b"IEND", b"")
 }
 
-pub fn write_png_chunk(buffer: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) -> Range<usize> {
+pub fn write_png_chunk(
+    buffer: &mut Vec<u8>,
+    chunk_type: &[u8; 4],
+    data: &[u8],
+) -> Result<Range<usize>, Error> {
     let before = buffer.len();
+    write_chunk_to(buffer, chunk_type, data)?;
+    let after = buffer.len();
+    Ok(before..after)
+}
 
-    buffer.extend_from_slice(
-        &u32::try_from(data.len())
-            .expect("png chunk larger than 2GiB")
-            .to_be_bytes(),
-    );
-    buffer.extend_from_slice(chunk_type);
-    buffer.extend_from_slice(data);
-    buffer.extend_from_slice(
-        &crc32(
-            &[chunk_type.as_slice(), data]
-                .into_iter()
-                .flatten()
-                .copied()
-                .collect::<Vec<_>>(),
-        )
-        .to_be_bytes(),
-    );
+/// Writes a chunk to `output`, computing its checksum as it goes rather than
+/// copying its data.
+fn write_chunk_to(output: &mut impl Write, chunk_type: &[u8; 4], data: &[u8]) -> Result<(), Error> {
+    let length = chunk_length(chunk_type, data.len())?;
+    let mut crc = Crc32::new();
+    crc.update(chunk_type);
+    crc.update(data);
 
-    let after = buffer.len();
-    before..after
+    output.write_all(&length.to_be_bytes())?;
+    output.write_all(chunk_type)?;
+    output.write_all(data)?;
+    output.write_all(&crc.finalize().to_be_bytes())?;
+    Ok(())
+}
+
+/// Returns the length of a chunk with `len` bytes of data, as it's written in
+/// the chunk's header, or an error if it's larger than 2GiB.
+pub fn chunk_length(chunk_type: &[u8; 4], len: usize) -> Result<u32, Error> {
+    if len > MAX_DIMENSION {
+        return Err(Error::ChunkTooLarge {
+            chunk_type: chunk_type.as_bstr().into(),
+            size: len,
+        });
+    }
+    Ok(len as u32)
 }
 
 pub fn write_png(
@@ -198,27 +692,777 @@ pub fn write_png(
     bit_depth: BitDepth,
     color_mode: ColorMode,
     palette: Option<&[u8]>,
-) {
-    write_png_header(buffer, width, height, bit_depth, color_mode);
-    if let Some(palette) = palette {
-        write_png_palette(buffer, palette);
+) -> Result<(), Error> {
+    let opts = PngOptions {
+        bit_depth,
+        color_mode,
+        color_palette: palette.map(<[u8]>::to_vec),
+        ..PngOptions::default()
+    };
+    let mut writer = PngWriter::new(buffer, width, height, &opts)?;
+    writer.write_padded(data)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// The number of bytes in each unfiltered scanline of an image.
+pub fn bytes_per_line(width: u32, bit_depth: BitDepth, color_mode: ColorMode) -> usize {
+    let bits_per_pixel = bit_depth.bits_per_sample() * color_mode.samples_per_pixel();
+    let bits_per_line = width as usize * bits_per_pixel;
+    bits_per_line.div_ceil(8)
+}
+
+/// Writes a PNG image a scanline at a time, without holding the whole image
+/// in memory.
+///
+/// Scanlines are filtered and compressed as they're written, with the zlib
+/// stream's checksum computed as it goes, and written in `IDAT` chunks of at
+/// most [`MAX_IDAT_LENGTH`] bytes. Interlaced images are the exception, as
+/// they're held in memory until their last scanline so that they can be
+/// reordered into their passes.
+pub struct PngWriter<W: Write> {
+    output: W,
+    /// The number of bytes written to `output`.
+    offset: u64,
+    width: u32,
+    height: u32,
+    bytes_per_line: usize,
+    bits_per_pixel: usize,
+    rows_written: u32,
+    filter: Filter,
+    level: Level,
+    /// The scanlines written so far, if the image is interlaced.
+    interlaced: Option<Vec<u8>>,
+    /// The previous scanline of the current pass, or empty before its first.
+    previous: Vec<u8>,
+    /// The previous scanline as it was filtered, for [`Filter::BruteForce`].
+    previous_filtered: Vec<u8>,
+    /// The current scanline as it's filtered.
+    filtered: Vec<u8>,
+    /// The image data's zlib stream, until it's ended after the last scanline.
+    deflater: Option<zlib::Deflater>,
+    /// Compressed image data not yet written in an `IDAT` chunk.
+    idat: Vec<u8>,
+    /// Ancillary chunks to be written after the image data.
+    trailing_chunks: Vec<([u8; 4], Vec<u8>)>,
+}
+
+impl<W: Write> PngWriter<W> {
+    /// Starts an image of the given dimensions, with the rest of its format
+    /// and metadata from `opts`, and writes its header and any metadata that
+    /// goes before the image data.
+    pub fn new(output: W, width: u32, height: u32, opts: &PngOptions) -> Result<Self, Error> {
+        let mut header = Vec::new();
+        write_png_header(
+            &mut header,
+            width,
+            height,
+            opts.bit_depth,
+            opts.color_mode,
+            opts.interlace,
+        )?;
+        if opts.srgb.is_some() && opts.icc_profile.is_some() {
+            return Err(invalid_chunk(
+                b"iCCP",
+                "an image can't have both an ICC profile and sRGB",
+            ));
+        }
+        // color space information goes before the palette, which it applies to
+        let color_space = [
+            opts.chromaticities.as_ref().map(Chromaticities::to_chunk),
+            opts.gamma.as_ref().map(Gamma::to_chunk),
+            opts.icc_profile.as_ref().map(IccProfile::to_chunk),
+            opts.srgb.as_ref().map(RenderingIntent::to_chunk),
+        ];
+        for chunk in color_space.into_iter().flatten() {
+            let (chunk_type, data) = chunk?;
+            write_png_chunk(&mut header, &chunk_type, &data)?;
+        }
+        if let Some(palette) = opts.color_palette.as_deref() {
+            write_png_palette(&mut header, palette)?;
+        }
+        if let Some(transparency) = &opts.transparency {
+            transparency
+                .check(
+                    opts.bit_depth,
+                    opts.color_mode,
+                    opts.color_palette.as_deref(),
+                )
+                .map_err(|reason| invalid_chunk(b"tRNS", reason))?;
+            let (chunk_type, data) = transparency.to_chunk()?;
+            write_png_chunk(&mut header, &chunk_type, &data)?;
+        }
+        if let Some(physical_dimensions) = &opts.physical_dimensions {
+            let (chunk_type, data) = physical_dimensions.to_chunk()?;
+            write_png_chunk(&mut header, &chunk_type, &data)?;
+        }
+        let mut trailing_chunks = Vec::new();
+        for text in &opts.text {
+            let (chunk_type, data) = text.to_chunk()?;
+            if text.after_image {
+                trailing_chunks.push((chunk_type, data));
+            } else {
+                write_png_chunk(&mut header, &chunk_type, &data)?;
+            }
+        }
+
+        let bytes_per_line = bytes_per_line(width, opts.bit_depth, opts.color_mode);
+        let bits_per_pixel = opts.bit_depth.bits_per_sample() * opts.color_mode.samples_per_pixel();
+        let mut idat = Vec::new();
+        let deflater = zlib::Deflater::new(opts.compression_level, &mut idat);
+        let mut writer = Self {
+            output,
+            offset: 0,
+            width,
+            height,
+            bytes_per_line,
+            bits_per_pixel,
+            rows_written: 0,
+            filter: opts.filter,
+            level: opts.compression_level,
+            interlaced: opts.interlace.then(Vec::new),
+            previous: Vec::new(),
+            previous_filtered: Vec::new(),
+            filtered: Vec::new(),
+            deflater: Some(deflater),
+            idat,
+            trailing_chunks,
+        };
+        writer.write_raw(&header)?;
+        Ok(writer)
+    }
+
+    /// The number of bytes written to the output so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Writes the next scanline, which must be exactly as long as the image's
+    /// scanlines are.
+    pub fn write_row(&mut self, row: &[u8]) -> Result<(), Error> {
+        if row.len() != self.bytes_per_line {
+            return Err(invalid_input(
+                "scanline length doesn't match the image width",
+            ));
+        }
+        if self.rows_written == self.height {
+            return Err(invalid_input(
+                "all of the image's scanlines have been written",
+            ));
+        }
+        self.rows_written += 1;
+        match &mut self.interlaced {
+            Some(image) => image.extend_from_slice(row),
+            None => self.write_scanline(row)?,
+        }
+
+        if self.rows_written == self.height {
+            if let Some(image) = self.interlaced.take() {
+                self.write_passes(&image)?;
+            }
+            self.finish_data()?;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` as the image's remaining scanlines, with the last padded
+    /// with zeroes, and any after it filled with them.
+    pub fn write_padded(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut rows = data.chunks(self.bytes_per_line);
+        let mut padded = Vec::new();
+        while self.rows_written < self.height {
+            let row = rows.next().unwrap_or_default();
+            if row.len() == self.bytes_per_line {
+                self.write_row(row)?;
+            } else {
+                padded.clear();
+                padded.extend_from_slice(row);
+                padded.resize(self.bytes_per_line, 0x00);
+                self.write_row(&padded)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `data` as all of the image's scanlines, like
+    /// [`PngWriter::write_padded`], but unfiltered, each in its own stored
+    /// block, and in a single `IDAT` chunk. Each scanline is then in the output
+    /// as it is, [`STORED_SCANLINE_GAP`] bytes after the previous one, or after
+    /// the chunk's header and the zlib header for the first.
+    pub fn write_stored(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.rows_written > 0 || self.interlaced.is_some() {
+            return Err(invalid_input(
+                "stored scanlines must be the whole of an image that isn't interlaced",
+            ));
+        }
+        if self.bytes_per_line > MAX_STORED_SCANLINE {
+            return Err(invalid_input(
+                "scanlines are too long to be stored in a block each",
+            ));
+        }
+        self.deflater = None;
+        self.idat.clear();
+
+        let mut idat = Vec::new();
+        zlib::write_header(&mut idat, FLevel::Fastest, None);
+        let mut adler = Adler32::new();
+        let mut rows = data.chunks(self.bytes_per_line);
+        let mut scanline = Vec::with_capacity(1 + self.bytes_per_line);
+        for index in 0..self.height {
+            let row = rows.next().unwrap_or_default();
+            scanline.clear();
+            scanline.push(FilterType::None as u8);
+            scanline.extend_from_slice(row);
+            scanline.resize(1 + self.bytes_per_line, 0x00);
+            adler.update(&scanline);
+
+            // deflate flag bits
+            idat.push(u8::from(index + 1 == self.height));
+            // deflate block length and its complement
+            let length = scanline.len() as u16;
+            idat.extend_from_slice(&length.to_le_bytes());
+            idat.extend_from_slice(&(!length).to_le_bytes());
+            idat.extend_from_slice(&scanline);
+        }
+        idat.extend_from_slice(&adler.finalize().to_be_bytes());
+        self.rows_written = self.height;
+
+        write_chunk_to(&mut self.output, b"IDAT", &idat)?;
+        self.offset += 12 + idat.len() as u64;
+        Ok(())
+    }
+
+    /// Writes an ancillary chunk, before the image data if no scanlines have
+    /// been written yet, or after it once they all have.
+    pub fn write_chunk(&mut self, chunk_type: &[u8; 4], data: &[u8]) -> Result<(), Error> {
+        if self.rows_written > 0 && self.rows_written < self.height {
+            return Err(invalid_input("chunks can't be written between scanlines"));
+        }
+        write_chunk_to(&mut self.output, chunk_type, data)?;
+        self.offset += 12 + data.len() as u64;
+        Ok(())
+    }
+
+    /// Ends the image with its footer, once all of its scanlines have been
+    /// written, and returns the output.
+    pub fn finish(mut self) -> Result<W, Error> {
+        if self.rows_written < self.height {
+            return Err(invalid_input(
+                "not all of the image's scanlines have been written",
+            ));
+        }
+        self.finish_data()?;
+        for (chunk_type, data) in mem::take(&mut self.trailing_chunks) {
+            self.write_chunk(&chunk_type, &data)?;
+        }
+        let mut footer = Vec::new();
+        write_png_footer(&mut footer)?;
+        self.write_raw(&footer)?;
+        self.output.flush()?;
+        Ok(self.output)
+    }
+
+    /// Writes the Adam7 passes of a whole interlaced image.
+    fn write_passes(&mut self, image: &[u8]) -> Result<(), Error> {
+        for pass in ADAM7 {
+            let (x, y, dx, dy) = pass;
+            let (pass_width, pass_height, pass_bytes_per_line) =
+                pass_dimensions(pass, self.width, self.height, self.bits_per_pixel);
+            self.previous.clear();
+            let mut row = vec![0x00; pass_bytes_per_line];
+            for pass_y in 0..pass_height {
+                let line = &image[(y + pass_y * dy) * self.bytes_per_line..][..self.bytes_per_line];
+                for pass_x in 0..pass_width {
+                    copy_pixel(line, x + pass_x * dx, &mut row, pass_x, self.bits_per_pixel);
+                }
+                self.write_scanline(&row)?;
+            }
+        }
+        Ok(())
     }
-    // We need to insert a 0x00 byte at the start of every line (every `width`
-    // bytes) to indicate that the line is not filtered.
-    let mut filtered_data = Vec::new();
 
+    /// Filters and compresses a scanline of the image, or of the current pass
+    /// if it's interlaced.
+    fn write_scanline(&mut self, row: &[u8]) -> Result<(), Error> {
+        self.previous.resize(row.len(), 0x00);
+        self.filter_row(row);
+        if let Some(deflater) = &mut self.deflater {
+            deflater.write(&self.filtered, &mut self.idat);
+        }
+        self.previous.copy_from_slice(row);
+        mem::swap(&mut self.previous_filtered, &mut self.filtered);
+        self.write_idat(MAX_IDAT_LENGTH)
+    }
+
+    /// Filters `row` into `self.filtered`, with the filter type chosen as
+    /// `self.filter` says.
+    fn filter_row(&mut self, row: &[u8]) {
+        let bytes_per_pixel = (self.bits_per_pixel / 8).max(1);
+        self.filtered.clear();
+        if let Filter::Fixed(filter_type) = self.filter {
+            filter_row(
+                filter_type,
+                row,
+                &self.previous,
+                bytes_per_pixel,
+                &mut self.filtered,
+            );
+            return;
+        }
+
+        let mut best_cost = u64::MAX;
+        let mut candidate = Vec::new();
+        for filter_type in FilterType::ALL {
+            candidate.clear();
+            filter_row(
+                filter_type,
+                row,
+                &self.previous,
+                bytes_per_pixel,
+                &mut candidate,
+            );
+            let cost = if self.filter == Filter::BruteForce {
+                let mut compressed = Vec::new();
+                write_deflated_with_dictionary(
+                    &mut compressed,
+                    &candidate,
+                    &self.previous_filtered,
+                    self.level,
+                );
+                compressed.len() as u64
+            } else {
+                candidate[1..]
+                    .iter()
+                    .map(|&byte| u64::from((byte as i8).unsigned_abs()))
+                    .sum()
+            };
+            if cost < best_cost {
+                best_cost = cost;
+                mem::swap(&mut self.filtered, &mut candidate);
+            }
+        }
+    }
+
+    /// Ends the image data's zlib stream, if it hasn't been already, and
+    /// writes the rest of it.
+    fn finish_data(&mut self) -> Result<(), Error> {
+        if let Some(deflater) = self.deflater.take() {
+            deflater.finish(&mut self.idat);
+            self.write_idat(1)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the pending image data in `IDAT` chunks while there's at least
+    /// `min_length` of it.
+    fn write_idat(&mut self, min_length: usize) -> Result<(), Error> {
+        let mut start = 0;
+        while self.idat.len() - start >= min_length {
+            let end = (start + MAX_IDAT_LENGTH).min(self.idat.len());
+            write_chunk_to(&mut self.output, b"IDAT", &self.idat[start..end])?;
+            self.offset += 12 + (end - start) as u64;
+            start = end;
+        }
+        self.idat.drain(..start);
+        Ok(())
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.write_all(bytes)?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+/// A chunk read from a PNG file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Chunk<'data> {
+    pub chunk_type: [u8; 4],
+    pub data: &'data [u8],
+    /// The offset of the chunk's length field in the file.
+    pub offset: usize,
+}
+
+/// An image read from a PNG file, with its pixel data as unfiltered
+/// scanlines, in the form [`write_png`] takes it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: BitDepth,
+    pub color_mode: ColorMode,
+    pub palette: Option<Vec<u8>>,
+    pub transparency: Option<Transparency>,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// Returns the color of each pixel, row by row, as 8-bit RGBA. Samples of
+    /// other bit depths are scaled to 8 bits, and palette indices are looked
+    /// up, with any missing from the palette treated as black. Pixels made
+    /// transparent by [`Image::transparency`] have an alpha of zero.
+    pub fn to_rgba8(&self) -> Vec<[u8; 4]> {
+        let bits = self.bit_depth.bits_per_sample();
+        let max = (1_u32 << bits) - 1;
+        let samples_per_pixel = self.color_mode.samples_per_pixel();
+        let bytes_per_line = bytes_per_line(self.width, self.bit_depth, self.color_mode);
+        let palette = self.palette.as_deref().unwrap_or_default();
+        let palette_alpha = match &self.transparency {
+            Some(Transparency::Palette(alpha)) => &alpha[..],
+            _ => &[],
+        };
+
+        let mut pixels = Vec::with_capacity(self.width as usize * self.height as usize);
+        for line in self.data.chunks(bytes_per_line) {
+            let raw = |index: usize| -> u32 {
+                match bits {
+                    16 => u16::from_be_bytes([line[index * 2], line[index * 2 + 1]]).into(),
+                    8 => line[index].into(),
+                    _ => {
+                        let bit = index * bits;
+                        u32::from(line[bit / 8] >> (8 - bits - bit % 8)) & max
+                    },
+                }
+            };
+            let scaled = |index: usize| -> u8 {
+                if bits == 16 {
+                    (raw(index) >> 8) as u8
+                } else {
+                    (raw(index) * 255 / max) as u8
+                }
+            };
+
+            for x in 0..self.width as usize {
+                let first = x * samples_per_pixel;
+                let sample = |channel: usize| scaled(first + channel);
+                let is_transparent = match self.transparency {
+                    Some(Transparency::Lightness(lightness)) => raw(first) == lightness.into(),
+                    Some(Transparency::RedGreenBlue(red, green, blue)) => [red, green, blue]
+                        .into_iter()
+                        .enumerate()
+                        .all(|(channel, value)| raw(first + channel) == value.into()),
+                    _ => false,
+                };
+                let alpha = if is_transparent { 0x00 } else { 0xFF };
+                pixels.push(match self.color_mode {
+                    Lightness => [sample(0), sample(0), sample(0), alpha],
+                    LightnessAlpha => [sample(0), sample(0), sample(0), sample(1)],
+                    RedGreenBlue => [sample(0), sample(1), sample(2), alpha],
+                    RedGreenBlueAlpha => [sample(0), sample(1), sample(2), sample(3)],
+                    Indexed => {
+                        let index = raw(first) as usize;
+                        let alpha = palette_alpha.get(index).copied().unwrap_or(0xFF);
+                        match palette.get(index * 3..index * 3 + 3) {
+                            Some(&[red, green, blue]) => [red, green, blue, alpha],
+                            _ => [0x00, 0x00, 0x00, 0xFF],
+                        }
+                    },
+                });
+            }
+        }
+        pixels
+    }
+}
+
+/// Splits a PNG file into its chunks, up to and including `IEND`, checking
+/// the signature and each chunk's CRC-32. Anything after `IEND` is ignored.
+pub fn read_png_chunks(data: &[u8]) -> Result<Vec<Chunk>, Error> {
+    if !data.starts_with(SIGNATURE) {
+        return Err(invalid_png(0, "missing PNG signature"));
+    }
+
+    let mut chunks = Vec::new();
+    let mut offset = SIGNATURE.len();
+    loop {
+        let header = data
+            .get(offset..offset + 8)
+            .ok_or_else(|| invalid_png(offset, "truncated chunk header"))?;
+        let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        if length > MAX_DIMENSION {
+            return Err(invalid_png(offset, "chunk is larger than 2GiB"));
+        }
+        let chunk_type: [u8; 4] = header[4..].try_into().unwrap();
+
+        let end = offset + 8 + length;
+        let stored = data
+            .get(end..end + 4)
+            .ok_or_else(|| invalid_png(offset, "truncated chunk"))?;
+        let stored = u32::from_be_bytes(stored.try_into().unwrap());
+        let computed = crc32(&data[offset + 4..end]);
+        if stored != computed {
+            return Err(Error::ChecksumMismatch {
+                checksum: "CRC-32",
+                stored,
+                computed,
+            });
+        }
+
+        chunks.push(Chunk {
+            chunk_type,
+            data: &data[offset + 8..end],
+            offset,
+        });
+        offset = end + 4;
+        if &chunk_type == b"IEND" {
+            return Ok(chunks);
+        }
+    }
+}
+
+/// Decodes a PNG file's pixel data, reversing any scanline filtering.
+/// Ancillary chunks are ignored, other than checking their CRCs.
+pub fn read_png(data: &[u8]) -> Result<Image, Error> {
+    let chunks = read_png_chunks(data)?;
+
+    let header = chunks
+        .first()
+        .filter(|chunk| &chunk.chunk_type == b"IHDR" && chunk.data.len() == 13)
+        .ok_or_else(|| invalid_png(SIGNATURE.len(), "first chunk isn't a valid IHDR"))?;
+    let fields = header.offset + 8;
+    let ihdr = header.data;
+    let width = u32::from_be_bytes(ihdr[0x0..0x4].try_into().unwrap());
+    let height = u32::from_be_bytes(ihdr[0x4..0x8].try_into().unwrap());
+    if width == 0
+        || height == 0
+        || width as usize > MAX_DIMENSION
+        || height as usize > MAX_DIMENSION
+    {
+        return Err(invalid_png(fields, "invalid image dimensions"));
+    }
+    let bit_depth = BitDepth::from_u8(ihdr[0x8])
+        .ok_or_else(|| invalid_png(fields + 0x8, "invalid bit depth"))?;
+    let color_mode = ColorMode::from_u8(ihdr[0x9])
+        .ok_or_else(|| invalid_png(fields + 0x9, "invalid color type"))?;
+    if !color_mode.allows(bit_depth) {
+        return Err(invalid_png(
+            fields + 0x8,
+            "bit depth isn't allowed for color type",
+        ));
+    }
+    if ihdr[0xA] != 0 {
+        return Err(invalid_png(fields + 0xA, "unknown compression method"));
+    }
+    if ihdr[0xB] != 0 {
+        return Err(invalid_png(fields + 0xB, "unknown filter method"));
+    }
+    let interlaced = match ihdr[0xC] {
+        0 => false,
+        1 => true,
+        _ => return Err(invalid_png(fields + 0xC, "unknown interlace method")),
+    };
+
+    let palette = chunks
+        .iter()
+        .find(|chunk| &chunk.chunk_type == b"PLTE")
+        .map(|chunk| chunk.data.to_vec());
+    if color_mode == Indexed && palette.is_none() {
+        return Err(invalid_png(header.offset, "indexed image has no palette"));
+    }
+
+    // a tRNS chunk that doesn't suit the color mode, such as one in an image
+    // that already has an alpha channel, is ignored like other decoders do
+    let transparency = chunks
+        .iter()
+        .find(|chunk| &chunk.chunk_type == b"tRNS")
+        .and_then(|chunk| {
+            let transparency = Transparency::from_chunk(chunk.data, color_mode);
+            if transparency.is_none() {
+                warn!(
+                    "ignoring tRNS chunk at offset {} that doesn't suit {color_mode:?} images",
+                    chunk.offset
+                );
+            }
+            transparency
+        });
+
+    let mut idat = Vec::new();
+    let mut idat_offset = None;
+    for chunk in chunks.iter().filter(|chunk| &chunk.chunk_type == b"IDAT") {
+        idat_offset.get_or_insert(chunk.offset);
+        idat.extend_from_slice(chunk.data);
+    }
+    let idat_offset = idat_offset.ok_or_else(|| invalid_png(header.offset, "no IDAT chunks"))?;
+
+    let bytes_per_line = bytes_per_line(width, bit_depth, color_mode);
     let bits_per_pixel = bit_depth.bits_per_sample() * color_mode.samples_per_pixel();
-    let bits_per_line = width * bits_per_pixel as u32;
-    let bytes_per_line = (bits_per_line + 7) / 8;
+    let bytes_per_pixel = (bits_per_pixel / 8).max(1);
+    let passes: &[(usize, usize, usize, usize)] = if interlaced { &ADAM7 } else { &[(0, 0, 1, 1)] };
+    let mut expected = 0_usize;
+    for &pass in passes {
+        let (_, pass_height, pass_bytes_per_line) =
+            pass_dimensions(pass, width, height, bits_per_pixel);
+        expected = (pass_bytes_per_line + 1)
+            .checked_mul(pass_height)
+            .and_then(|len| len.checked_add(expected))
+            .ok_or_else(|| invalid_png(fields, "invalid image dimensions"))?;
+    }
+    let scanlines = zlib::inflate_with(&idat, None, expected)?;
+    if scanlines.len() != expected {
+        return Err(invalid_png(
+            idat_offset,
+            "image data is shorter than its dimensions",
+        ));
+    }
+
+    let unknown_filter = || invalid_png(idat_offset, "unknown scanline filter type");
+    let data = if interlaced {
+        let mut data = vec![0x00; bytes_per_line * height as usize];
+        let mut rest = &scanlines[..];
+        for pass in ADAM7 {
+            let (x, y, dx, dy) = pass;
+            let (pass_width, pass_height, pass_bytes_per_line) =
+                pass_dimensions(pass, width, height, bits_per_pixel);
+            let (pass_scanlines, after) = rest.split_at((pass_bytes_per_line + 1) * pass_height);
+            rest = after;
+            let pass_data = unfilter(pass_scanlines, pass_bytes_per_line, bytes_per_pixel)
+                .ok_or_else(unknown_filter)?;
+            for pass_y in 0..pass_height {
+                let row = &pass_data[pass_y * pass_bytes_per_line..];
+                let line = &mut data[(y + pass_y * dy) * bytes_per_line..][..bytes_per_line];
+                for pass_x in 0..pass_width {
+                    copy_pixel(row, pass_x, line, x + pass_x * dx, bits_per_pixel);
+                }
+            }
+        }
+        data
+    } else {
+        unfilter(&scanlines, bytes_per_line, bytes_per_pixel).ok_or_else(unknown_filter)?
+    };
+
+    Ok(Image {
+        width,
+        height,
+        bit_depth,
+        color_mode,
+        palette,
+        transparency,
+        data,
+    })
+}
 
-    for (i, byte) in data.iter().enumerate() {
-        if i % (bytes_per_line as usize) == 0 {
-            filtered_data.push(0x00);
+/// Reverses the filter applied to each scanline, dropping the filter type
+/// bytes, or returns `None` if any filter type is unknown.
+fn unfilter(scanlines: &[u8], bytes_per_line: usize, bytes_per_pixel: usize) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(scanlines.len());
+    for line in scanlines.chunks(bytes_per_line + 1) {
+        let filter = FilterType::from_u8(line[0])?;
+        let start = data.len();
+        data.extend_from_slice(&line[1..]);
+        let (previous, current) = data.split_at_mut(start);
+        let previous = (start > 0).then(|| &previous[start - bytes_per_line..]);
+        for index in 0..bytes_per_line {
+            let left = index
+                .checked_sub(bytes_per_pixel)
+                .map_or(0, |left| current[left]);
+            let up = previous.map_or(0, |previous| previous[index]);
+            let up_left = previous
+                .zip(index.checked_sub(bytes_per_pixel))
+                .map_or(0, |(previous, left)| previous[left]);
+            current[index] = current[index].wrapping_add(filter.predict(left, up, up_left));
         }
-        filtered_data.push(*byte);
     }
-    write_png_body(buffer, &filtered_data);
-    write_png_footer(buffer);
+    Some(data)
+}
+
+/// The Adam7 interlacing passes, as the column and row of their first pixel
+/// and the spacing between their columns and rows.
+const ADAM7: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// The width, height and scanline length of a pass over an image, which are
+/// all zero if the pass is empty.
+fn pass_dimensions(
+    (x, y, dx, dy): (usize, usize, usize, usize),
+    width: u32,
+    height: u32,
+    bits_per_pixel: usize,
+) -> (usize, usize, usize) {
+    let pass_width = (width as usize).saturating_sub(x).div_ceil(dx);
+    let pass_height = (height as usize).saturating_sub(y).div_ceil(dy);
+    if pass_width == 0 || pass_height == 0 {
+        return (0, 0, 0);
+    }
+    (
+        pass_width,
+        pass_height,
+        (pass_width * bits_per_pixel).div_ceil(8),
+    )
+}
+
+/// Copies the pixel in column `from_x` of the scanline `from` to column `to_x`
+/// of `to`, including pixels smaller than a byte.
+fn copy_pixel(from: &[u8], from_x: usize, to: &mut [u8], to_x: usize, bits_per_pixel: usize) {
+    if bits_per_pixel >= 8 {
+        let len = bits_per_pixel / 8;
+        to[to_x * len..][..len].copy_from_slice(&from[from_x * len..][..len]);
+    } else {
+        // pixels are packed from the most significant bit down
+        let mask = (1_u8 << bits_per_pixel) - 1;
+        let from_bit = from_x * bits_per_pixel;
+        let value = from[from_bit / 8] >> (8 - bits_per_pixel - from_bit % 8) & mask;
+        let to_bit = to_x * bits_per_pixel;
+        let shift = 8 - bits_per_pixel - to_bit % 8;
+        to[to_bit / 8] = to[to_bit / 8] & !(mask << shift) | value << shift;
+    }
+}
+
+/// Appends `row` to `output` filtered with `filter_type`, preceded by the
+/// filter type byte, given the previous scanline, which is zeroes for the
+/// first.
+fn filter_row(
+    filter_type: FilterType,
+    row: &[u8],
+    previous: &[u8],
+    bytes_per_pixel: usize,
+    output: &mut Vec<u8>,
+) {
+    output.push(filter_type.u8());
+    for index in 0..row.len() {
+        let left = index
+            .checked_sub(bytes_per_pixel)
+            .map_or(0, |left| row[left]);
+        let up = previous[index];
+        let up_left = index
+            .checked_sub(bytes_per_pixel)
+            .map_or(0, |left| previous[left]);
+        output.push(row[index].wrapping_sub(filter_type.predict(left, up, up_left)));
+    }
+}
+
+/// Predicts a byte from its neighbours to the left, above and above-left,
+/// whichever is closest to their gradient.
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = i16::from(left) + i16::from(up) - i16::from(up_left);
+    let distance = |value: u8| (estimate - i16::from(value)).abs();
+    if distance(left) <= distance(up) && distance(left) <= distance(up_left) {
+        left
+    } else if distance(up) <= distance(up_left) {
+        up
+    } else {
+        up_left
+    }
+}
+
+fn invalid_png(offset: usize, reason: &'static str) -> Error {
+    Error::InvalidPng { offset, reason }
+}
+
+fn invalid_chunk(chunk_type: &[u8; 4], reason: &'static str) -> Error {
+    Error::InvalidPngChunk {
+        chunk_type: chunk_type.as_bstr().into(),
+        reason,
+    }
+}
+
+fn invalid_input(reason: &str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidInput, reason).into()
 }
 
 pub const PALLETTE_8_BIT_DATA: &[u8; 256 * 3] = &[
@@ -1 +1 @@
-use crate::font;
+
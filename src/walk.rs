@@ -0,0 +1,334 @@
+//! Reading files from a directory tree, for
+//! [`Files::from_dir`](crate::Files::from_dir).
+
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::Metadata;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use tracing::warn;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::DirOptions;
+use crate::Directory;
+use crate::Error;
+use crate::FileEntry;
+use crate::Symlink;
+
+/// Reads the files in the directory at `root` into entries named by their
+/// paths relative to it, in the order they were found.
+pub fn walk(root: &Path, opts: &DirOptions) -> Result<Vec<(Vec<u8>, FileEntry)>, Error> {
+    let patterns = opts
+        .exclude
+        .iter()
+        .filter_map(|line| Pattern::parse(line.as_bytes(), b""))
+        .collect();
+    let mut walker = Walker {
+        opts,
+        patterns,
+        ancestors: Vec::new(),
+        entries: Vec::new(),
+    };
+    walker.walk_dir(root, b"")?;
+    Ok(walker.entries)
+}
+
+struct Walker<'opts> {
+    opts: &'opts DirOptions,
+    /// The exclude patterns in effect, from the options and then any
+    /// `.gitignore` files from the root down to the current directory.
+    patterns: Vec<Pattern>,
+    /// The canonical paths of the directories being walked, to avoid cycles
+    /// when following symbolic links.
+    ancestors: Vec<PathBuf>,
+    entries: Vec<(Vec<u8>, FileEntry)>,
+}
+
+impl Walker<'_> {
+    /// Adds the contents of the directory at `dir`, whose name relative to the
+    /// root is `prefix`, which is empty or ends with a slash.
+    fn walk_dir(&mut self, dir: &Path, prefix: &[u8]) -> Result<(), Error> {
+        let canonical = fs::canonicalize(dir).map_err(|error| io_error(dir, error))?;
+        self.ancestors.push(canonical);
+        let patterns_before = self.patterns.len();
+
+        if self.opts.gitignore {
+            let path = dir.join(".gitignore");
+            match fs::read(&path) {
+                Ok(lines) => self.patterns.extend(
+                    lines
+                        .split(|&byte| byte == b'\n')
+                        .filter_map(|line| Pattern::parse(line, prefix)),
+                ),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {},
+                Err(error) => return Err(io_error(&path, error)),
+            }
+        }
+
+        let mut children = Vec::new();
+        for child in fs::read_dir(dir).map_err(|error| io_error(dir, error))? {
+            children.push(child.map_err(|error| io_error(dir, error))?.file_name());
+        }
+        children.sort();
+
+        for child in children {
+            let path = dir.join(&child);
+            let name = [prefix, &normalize(&child, self.opts)].concat();
+
+            let mut metadata =
+                fs::symlink_metadata(&path).map_err(|error| io_error(&path, error))?;
+            if metadata.file_type().is_symlink() && self.opts.follow_symlinks {
+                match fs::metadata(&path) {
+                    Ok(target) => metadata = target,
+                    // a dangling link can only be added as a link
+                    Err(error) if error.kind() == io::ErrorKind::NotFound => {},
+                    Err(error) => return Err(io_error(&path, error)),
+                }
+            }
+            let file_type = metadata.file_type();
+
+            if is_excluded(&self.patterns, &name, file_type.is_dir()) {
+                continue;
+            }
+
+            let mut entry = FileEntry {
+                mtime: self.opts.mtimes.then(|| mtime(&metadata)).flatten(),
+                mode: self.opts.modes.then(|| mode(&metadata)).flatten(),
+                ..FileEntry::default()
+            };
+            if file_type.is_symlink() {
+                let target = fs::read_link(&path).map_err(|error| io_error(&path, error))?;
+                entry.body = normalize(target.as_os_str(), self.opts);
+                entry.kind = Symlink;
+                self.entries.push((name, entry));
+            } else if file_type.is_dir() {
+                let canonical = fs::canonicalize(&path).map_err(|error| io_error(&path, error))?;
+                if self.ancestors.contains(&canonical) {
+                    warn!("skipping {path:?}, which is a symbolic link to one of its ancestors");
+                    continue;
+                }
+                let name = [&name[..], b"/"].concat();
+                if self.opts.directories {
+                    entry.kind = Directory;
+                    self.entries.push((name.clone(), entry));
+                }
+                self.walk_dir(&path, &name)?;
+            } else if file_type.is_file() {
+                entry.body = fs::read(&path).map_err(|error| io_error(&path, error))?;
+                self.entries.push((name, entry));
+            } else {
+                warn!("skipping {path:?}, which isn't a file, directory or symbolic link");
+            }
+        }
+
+        self.patterns.truncate(patterns_before);
+        self.ancestors.pop();
+        Ok(())
+    }
+}
+
+/// Converts a file name or link target to bytes, with slashes as separators,
+/// and normalized to NFC if that's enabled.
+pub fn normalize(name: &OsStr, opts: &DirOptions) -> Vec<u8> {
+    #[cfg(unix)]
+    let name = std::os::unix::ffi::OsStrExt::as_bytes(name).to_vec();
+    #[cfg(not(unix))]
+    let name = name.to_string_lossy().replace('\\', "/").into_bytes();
+
+    match std::str::from_utf8(&name) {
+        Ok(utf8) if opts.normalize_unicode => utf8.nfc().collect::<String>().into_bytes(),
+        _ => name,
+    }
+}
+
+/// The modification time of a file in seconds since the Unix epoch, if the
+/// platform provides it.
+pub fn mtime(metadata: &Metadata) -> Option<i64> {
+    let modified = metadata.modified().ok()?;
+    Some(match modified.duration_since(UNIX_EPOCH) {
+        Ok(after) => after.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs() as i64),
+    })
+}
+
+/// The permission bits of a file, on Unix.
+#[cfg(unix)]
+pub fn mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub fn mode(_metadata: &Metadata) -> Option<u32> {
+    None
+}
+
+/// Wraps an I/O error with the path it occurred on.
+pub fn io_error(path: &Path, error: io::Error) -> Error {
+    Error::Io {
+        path: path.to_string_lossy().as_bytes().into(),
+        kind: error.kind(),
+        message: error.to_string(),
+    }
+}
+
+/// Whether the last of `patterns` to match a path excludes it, rather than
+/// re-including it.
+pub fn is_excluded(patterns: &[Pattern], path: &[u8], is_dir: bool) -> bool {
+    let last = patterns
+        .iter()
+        .rev()
+        .find(|pattern| pattern.matches(path, is_dir));
+    matches!(last, Some(pattern) if !pattern.negated)
+}
+
+/// A `.gitignore`-style pattern.
+///
+/// Patterns containing a slash other than a trailing one match paths relative
+/// to their base, and others match only the final component of a path. A
+/// trailing slash matches only directories, and a leading `!` re-includes
+/// paths excluded by earlier patterns. `*` and `?` don't match slashes, but
+/// `**` between slashes matches any number of directories.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Pattern {
+    /// The directory the pattern applies within, empty or ending with a
+    /// slash.
+    base: Vec<u8>,
+    glob: Vec<u8>,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl Pattern {
+    /// Parses a line of a `.gitignore` file in the directory `base`, returning
+    /// `None` for blank lines and comments.
+    pub fn parse(line: &[u8], base: &[u8]) -> Option<Self> {
+        let mut line = line.strip_suffix(b"\r").unwrap_or(line);
+        // trailing spaces are ignored unless they're escaped
+        while let [rest @ .., last, b' '] = line {
+            if *last == b'\\' {
+                break;
+            }
+            line = &line[..rest.len() + 1];
+        }
+        if line == b" " || line.is_empty() || line.starts_with(b"#") {
+            return None;
+        }
+
+        let (negated, line) = match line.strip_prefix(b"!") {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix(b"/") {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let anchored = line.contains(&b'/');
+        let line = line.strip_prefix(b"/").unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            base: base.to_vec(),
+            glob: line.to_vec(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    /// Whether the pattern matches `path`, relative to the root and without a
+    /// trailing slash.
+    pub fn matches(&self, path: &[u8], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let path = match path.strip_prefix(self.base.as_slice()) {
+            Some(path) => path,
+            None => return false,
+        };
+        if self.anchored {
+            glob_matches(&self.glob, path)
+        } else {
+            let name = path.rsplit(|&byte| byte == b'/').next().unwrap_or(path);
+            glob_matches(&self.glob, name)
+        }
+    }
+}
+
+fn glob_matches(glob: &[u8], text: &[u8]) -> bool {
+    match glob {
+        [] => text.is_empty(),
+        [b'*', b'*', b'/', rest @ ..] =>
+            glob_matches(rest, text)
+                || (0..text.len())
+                    .any(|index| text[index] == b'/' && glob_matches(rest, &text[index + 1..])),
+        [b'*', b'*'] => true,
+        [b'*', rest @ ..] =>
+            glob_matches(rest, text)
+                || matches!(text, [first, tail @ ..] if *first != b'/' && glob_matches(glob, tail)),
+        [b'?', rest @ ..] => match text {
+            [first, ..] if *first != b'/' => {
+                let len = utf8_len(*first).min(text.len());
+                glob_matches(rest, &text[len..])
+            },
+            _ => false,
+        },
+        [b'[', class @ ..] => match (match_class(class, text.first().copied()), text) {
+            (Some((true, rest)), [_, tail @ ..]) => glob_matches(rest, tail),
+            (Some(_), _) => false,
+            // an unterminated class is matched literally
+            (None, [b'[', tail @ ..]) => glob_matches(class, tail),
+            (None, _) => false,
+        },
+        [b'\\', literal, rest @ ..] | [literal, rest @ ..] =>
+            matches!(text, [first, tail @ ..] if first == literal && glob_matches(rest, tail)),
+    }
+}
+
+/// Matches a byte against a bracketed character class, which follows the
+/// opening bracket in `class`. Returns whether it matched and the rest of
+/// the glob following the class, or `None` if the class isn't terminated.
+fn match_class(class: &[u8], byte: Option<u8>) -> Option<(bool, &[u8])> {
+    let (negated, mut class) = match class {
+        [b'!' | b'^', rest @ ..] => (true, rest),
+        _ => (false, class),
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let (low, rest) = match class {
+            [] => return None,
+            [b']', rest @ ..] if !first =>
+                return Some((
+                    matches!(byte, Some(byte) if byte != b'/') && matched != negated,
+                    rest,
+                )),
+            [b'\\', low, rest @ ..] | [low, rest @ ..] => (*low, rest),
+        };
+        let (high, rest) = match rest {
+            [b'-', b'\\', high, rest @ ..] => (*high, rest),
+            [b'-', high, rest @ ..] if *high != b']' => (*high, rest),
+            _ => (low, rest),
+        };
+        matched |= matches!(byte, Some(byte) if (low..=high).contains(&byte));
+        class = rest;
+        first = false;
+    }
+}
+
+/// The length of the UTF-8 sequence starting with `byte`, so that `?` matches
+/// a whole character.
+fn utf8_len(byte: u8) -> usize {
+    match byte {
+        0xF0.. => 4,
+        0xE0.. => 3,
+        0xC0.. => 2,
+        _ => 1,
+    }
+}
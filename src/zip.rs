@@ -1,189 +1,1266 @@
+use std::borrow::Cow;
+use std::io;
+use std::io::Read;
 use std::io::Write;
 
 use bstr::ByteSlice;
 
+pub use self::Compression::*;
+pub use self::FileOrder::*;
 use crate::checksums::crc32;
-use crate::padding::write_aligned_pad_end;
-use crate::padding::write_aligned_pad_start;
+use crate::checksums::Crc32;
+use crate::deflate::deflate_with;
+use crate::deflate::inflate_with_limit;
+use crate::deflate::Deflater;
+use crate::deflate::Level;
+use crate::padding::pad_end_len;
+use crate::padding::pad_start_len;
+use crate::Error;
+use crate::FileEntry;
+use crate::FileKind;
+use crate::ZipOptions;
 
-const BLOCK_SIZE: usize = 1024;
+/// The default alignment of file bodies in an archive.
+pub const BLOCK_SIZE: usize = 1024;
 
-pub fn zip<'files, Files>(files: Files) -> Vec<u8>
-where Files: 'files + IntoIterator<Item = (&'files [u8], &'files [u8])> {
-    let mut files: Vec<(&[u8], &[u8])> = files.into_iter().collect();
-    files.sort_by_cached_key(|(path, body)| {
-        (
-            // file named "mimetype" goes first, for the sake of package formats including EPUB and
-            // ODT.
-            path != b"mimetype",
-            // followed by any empty files, since they have no associated data and therefor weaker
-            // alignment requirements, so we want to pack them all together.
-            !body.is_empty(),
-            // files before directories
-            path.iter().filter(|&&b| b == b'/').count(),
-            // then lexicographically by path
-            *path,
-            // and only then by body
-            *body,
-        )
-    });
-    zip_with(&files, Vec::new(), b"")
+/// The order in which files are written to an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum FileOrder {
+    /// Files are sorted to pack them tightly: empty files first, then files
+    /// before directories, then lexicographically by path.
+    #[default]
+    Sorted,
+    /// Files are written in the order they were given.
+    Given,
+}
+
+/// How the body of a file is compressed in an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Compression {
+    /// The body is stored as-is (method 0).
+    Stored,
+    /// The body is compressed with DEFLATE (method 8).
+    Deflated,
+    /// The body is compressed with DEFLATE only if that makes it smaller,
+    /// and stored otherwise.
+    #[default]
+    Auto,
+}
+
+impl Compression {
+    /// Returns the compression method, and the body as it is to be written.
+    pub fn compress(self, body: &[u8], level: Level) -> (u16, Cow<'_, [u8]>) {
+        if !body.is_empty() && self != Stored {
+            let deflated = deflate_with(body, level);
+            if self == Deflated || deflated.len() < body.len() {
+                return (METHOD_DEFLATED, Cow::Owned(deflated));
+            }
+        }
+        (METHOD_STORED, Cow::Borrowed(body))
+    }
+}
+
+pub fn zip<'files, Files>(files: Files, opts: &ZipOptions) -> Result<Vec<u8>, Error>
+where Files: 'files + IntoIterator<Item = (&'files [u8], &'files FileEntry)> {
+    let mut files: Vec<(&[u8], &FileEntry)> = files.into_iter().collect();
+    sort(&mut files, opts);
+    zip_with(&files, opts, Vec::new(), b"")
+}
+
+/// Sorts `files` into the order they should be written to an archive with the
+/// given options.
+pub fn sort(files: &mut [(&[u8], &FileEntry)], opts: &ZipOptions) {
+    let is_mimetype = |path: &[u8]| opts.mimetype_first && path == b"mimetype";
+    match opts.order {
+        Sorted => files.sort_by_cached_key(|&(path, entry)| sort_key(path, entry, opts)),
+        // this is a stable sort, so everything else keeps its given order
+        Given => files.sort_by_key(|(path, _body)| !is_mimetype(path)),
+    }
+}
+
+/// The key that [`Sorted`] files are sorted by.
+pub fn sort_key<'a>(path: &'a [u8], entry: &'a FileEntry, opts: &ZipOptions) -> impl Ord + 'a {
+    let is_mimetype = opts.mimetype_first && path == b"mimetype";
+    (
+        // file named "mimetype" goes first, for the sake of package formats including
+        // EPUB and ODT.
+        !is_mimetype,
+        // followed by any empty files, since they have no associated data and therefor
+        // weaker alignment requirements, so we want to pack them all together.
+        !entry.body.is_empty(),
+        // files before directories
+        path.iter().filter(|&&b| b == b'/').count(),
+        // then lexicographically by path
+        path,
+        // and only then by body
+        entry.body.as_slice(),
+    )
 }
 
 /// Creates a zip file from files in the order given, appending to the `prefix`
 /// buffer `Vec` (which does not need to be empty), and ending with the
 /// given `suffix`,
-pub fn zip_with(files: &[(&[u8], &[u8])], prefix: Vec<u8>, suffix: &[u8]) -> Vec<u8> {
-    let mut output = prefix;
-
-    if suffix.find(b"PK\x05\x06").is_some() {
-        panic!("Zip file suffix must not contain zip file terminator signature PK\\x05\\x06");
-    }
-
-    let mut files_with_offsets = Vec::with_capacity(files.len());
-
-    for (name, body) in files.iter() {
-        let mut header = Vec::new();
-        // 0x0000..0x0004: local file header signature
-        header.extend_from_slice(b"PK\x03\x04");
-        // 0x0004..0x0006: version needed to extract
-        header.extend_from_slice(&1_0_u16.to_le_bytes());
-        // 0x0006..0x0008: general purpose bit flag
-        header.extend_from_slice(&[0x00; 2]);
-        // 0x0008..0x000A: compression method
-        header.extend_from_slice(&[0x00; 2]);
-        // 0x000A..0x000C: modification time
-        header.extend_from_slice(b"PK");
-        // 0x000C..0x000E: modification date
-        header.extend_from_slice(b"PK");
-        // 0x000E..0x0012: checksum
-        header.extend_from_slice(&crc32(body).to_le_bytes());
-        // 0x0012..0x0016: compressed size
-        header.extend_from_slice(
-            &u32::try_from(body.len())
-                .expect("file size larger than 4GiB")
-                .to_le_bytes(),
-        );
-        // 0x0016..0x001A: uncompressed size
-        header.extend_from_slice(
-            &u32::try_from(body.len())
-                .expect("file size larger than 4GiB")
-                .to_le_bytes(),
-        );
-        // 0x001A..0x001E: file name length
-        header.extend_from_slice(
-            &u16::try_from(name.len())
-                .expect("file name larger than 64KiB")
-                .to_le_bytes(),
-        );
-        // 0x001E..0x0022: extra fields length
-        header.extend_from_slice(&[0x00; 2]);
-        // 0x0022: file name, followed by extra fields (we have none)
-        header.extend_from_slice(name);
-        let range = if !body.is_empty() && name != b"mimetype" {
-            let header_range = write_aligned_pad_start(&mut output, &header, BLOCK_SIZE);
-            let body_range = write_aligned_pad_end(&mut output, body, BLOCK_SIZE);
-            header_range.start..body_range.end
+///
+/// ZIP64 extensions are used for any sizes, offsets and counts that don't fit
+/// in the original format, but only where they're needed.
+pub fn zip_with(
+    files: &[(&[u8], &FileEntry)],
+    opts: &ZipOptions,
+    prefix: Vec<u8>,
+    suffix: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let offset = prefix.len() as u64;
+    let mut writer = ZipWriter::with_offset(prefix, opts.clone(), offset);
+    for &(name, entry) in files {
+        writer.add(name, entry)?;
+    }
+    writer.finish(suffix)
+}
+
+/// Writes a zip archive incrementally, holding only its central directory in
+/// memory rather than the file bodies or output.
+///
+/// Files are written in the order they're added, and can be added whole with
+/// [`ZipWriter::add`], or started with [`ZipWriter::start_file`] and then
+/// streamed with [`Write`]. A streamed file's checksum and sizes aren't known
+/// until it ends, so they follow its body in a ZIP64 data descriptor rather
+/// than being in its local header. Streamed bodies are always aligned, and are
+/// deflated unless their compression is [`Stored`].
+pub struct ZipWriter<W: Write> {
+    output: W,
+    opts: ZipOptions,
+    /// The offset that the next byte written to `output` will have.
+    offset: u64,
+    entries: Vec<CentralEntry>,
+    /// The file being streamed, if any.
+    current: Option<StreamedFile>,
+}
+
+/// What the central directory records about an entry.
+struct CentralEntry {
+    name: Vec<u8>,
+    comment: Vec<u8>,
+    flags: u16,
+    method: u16,
+    mtime: i64,
+    crc: u32,
+    uncompressed_length: u64,
+    compressed_length: u64,
+    external_attributes: u32,
+    header_offset: u64,
+    /// Shared by the local and central headers, so that they agree.
+    version_needed: u16,
+}
+
+struct StreamedFile {
+    entry: CentralEntry,
+    crc: Crc32,
+    deflater: Option<Deflater>,
+    is_aligned: bool,
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(output: W, opts: ZipOptions) -> Self {
+        Self::with_offset(output, opts, 0)
+    }
+
+    /// Creates a writer for output that already has `offset` bytes written to
+    /// it, such as a prefix of other data, which the archive's offsets and
+    /// alignment include.
+    pub fn with_offset(output: W, opts: ZipOptions, offset: u64) -> Self {
+        Self {
+            output,
+            opts,
+            offset,
+            entries: Vec::new(),
+            current: None,
+        }
+    }
+
+    /// The offset that the next byte written to the output will have,
+    /// including any it was created with.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Writes `len` zero bytes, ending any file being streamed, so that the
+    /// next record starts later in the output, such as after bytes that will
+    /// be replaced with other data.
+    pub fn skip(&mut self, len: u64) -> Result<(), Error> {
+        self.finish_file()?;
+        self.write_padding(len)?;
+        Ok(())
+    }
+
+    /// Adds a file with its whole body, ending any file being streamed.
+    pub fn add(&mut self, name: &[u8], entry: &FileEntry) -> Result<(), Error> {
+        self.finish_file()?;
+        let (name, body, compression, external_attributes) = prepare(name, entry, &self.opts);
+        let (method, data) = compression.compress(body, self.opts.compression_level);
+        let mtime = entry.mtime.unwrap_or(self.opts.mtime);
+        let mut central = CentralEntry {
+            name: name.into_owned(),
+            comment: entry.comment.clone(),
+            flags: 0,
+            method,
+            mtime,
+            crc: crc32(body),
+            uncompressed_length: body.len() as u64,
+            compressed_length: data.len() as u64,
+            external_attributes,
+            header_offset: 0,
+            version_needed: 0,
+        };
+        let is_zip64 = central.uncompressed_length >= ZIP64_THRESHOLD
+            || central.compressed_length >= ZIP64_THRESHOLD;
+
+        let mut extra_fields = Vec::new();
+        if is_zip64 {
+            // ZIP64 extended information, which must include both sizes in a local header
+            extra_fields.extend_from_slice(&ZIP64_EXTRA_FIELD.to_le_bytes());
+            extra_fields.extend_from_slice(&16_u16.to_le_bytes());
+            extra_fields.extend_from_slice(&central.uncompressed_length.to_le_bytes());
+            extra_fields.extend_from_slice(&central.compressed_length.to_le_bytes());
+        }
+        if self.opts.extended_timestamps {
+            write_extended_timestamp(&mut extra_fields, mtime);
+        }
+        let header_length = local_header(&central, is_zip64, &extra_fields)?.len();
+
+        let is_aligned = !data.is_empty() && !self.is_mimetype(&central.name);
+        if is_aligned {
+            self.write_padding(pad_start_len(
+                self.offset,
+                header_length as u64,
+                self.alignment(),
+            ))?;
+        }
+        central.header_offset = self.offset;
+        // the central header also needs ZIP64 for a large offset
+        let needs_zip64 = is_zip64 || central.header_offset >= ZIP64_THRESHOLD;
+        central.version_needed = version_needed(needs_zip64, method);
+        self.write_raw(&local_header(&central, is_zip64, &extra_fields)?)?;
+        self.write_raw(&data)?;
+        if is_aligned {
+            self.write_padding(pad_end_len(self.offset, self.alignment()))?;
+        }
+        self.entries.push(central);
+        Ok(())
+    }
+
+    /// Starts a file whose body is then written with [`Write`], ending any
+    /// file already being streamed. The entry's own body, if any, is written
+    /// first.
+    pub fn start_file(&mut self, name: &[u8], entry: &FileEntry) -> Result<(), Error> {
+        self.finish_file()?;
+        let (name, body, compression, external_attributes) = prepare(name, entry, &self.opts);
+        let method = if compression == Stored {
+            METHOD_STORED
         } else {
-            let before = output.len();
-            output.extend_from_slice(&header);
-            output.extend_from_slice(body);
-            let after = output.len();
-            before..after
+            METHOD_DEFLATED
         };
-        files_with_offsets.push((*name, *body, range.start));
-    }
-
-    let mut central_directory = Vec::new();
-    for (name, body, header_offset) in files_with_offsets.iter() {
-        let name = name.to_vec();
-        let name_length = u16::try_from(name.len()).expect("file name larger than 64KiB");
-        let body_length = u32::try_from(body.len()).expect("file size larger than 4GiB");
-        let header_offset = u32::try_from(*header_offset).expect("archive larger than 4GiB");
-        let crc = crc32(body).to_le_bytes();
-        let mut header = Vec::new();
-        // 0x0000..0x0004: central file header signature
-        header.extend_from_slice(b"PK\x01\x02");
-        // 0x0004..0x0006: creator version and platform
-        header.extend_from_slice(&1_0_u16.to_le_bytes());
-        // 0x0006..0x0008: required version
-        header.extend_from_slice(&1_0_u16.to_le_bytes());
-        // 0x0008..0x000A: general purpose bit flag
-        header.extend_from_slice(&[0x00; 2]);
-        // 0x000A..0x000C: compression method
-        header.extend_from_slice(&[0x00; 2]);
-        // 0x000C..0x000E: modification time
-        header.extend_from_slice(b"PK");
-        // 0x000E..0x0010: modification date
-        header.extend_from_slice(b"PK");
-        // 0x0010..0x0014: checksum
-        header.extend_from_slice(&crc);
-        // 0x0014..0x0018: compressed size
-        header.extend_from_slice(&body_length.to_le_bytes());
-        // 0x0018..0x001C: uncompressed size
-        header.extend_from_slice(&body_length.to_le_bytes());
-        // 0x001C..0x001E: file name length
-        header.extend_from_slice(&name_length.to_le_bytes());
-        // 0x001E..0x0020: extra field length
-        header.extend_from_slice(&[0x00; 2]);
-        // 0x0020..0x0022: file comment length
-        header.extend_from_slice(&[0x00; 2]);
-        // 0x0022..0x0024: disk number
-        header.extend_from_slice(&[0x00; 2]);
-        // 0x0024..0x0026: internal file attributes
-        header.extend_from_slice(&[0x00; 2]);
-        // 0x0026..0x002A: external file attributes
-        header.extend_from_slice(&[0x00; 4]);
-        // 0x002A..0x002E: local file header offset from start of archive
-        header.extend_from_slice(&header_offset.to_le_bytes());
-        // 0x002E..: file name, followed by extra fields and comments (we have none)
-        header.extend_from_slice(&name);
-
-        central_directory.extend_from_slice(&header);
-    }
-
-    let archive_terminator = vec![0; 22 + suffix.len()];
-    central_directory.extend_from_slice(&archive_terminator);
-
-    let central_directory_range =
-        write_aligned_pad_start(&mut output, &central_directory, BLOCK_SIZE);
-
-    let final_len = output.len();
-    let mut archive_terminator = &mut output[final_len - archive_terminator.len()..];
-
-    let directory_offset =
-        u32::try_from(central_directory_range.start).expect("archive larger than 4GiB");
-    let directory_count = u16::try_from(files.len()).expect("more than 64Ki files");
-    let directory_length =
-        u32::try_from(central_directory_range.len() - archive_terminator.len()).unwrap();
-    let suffix_length = u16::try_from(suffix.len()).expect("comment longer than 64KiB");
-
-    // 0x0000..0x0004: archive terminator signature
-    archive_terminator.write_all(b"PK\x05\x06").unwrap();
-    // 0x0004..0x0006: disk number
-    archive_terminator.write_all(&[0x00; 2]).unwrap();
-    // 0x0006..0x0008: disk number with central directory
-    archive_terminator.write_all(&[0x00; 2]).unwrap();
-    // 0x0008..0x000A: directory entries on disk
-    archive_terminator
-        .write_all(&directory_count.to_le_bytes())
-        .unwrap();
-    // 0x000A..0x000C: directory entries total
-    archive_terminator
-        .write_all(&directory_count.to_le_bytes())
-        .unwrap();
-    // 0x000C..0x0010: central directory byte length
-    archive_terminator
-        .write_all(&directory_length.to_le_bytes())
-        .unwrap();
-    // 0x0010..0x0014: central directory offset from start of archive
-    archive_terminator
-        .write_all(&directory_offset.to_le_bytes())
-        .unwrap();
-    // 0x0014..: archive comment (suffix) length, then content
-    archive_terminator
-        .write_all(&suffix_length.to_le_bytes())
+        let mtime = entry.mtime.unwrap_or(self.opts.mtime);
+        let mut central = CentralEntry {
+            name: name.into_owned(),
+            comment: entry.comment.clone(),
+            flags: FLAG_DATA_DESCRIPTOR,
+            method,
+            mtime,
+            crc: 0,
+            uncompressed_length: 0,
+            compressed_length: 0,
+            external_attributes,
+            header_offset: 0,
+            version_needed: version_needed(true, method),
+        };
+
+        // ZIP64 extended information with both sizes zeroed, since we can't know
+        // yet whether the data descriptor will need 8-byte sizes
+        let mut extra_fields = Vec::new();
+        extra_fields.extend_from_slice(&ZIP64_EXTRA_FIELD.to_le_bytes());
+        extra_fields.extend_from_slice(&16_u16.to_le_bytes());
+        extra_fields.extend_from_slice(&[0x00; 16]);
+        if self.opts.extended_timestamps {
+            write_extended_timestamp(&mut extra_fields, mtime);
+        }
+        let header = local_header(&central, true, &extra_fields)?;
+
+        let is_aligned = !self.is_mimetype(&central.name);
+        if is_aligned {
+            self.write_padding(pad_start_len(
+                self.offset,
+                header.len() as u64,
+                self.alignment(),
+            ))?;
+        }
+        central.header_offset = self.offset;
+        self.write_raw(&header)?;
+        self.current = Some(StreamedFile {
+            entry: central,
+            crc: Crc32::new(),
+            deflater: (method == METHOD_DEFLATED)
+                .then(|| Deflater::new(self.opts.compression_level)),
+            is_aligned,
+        });
+        self.write_body(body)?;
+        Ok(())
+    }
+
+    /// Ends the archive with the given comment, and returns the output.
+    pub fn finish(mut self, comment: &[u8]) -> Result<W, Error> {
+        self.finish_file()?;
+        if comment.find(b"PK\x05\x06").is_some() {
+            return Err(Error::CommentContainsTerminator);
+        }
+        let comment_length = comment_length(comment)?;
+
+        let mut central_directory = Vec::new();
+        for entry in &self.entries {
+            central_directory
+                .extend_from_slice(&central_header(entry, self.opts.extended_timestamps)?);
+        }
+        let directory_count = self.entries.len() as u64;
+        let directory_length = central_directory.len() as u64;
+
+        // We need to know where the central directory will start to know whether
+        // we need the ZIP64 terminator, so we work it out as though we don't.
+        let terminator_length = 22 + comment.len() as u64;
+        let padding = pad_start_len(
+            self.offset,
+            directory_length + terminator_length,
+            self.alignment(),
+        );
+        let directory_offset = self.offset + padding;
+
+        let is_zip64 = directory_count >= u16::MAX.into()
+            || directory_length >= ZIP64_THRESHOLD
+            || directory_offset >= ZIP64_THRESHOLD;
+        let zip64_terminator_length = if is_zip64 { 56 + 20 } else { 0 };
+
+        self.write_padding(pad_start_len(
+            self.offset,
+            directory_length + zip64_terminator_length + terminator_length,
+            self.alignment(),
+        ))?;
+        let directory_offset = self.offset;
+        self.write_raw(&central_directory)?;
+
+        let mut archive_terminator = Vec::new();
+        if is_zip64 {
+            let zip64_terminator_offset = directory_offset + directory_length;
+
+            // 0x0000..0x0004: zip64 archive terminator signature
+            archive_terminator.extend_from_slice(b"PK\x06\x06");
+            // 0x0004..0x000C: size of the rest of this record
+            archive_terminator.extend_from_slice(&44_u64.to_le_bytes());
+            // 0x000C..0x000E: creator version and platform
+            archive_terminator.extend_from_slice(
+                &version_made_by(version_needed(true, METHOD_STORED)).to_le_bytes(),
+            );
+            // 0x000E..0x0010: required version
+            archive_terminator
+                .extend_from_slice(&version_needed(true, METHOD_STORED).to_le_bytes());
+            // 0x0010..0x0014: disk number
+            archive_terminator.extend_from_slice(&[0x00; 4]);
+            // 0x0014..0x0018: disk number with central directory
+            archive_terminator.extend_from_slice(&[0x00; 4]);
+            // 0x0018..0x0020: directory entries on disk
+            archive_terminator.extend_from_slice(&directory_count.to_le_bytes());
+            // 0x0020..0x0028: directory entries total
+            archive_terminator.extend_from_slice(&directory_count.to_le_bytes());
+            // 0x0028..0x0030: central directory byte length
+            archive_terminator.extend_from_slice(&directory_length.to_le_bytes());
+            // 0x0030..0x0038: central directory offset from start of archive
+            archive_terminator.extend_from_slice(&directory_offset.to_le_bytes());
+
+            // 0x0000..0x0004: zip64 archive terminator locator signature
+            archive_terminator.extend_from_slice(b"PK\x06\x07");
+            // 0x0004..0x0008: disk number with zip64 archive terminator
+            archive_terminator.extend_from_slice(&[0x00; 4]);
+            // 0x0008..0x0010: zip64 archive terminator offset from start of archive
+            archive_terminator.extend_from_slice(&zip64_terminator_offset.to_le_bytes());
+            // 0x0010..0x0014: total number of disks
+            archive_terminator.extend_from_slice(&1_u32.to_le_bytes());
+        }
+
+        // Any values that don't fit are saturated, indicating that they should be
+        // read from the zip64 archive terminator instead.
+        let directory_count = u16::try_from(directory_count).unwrap_or(u16::MAX);
+        let directory_length = saturating_u32(directory_length);
+        let directory_offset = saturating_u32(directory_offset);
+
+        // 0x0000..0x0004: archive terminator signature
+        archive_terminator.extend_from_slice(b"PK\x05\x06");
+        // 0x0004..0x0006: disk number
+        archive_terminator.extend_from_slice(&[0x00; 2]);
+        // 0x0006..0x0008: disk number with central directory
+        archive_terminator.extend_from_slice(&[0x00; 2]);
+        // 0x0008..0x000A: directory entries on disk
+        archive_terminator.extend_from_slice(&directory_count.to_le_bytes());
+        // 0x000A..0x000C: directory entries total
+        archive_terminator.extend_from_slice(&directory_count.to_le_bytes());
+        // 0x000C..0x0010: central directory byte length
+        archive_terminator.extend_from_slice(&directory_length.to_le_bytes());
+        // 0x0010..0x0014: central directory offset from start of archive
+        archive_terminator.extend_from_slice(&directory_offset.to_le_bytes());
+        // 0x0014..: archive comment length, then content
+        archive_terminator.extend_from_slice(&comment_length.to_le_bytes());
+        archive_terminator.extend_from_slice(comment);
+        self.write_raw(&archive_terminator)?;
+
+        self.output.flush()?;
+        Ok(self.output)
+    }
+
+    /// Ends the file being streamed, if any, writing its data descriptor.
+    fn finish_file(&mut self) -> Result<(), Error> {
+        let mut file = match self.current.take() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        if let Some(deflater) = file.deflater.take() {
+            let mut data = Vec::new();
+            deflater.finish(&mut data);
+            file.entry.compressed_length += data.len() as u64;
+            self.write_raw(&data)?;
+        }
+        file.entry.crc = file.crc.finalize();
+
+        let mut descriptor = Vec::new();
+        // 0x0000..0x0004: data descriptor signature
+        descriptor.extend_from_slice(b"PK\x07\x08");
+        // 0x0004..0x0008: checksum
+        descriptor.extend_from_slice(&file.entry.crc.to_le_bytes());
+        // 0x0008..0x0010: compressed size, 8 bytes since the local header has ZIP64
+        // extended information
+        descriptor.extend_from_slice(&file.entry.compressed_length.to_le_bytes());
+        // 0x0010..0x0018: uncompressed size
+        descriptor.extend_from_slice(&file.entry.uncompressed_length.to_le_bytes());
+        self.write_raw(&descriptor)?;
+
+        if file.is_aligned {
+            self.write_padding(pad_end_len(self.offset, self.alignment()))?;
+        }
+        self.entries.push(file.entry);
+        Ok(())
+    }
+
+    fn write_body(&mut self, data: &[u8]) -> io::Result<()> {
+        let file = self.current.as_mut().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no file has been started")
+        })?;
+        file.crc.update(data);
+        file.entry.uncompressed_length += data.len() as u64;
+        let data = match &mut file.deflater {
+            Some(deflater) => {
+                let mut compressed = Vec::new();
+                deflater.write(data, &mut compressed);
+                Cow::Owned(compressed)
+            },
+            None => Cow::Borrowed(data),
+        };
+        file.entry.compressed_length += data.len() as u64;
+        self.output.write_all(&data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.write_all(bytes)?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn write_padding(&mut self, len: u64) -> io::Result<()> {
+        io::copy(&mut io::repeat(0x00).take(len), &mut self.output)?;
+        self.offset += len;
+        Ok(())
+    }
+
+    fn alignment(&self) -> u64 {
+        self.opts.alignment.max(1) as u64
+    }
+
+    fn is_mimetype(&self, name: &[u8]) -> bool {
+        self.opts.mimetype_first && name == b"mimetype"
+    }
+}
+
+impl<W: Write> Write for ZipWriter<W> {
+    /// Writes to the body of the file being streamed. Deflated bodies are
+    /// compressed in chunks, so not all of the data may have reached the
+    /// output until the file ends.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_body(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Returns an entry's name, body, compression and external file attributes,
+/// with directories and symbolic links in the form readers expect.
+fn prepare<'entry>(
+    name: &'entry [u8],
+    entry: &'entry FileEntry,
+    opts: &ZipOptions,
+) -> (Cow<'entry, [u8]>, &'entry [u8], Compression, u32) {
+    let (name, body, compression, file_type) = match entry.kind {
+        // package formats need the mimetype stored, so it can be read at a fixed offset
+        FileKind::File if opts.mimetype_first && name == b"mimetype" =>
+            (Cow::Borrowed(name), entry.body.as_slice(), Stored, S_IFREG),
+        FileKind::File => (
+            Cow::Borrowed(name),
+            entry.body.as_slice(),
+            entry.compression.unwrap_or(opts.compression),
+            S_IFREG,
+        ),
+        // directories have no body, and need a trailing slash for readers that
+        // don't check their attributes
+        FileKind::Directory => {
+            let name = if name.ends_with(b"/") {
+                Cow::Borrowed(name)
+            } else {
+                Cow::Owned([name, b"/"].concat())
+            };
+            (name, &[][..], Stored, S_IFDIR)
+        },
+        // link targets are stored so that readers don't need to inflate them
+        FileKind::Symlink => (Cow::Borrowed(name), entry.body.as_slice(), Stored, S_IFLNK),
+    };
+    let mode = entry.mode.unwrap_or(match entry.kind {
+        FileKind::File => 0o644,
+        FileKind::Directory => 0o755,
+        FileKind::Symlink => 0o777,
+    });
+    let mut external_attributes = (file_type | mode & 0o7777) << 16;
+    if entry.kind == FileKind::Directory {
+        external_attributes |= MSDOS_DIRECTORY;
+    }
+    (name, body, compression, external_attributes)
+}
+
+/// Writes a local file header. With `is_zip64`, `extra_fields` must start with
+/// ZIP64 extended information holding both sizes.
+fn local_header(
+    entry: &CentralEntry,
+    is_zip64: bool,
+    extra_fields: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let (compressed_length, uncompressed_length) = if is_zip64 {
+        (u32::MAX, u32::MAX)
+    } else {
+        (
+            saturating_u32(entry.compressed_length),
+            saturating_u32(entry.uncompressed_length),
+        )
+    };
+    let (time, date) = dos_time_date(entry.mtime);
+    let mut header = Vec::new();
+    // 0x0000..0x0004: local file header signature
+    header.extend_from_slice(b"PK\x03\x04");
+    // 0x0004..0x0006: version needed to extract
+    header.extend_from_slice(&entry.version_needed.to_le_bytes());
+    // 0x0006..0x0008: general purpose bit flag
+    header.extend_from_slice(&entry.flags.to_le_bytes());
+    // 0x0008..0x000A: compression method
+    header.extend_from_slice(&entry.method.to_le_bytes());
+    // 0x000A..0x000C: modification time
+    header.extend_from_slice(&time.to_le_bytes());
+    // 0x000C..0x000E: modification date
+    header.extend_from_slice(&date.to_le_bytes());
+    // 0x000E..0x0012: checksum
+    header.extend_from_slice(&entry.crc.to_le_bytes());
+    // 0x0012..0x0016: compressed size
+    header.extend_from_slice(&compressed_length.to_le_bytes());
+    // 0x0016..0x001A: uncompressed size
+    header.extend_from_slice(&uncompressed_length.to_le_bytes());
+    // 0x001A..0x001E: file name length
+    header.extend_from_slice(&name_length(&entry.name)?.to_le_bytes());
+    // 0x001E..0x0022: extra fields length
+    header.extend_from_slice(&(extra_fields.len() as u16).to_le_bytes());
+    // 0x0022: file name, followed by extra fields
+    header.extend_from_slice(&entry.name);
+    header.extend_from_slice(extra_fields);
+    Ok(header)
+}
+
+fn central_header(entry: &CentralEntry, extended_timestamps: bool) -> Result<Vec<u8>, Error> {
+    let (time, date) = dos_time_date(entry.mtime);
+
+    // ZIP64 extended information, which only includes the values that
+    // don't fit in the header itself
+    let mut extra_fields = Vec::new();
+    let mut zip64_values = Vec::new();
+    if entry.uncompressed_length >= ZIP64_THRESHOLD {
+        zip64_values.extend_from_slice(&entry.uncompressed_length.to_le_bytes());
+    }
+    if entry.compressed_length >= ZIP64_THRESHOLD {
+        zip64_values.extend_from_slice(&entry.compressed_length.to_le_bytes());
+    }
+    if entry.header_offset >= ZIP64_THRESHOLD {
+        zip64_values.extend_from_slice(&entry.header_offset.to_le_bytes());
+    }
+    if !zip64_values.is_empty() {
+        extra_fields.extend_from_slice(&ZIP64_EXTRA_FIELD.to_le_bytes());
+        extra_fields.extend_from_slice(&(zip64_values.len() as u16).to_le_bytes());
+        extra_fields.extend_from_slice(&zip64_values);
+    }
+    if extended_timestamps {
+        write_extended_timestamp(&mut extra_fields, entry.mtime);
+    }
+
+    let mut header = Vec::new();
+    // 0x0000..0x0004: central file header signature
+    header.extend_from_slice(b"PK\x01\x02");
+    // 0x0004..0x0006: creator version and platform
+    header.extend_from_slice(&version_made_by(entry.version_needed).to_le_bytes());
+    // 0x0006..0x0008: required version
+    header.extend_from_slice(&entry.version_needed.to_le_bytes());
+    // 0x0008..0x000A: general purpose bit flag
+    header.extend_from_slice(&entry.flags.to_le_bytes());
+    // 0x000A..0x000C: compression method
+    header.extend_from_slice(&entry.method.to_le_bytes());
+    // 0x000C..0x000E: modification time
+    header.extend_from_slice(&time.to_le_bytes());
+    // 0x000E..0x0010: modification date
+    header.extend_from_slice(&date.to_le_bytes());
+    // 0x0010..0x0014: checksum
+    header.extend_from_slice(&entry.crc.to_le_bytes());
+    // 0x0014..0x0018: compressed size
+    header.extend_from_slice(&saturating_u32(entry.compressed_length).to_le_bytes());
+    // 0x0018..0x001C: uncompressed size
+    header.extend_from_slice(&saturating_u32(entry.uncompressed_length).to_le_bytes());
+    // 0x001C..0x001E: file name length
+    header.extend_from_slice(&name_length(&entry.name)?.to_le_bytes());
+    // 0x001E..0x0020: extra field length
+    header.extend_from_slice(&(extra_fields.len() as u16).to_le_bytes());
+    // 0x0020..0x0022: file comment length
+    header.extend_from_slice(&comment_length(&entry.comment)?.to_le_bytes());
+    // 0x0022..0x0024: disk number
+    header.extend_from_slice(&[0x00; 2]);
+    // 0x0024..0x0026: internal file attributes
+    header.extend_from_slice(&[0x00; 2]);
+    // 0x0026..0x002A: external file attributes
+    header.extend_from_slice(&entry.external_attributes.to_le_bytes());
+    // 0x002A..0x002E: local file header offset from start of archive
+    header.extend_from_slice(&saturating_u32(entry.header_offset).to_le_bytes());
+    // 0x002E..: file name, followed by extra fields and comment
+    header.extend_from_slice(&entry.name);
+    header.extend_from_slice(&extra_fields);
+    header.extend_from_slice(&entry.comment);
+    Ok(header)
+}
+
+/// Sizes and offsets at or above this value are stored in ZIP64 extra fields.
+const ZIP64_THRESHOLD: u64 = u32::MAX as u64;
+
+/// Header ID of the ZIP64 extended information extra field.
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+
+/// Header ID of the extended timestamp extra field.
+const EXTENDED_TIMESTAMP_EXTRA_FIELD: u16 = 0x5455;
+
+/// The earliest time that MS-DOS fields can represent, 1980-01-01 00:00:00,
+/// in seconds since the Unix epoch.
+pub const DOS_EPOCH: i64 = 315_532_800;
+
+/// The latest time that MS-DOS fields can represent, 2107-12-31 23:59:58, in
+/// seconds since the Unix epoch.
+pub const DOS_MAX: i64 = days_from_civil(2107, 12, 31) * 86_400 + 86_398;
+
+/// The modification time used for files without their own: the value of the
+/// `SOURCE_DATE_EPOCH` environment variable if it's set, for reproducible
+/// builds, or else [`DOS_EPOCH`].
+pub fn default_mtime() -> i64 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse().ok())
+        .unwrap_or(DOS_EPOCH)
+}
+
+/// Converts a time in seconds since the Unix epoch to MS-DOS time and date
+/// fields in UTC, clamped to the range they can represent.
+pub fn dos_time_date(mtime: i64) -> (u16, u16) {
+    let mtime = mtime.clamp(DOS_EPOCH, DOS_MAX);
+    let (year, month, day) = civil_from_days(mtime.div_euclid(86_400));
+    let seconds = mtime.rem_euclid(86_400);
+
+    let time = (seconds / 3600) << 11 | (seconds / 60 % 60) << 5 | (seconds % 60 / 2);
+    let date = (year - 1980) << 9 | month << 5 | day;
+    (time as u16, date as u16)
+}
+
+/// Converts MS-DOS time and date fields in UTC to a time in seconds since the
+/// Unix epoch.
+pub fn unix_time(time: u16, date: u16) -> i64 {
+    let (time, date) = (i64::from(time), i64::from(date));
+    let year = (date >> 9) + 1980;
+    let month = (date >> 5 & 0x0F).clamp(1, 12);
+    let day = (date & 0x1F).max(1);
+    let seconds = (time >> 11) * 3600 + (time >> 5 & 0x3F) * 60 + (time & 0x1F) * 2;
+    days_from_civil(year, month, day) * 86_400 + seconds
+}
+
+/// Returns the year, month and day of a number of days since the Unix epoch,
+/// in the proleptic Gregorian calendar.
+const fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // months are counted from March, so that leap days come last
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// Returns the number of days since the Unix epoch of a date in the proleptic
+/// Gregorian calendar.
+const fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Writes an extended timestamp extra field holding only the modification
+/// time, which is all the central directory's copy may hold.
+fn write_extended_timestamp(extra_fields: &mut Vec<u8>, mtime: i64) {
+    let mtime = mtime.clamp(i32::MIN.into(), i32::MAX.into()) as i32;
+    extra_fields.extend_from_slice(&EXTENDED_TIMESTAMP_EXTRA_FIELD.to_le_bytes());
+    extra_fields.extend_from_slice(&5_u16.to_le_bytes());
+    // flags: modification time present
+    extra_fields.push(0b_0000_0001);
+    extra_fields.extend_from_slice(&mtime.to_le_bytes());
+}
+
+/// Compression method of stored entries.
+const METHOD_STORED: u16 = 0;
+
+/// Compression method of deflated entries.
+const METHOD_DEFLATED: u16 = 8;
+
+/// General purpose flag bit set for encrypted entries.
+const FLAG_ENCRYPTED: u16 = 1 << 0;
+
+/// General purpose flag bit set for entries whose checksum and sizes follow
+/// their body, rather than being in their local header.
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+
+/// Host system in the upper byte of an entry's creator version, which
+/// determines how its external file attributes are interpreted.
+const HOST_UNIX: u16 = 3;
+
+/// Unix file type bits of regular files, directories and symbolic links,
+/// which are stored with the permission bits in the upper 16 bits of an
+/// entry's external file attributes.
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+/// MS-DOS directory attribute, in the lower byte of an entry's external
+/// file attributes.
+const MSDOS_DIRECTORY: u32 = 0x10;
+
+/// The version of the zip format required to extract an entry.
+fn version_needed(is_zip64: bool, method: u16) -> u16 {
+    if is_zip64 {
+        4_5
+    } else if method == METHOD_DEFLATED {
+        2_0
+    } else {
+        1_0
+    }
+}
+
+/// The version and platform that an entry was created with, which is always
+/// Unix so that readers apply the mode in the external file attributes.
+fn version_made_by(version_needed: u16) -> u16 {
+    HOST_UNIX << 8 | version_needed
+}
+
+fn saturating_u32(value: u64) -> u32 {
+    u32::try_from(value).unwrap_or(u32::MAX)
+}
+
+fn comment_length(comment: &[u8]) -> Result<u16, Error> {
+    u16::try_from(comment.len()).map_err(|_| Error::CommentTooLong { len: comment.len() })
+}
+
+fn name_length(name: &[u8]) -> Result<u16, Error> {
+    u16::try_from(name.len()).map_err(|_| Error::NameTooLong {
+        name: name.into(),
+        len: name.len(),
+    })
+}
+
+/// A zip archive read from a buffer, which may have other data prepended to
+/// it, such as the start of a PNG file.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Archive<'data> {
+    data: &'data [u8],
+    /// The offset in `data` that offsets in the archive are relative to.
+    base: usize,
+    entries: Vec<Entry<'data>>,
+    comment: &'data [u8],
+}
+
+/// An entry in a zip archive, as described by its central directory header.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Entry<'data> {
+    pub name: &'data [u8],
+    pub comment: &'data [u8],
+    /// The central directory header's extra fields.
+    pub extra_fields: &'data [u8],
+    pub version_made_by: u16,
+    pub version_needed: u16,
+    pub flags: u16,
+    pub method: u16,
+    /// The modification time, in MS-DOS format.
+    pub modified_time: u16,
+    /// The modification date, in MS-DOS format.
+    pub modified_date: u16,
+    pub crc: u32,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    pub internal_attributes: u16,
+    pub external_attributes: u32,
+    /// The offset of the local file header in the buffer the archive was
+    /// read from.
+    pub header_offset: usize,
+    /// The body as stored in the archive, which may be compressed.
+    pub data: &'data [u8],
+}
+
+impl<'data> Archive<'data> {
+    /// Reads the archive at the end of `data`, checking that its central
+    /// directory and local file headers are consistent.
+    pub fn new(data: &'data [u8]) -> Result<Self, Error> {
+        let terminator = find_terminator(data)?;
+
+        // 0x0004..0x0006: disk number, and 0x0006..0x0008: disk number with central
+        // directory
+        if u16_at(data, terminator + 0x04) != Some(0) || u16_at(data, terminator + 0x06) != Some(0)
+        {
+            return Err(Error::UnsupportedZip {
+                reason: "multi-disk archives",
+            });
+        }
+        let mut count = u64::from(u16_at(data, terminator + 0x0A).unwrap());
+        let mut directory_length = u64::from(u32_at(data, terminator + 0x0C).unwrap());
+        let mut directory_offset = u64::from(u32_at(data, terminator + 0x10).unwrap());
+        let comment_length = usize::from(u16_at(data, terminator + 0x14).unwrap());
+        let comment = &data[terminator + 22..][..comment_length];
+        let mut directory_end = terminator;
+
+        if terminator >= 20 && data[terminator - 20..].starts_with(b"PK\x06\x07") {
+            let locator = terminator - 20;
+            let stated = u64_at(data, locator + 0x08).unwrap();
+            // The record normally immediately precedes its locator, which lets us
+            // find it even if data has been prepended to the archive.
+            let record = [locator.checked_sub(56), usize::try_from(stated).ok()]
+                .into_iter()
+                .flatten()
+                .find(|&record| {
+                    matches!(data.get(record..locator), Some(r) if r.starts_with(b"PK\x06\x06"))
+                })
+                .ok_or_else(|| {
+                    invalid_zip(
+                        locator,
+                        "zip64 terminator locator doesn't point to a record",
+                    )
+                })?;
+            let field = |offset| {
+                u64_at(data, record + offset)
+                    .ok_or_else(|| invalid_zip(record, "truncated zip64 terminator"))
+            };
+            count = field(0x20)?;
+            directory_length = field(0x28)?;
+            directory_offset = field(0x30)?;
+            directory_end = record;
+        }
+
+        let directory_start = usize::try_from(directory_length)
+            .ok()
+            .and_then(|length| directory_end.checked_sub(length))
+            .ok_or_else(|| {
+                invalid_zip(terminator, "central directory is longer than the archive")
+            })?;
+        let base = usize::try_from(directory_offset)
+            .ok()
+            .and_then(|offset| directory_start.checked_sub(offset))
+            .ok_or_else(|| {
+                invalid_zip(terminator, "central directory offset is past its position")
+            })?;
+
+        let mut entries = Vec::new();
+        let mut offset = directory_start;
+        for _ in 0..count {
+            let (entry, next) = read_entry(data, base, offset, directory_end)?;
+            entries.push(entry);
+            offset = next;
+        }
+        if offset != directory_end {
+            return Err(invalid_zip(
+                offset,
+                "central directory length doesn't match its entries",
+            ));
+        }
+
+        Ok(Self {
+            data,
+            base,
+            entries,
+            comment,
+        })
+    }
+
+    /// The entries in the archive, in central directory order.
+    pub fn entries(&self) -> &[Entry<'data>] {
+        &self.entries
+    }
+
+    /// Returns the first entry with the given name.
+    pub fn get(&self, name: &[u8]) -> Option<&Entry<'data>> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// The archive comment.
+    pub fn comment(&self) -> &'data [u8] {
+        self.comment
+    }
+
+    /// Any data prepended to the archive, which offsets within it don't count.
+    /// This is empty for archives like those written by [`zip_with`] with a
+    /// prefix, whose offsets are from the start of the buffer.
+    pub fn prefix(&self) -> &'data [u8] {
+        &self.data[..self.base]
+    }
+
+    /// Decompresses every entry, checking its size and checksum.
+    pub fn verify(&self) -> Result<(), Error> {
+        for entry in &self.entries {
+            entry.body()?;
+        }
+        Ok(())
+    }
+}
+
+impl<'data> Entry<'data> {
+    /// The modification time in seconds since the Unix epoch, from the
+    /// extended timestamp extra field if there is one, or else from the
+    /// MS-DOS fields, read as UTC.
+    pub fn mtime(&self) -> i64 {
+        match find_extra_field(self.extra_fields, EXTENDED_TIMESTAMP_EXTRA_FIELD) {
+            Some(&[flags, a, b, c, d, ..]) if flags & 1 != 0 =>
+                i32::from_le_bytes([a, b, c, d]).into(),
+            _ => unix_time(self.modified_time, self.modified_date),
+        }
+    }
+
+    /// The Unix mode, including the file type bits, if the entry was created
+    /// on a Unix host and has one.
+    pub fn unix_mode(&self) -> Option<u32> {
+        let mode = self.external_attributes >> 16;
+        (self.version_made_by >> 8 == HOST_UNIX && mode != 0).then_some(mode)
+    }
+
+    /// Whether this is a directory, which by convention has a name ending
+    /// with a slash.
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with(b"/")
+    }
+
+    /// Whether this is a symbolic link, whose body is its target.
+    pub fn is_symlink(&self) -> bool {
+        matches!(self.unix_mode(), Some(mode) if mode & S_IFMT == S_IFLNK)
+    }
+
+    /// Decompresses the body, checking its size and checksum. Decompression
+    /// stops as soon as the output exceeds the size given in the header.
+    pub fn body(&self) -> Result<Cow<'data, [u8]>, Error> {
+        if self.flags & FLAG_ENCRYPTED != 0 {
+            return Err(Error::UnsupportedZip {
+                reason: "encrypted entries",
+            });
+        }
+        let size = usize::try_from(self.uncompressed_size).unwrap_or(usize::MAX);
+        let body = match self.method {
+            METHOD_STORED => Cow::Borrowed(self.data),
+            METHOD_DEFLATED => Cow::Owned(inflate_with_limit(self.data, size)?),
+            method => return Err(Error::UnsupportedCompression { method }),
+        };
+        if body.len() != size {
+            return Err(invalid_zip(
+                self.header_offset,
+                "entry's size doesn't match its header",
+            ));
+        }
+        let computed = crc32(&body);
+        if computed != self.crc {
+            return Err(Error::ChecksumMismatch {
+                checksum: "CRC-32",
+                stored: self.crc,
+                computed,
+            });
+        }
+        Ok(body)
+    }
+}
+
+/// Finds the archive terminator, searching backwards from the end of `data`
+/// past the largest possible comment.
+fn find_terminator(data: &[u8]) -> Result<usize, Error> {
+    let earliest = data.len().saturating_sub(22 + usize::from(u16::MAX));
+    // a terminator closer than 22 bytes to the end is truncated
+    let candidates = || {
+        (earliest..=data.len().saturating_sub(22))
+            .rev()
+            .filter(|&offset| offset + 22 <= data.len())
+            .filter(|&offset| data[offset..].starts_with(b"PK\x05\x06"))
+    };
+    let comment_end =
+        |offset: usize| offset + 22 + usize::from(u16_at(data, offset + 0x14).unwrap());
+
+    // Prefer a terminator whose comment reaches exactly to the end, but accept
+    // one followed by trailing garbage.
+    candidates()
+        .find(|&offset| comment_end(offset) == data.len())
+        .or_else(|| candidates().find(|&offset| comment_end(offset) <= data.len()))
+        .ok_or_else(|| invalid_zip(data.len(), "archive terminator not found"))
+}
+
+/// Reads the central directory header at `offset`, and the local file header
+/// it refers to. Returns the entry and the offset of the next header.
+fn read_entry(
+    data: &[u8],
+    base: usize,
+    offset: usize,
+    directory_end: usize,
+) -> Result<(Entry, usize), Error> {
+    let header = &data[..directory_end];
+    if !header[offset..].starts_with(b"PK\x01\x02") || offset + 46 > directory_end {
+        return Err(invalid_zip(offset, "expected central directory header"));
+    }
+    let name_length = usize::from(u16_at(header, offset + 0x1C).unwrap());
+    let extra_length = usize::from(u16_at(header, offset + 0x1E).unwrap());
+    let comment_length = usize::from(u16_at(header, offset + 0x20).unwrap());
+    let name_start = offset + 46;
+    let next = name_start + name_length + extra_length + comment_length;
+    if next > directory_end {
+        return Err(invalid_zip(
+            offset,
+            "central directory header overruns directory",
+        ));
+    }
+    let name = &header[name_start..][..name_length];
+    let extra_fields = &header[name_start + name_length..][..extra_length];
+    let comment = &header[name_start + name_length + extra_length..next];
+
+    let mut compressed_size = u64::from(u32_at(header, offset + 0x14).unwrap());
+    let mut uncompressed_size = u64::from(u32_at(header, offset + 0x18).unwrap());
+    let mut header_offset = u64::from(u32_at(header, offset + 0x2A).unwrap());
+    if let Some(mut zip64) = find_extra_field(extra_fields, ZIP64_EXTRA_FIELD) {
+        // Only the values that are saturated in the header are present, in this
+        // order.
+        for value in [
+            &mut uncompressed_size,
+            &mut compressed_size,
+            &mut header_offset,
+        ] {
+            if *value == ZIP64_THRESHOLD {
+                *value = u64_at(zip64, 0)
+                    .ok_or_else(|| invalid_zip(offset, "truncated zip64 extra field"))?;
+                zip64 = &zip64[8..];
+            }
+        }
+    }
+
+    let mut entry = Entry {
+        name,
+        comment,
+        extra_fields,
+        version_made_by: u16_at(header, offset + 0x04).unwrap(),
+        version_needed: u16_at(header, offset + 0x06).unwrap(),
+        flags: u16_at(header, offset + 0x08).unwrap(),
+        method: u16_at(header, offset + 0x0A).unwrap(),
+        modified_time: u16_at(header, offset + 0x0C).unwrap(),
+        modified_date: u16_at(header, offset + 0x0E).unwrap(),
+        crc: u32_at(header, offset + 0x10).unwrap(),
+        compressed_size,
+        uncompressed_size,
+        internal_attributes: u16_at(header, offset + 0x24).unwrap(),
+        external_attributes: u32_at(header, offset + 0x26).unwrap(),
+        header_offset: 0,
+        data: &[],
+    };
+
+    let local = usize::try_from(header_offset)
+        .ok()
+        .and_then(|header_offset| base.checked_add(header_offset))
+        .filter(|&local| matches!(data.get(local..), Some(l) if l.starts_with(b"PK\x03\x04")))
+        .ok_or_else(|| invalid_zip(offset, "local file header not found"))?;
+    let truncated = || invalid_zip(local, "truncated local file header");
+    let local_name_length = usize::from(u16_at(data, local + 0x1A).ok_or_else(truncated)?);
+    let local_extra_length = usize::from(u16_at(data, local + 0x1C).ok_or_else(truncated)?);
+    let local_name = data
+        .get(local + 30..local + 30 + local_name_length)
+        .ok_or_else(truncated)?;
+    if local_name != name {
+        return Err(invalid_zip(local, "local file header's name doesn't match"));
+    }
+    if u16_at(data, local + 0x08) != Some(entry.method) {
+        return Err(invalid_zip(
+            local,
+            "local file header's method doesn't match",
+        ));
+    }
+    // Entries written with a data descriptor may have zeroes here instead.
+    if entry.flags & FLAG_DATA_DESCRIPTOR == 0 && u32_at(data, local + 0x0E) != Some(entry.crc) {
+        return Err(invalid_zip(
+            local,
+            "local file header's checksum doesn't match",
+        ));
+    }
+
+    let body_start = local + 30 + local_name_length + local_extra_length;
+    let body = usize::try_from(compressed_size)
+        .ok()
+        .and_then(|size| data.get(body_start..)?.get(..size))
+        .ok_or_else(|| invalid_zip(local, "entry body extends past the end of the data"))?;
+    entry.header_offset = local;
+    entry.data = body;
+
+    Ok((entry, next))
+}
+
+/// Finds the data of the first extra field with the given header ID.
+fn find_extra_field(mut extra_fields: &[u8], id: u16) -> Option<&[u8]> {
+    while extra_fields.len() >= 4 {
+        let length = usize::from(u16_at(extra_fields, 2).unwrap());
+        let data = extra_fields.get(4..4 + length)?;
+        if u16_at(extra_fields, 0) == Some(id) {
+            return Some(data);
+        }
+        extra_fields = &extra_fields[4 + length..];
+    }
+    None
+}
+
+fn invalid_zip(offset: usize, reason: &'static str) -> Error {
+    Error::InvalidZip { offset, reason }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().unwrap(),
+    ))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().unwrap(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dos_times_and_dates() {
+        for (mtime, time, date) in [
+            // the first and last times MS-DOS fields can represent
+            (DOS_EPOCH, 0x0000, 0x0021),
+            (DOS_MAX, 0xBF7D, 0xFF9F),
+            // 2000-02-29 12:34:56 and 2024-02-29 23:59:58
+            (951_827_696, 0x645C, 0x285D),
+            (1_709_251_198, 0xBF7D, 0x585D),
+            // 2100-02-28 and 2100-03-01, since 2100 isn't a leap year
+            (4_107_456_000, 0x0000, 0xF05C),
+            (4_107_542_400, 0x0000, 0xF061),
+        ] {
+            assert_eq!(dos_time_date(mtime), (time, date), "{mtime}");
+            assert_eq!(unix_time(time, date), mtime, "{mtime}");
+        }
+        // two-second precision
+        assert_eq!(dos_time_date(951_827_697), (0x645C, 0x285D));
+        // out of range times are clamped
+        assert_eq!(dos_time_date(DOS_EPOCH - 1), dos_time_date(DOS_EPOCH));
+        assert_eq!(dos_time_date(0), dos_time_date(DOS_EPOCH));
+        assert_eq!(dos_time_date(DOS_MAX + 2), dos_time_date(DOS_MAX));
+        assert_eq!(DOS_MAX, 4_354_819_198);
+    }
+
+    #[test]
+    fn extended_timestamps() {
+        let opts = ZipOptions {
+            extended_timestamps: true,
+            ..ZipOptions::default()
+        };
+        let mtimes = [0, -86_400, DOS_MAX + 2, i64::from(i32::MAX) + 1];
+        let entries: Vec<(Vec<u8>, FileEntry)> = mtimes
+            .iter()
+            .enumerate()
+            .map(|(index, &mtime)| {
+                (vec![b'a' + index as u8], FileEntry {
+                    body: b"body".to_vec(),
+                    mtime: Some(mtime),
+                    ..FileEntry::default()
+                })
+            })
+            .collect();
+        let archive = zip(
+            entries.iter().map(|(name, entry)| (&name[..], entry)),
+            &opts,
+        )
         .unwrap();
-    archive_terminator.write_all(suffix).unwrap();
-    output
+        let archive = Archive::new(&archive).unwrap();
+
+        for (entry, &mtime) in archive.entries().iter().zip(&mtimes) {
+            // the MS-DOS fields are clamped, but the extended timestamp isn't
+            // unless it doesn't fit in 32 bits
+            let clamped = mtime.clamp(i32::MIN.into(), i32::MAX.into());
+            assert_eq!(entry.mtime(), clamped);
+            assert_eq!(
+                (entry.modified_time, entry.modified_date),
+                dos_time_date(mtime)
+            );
+            let mut field = vec![0x55, 0x54, 0x05, 0x00, 0x01];
+            field.extend_from_slice(&(clamped as i32).to_le_bytes());
+            assert_eq!(entry.extra_fields, field);
+        }
+    }
+
+    #[test]
+    fn truncated_terminators() {
+        assert!(Archive::new(b"PK\x05\x06").is_err());
+        let mut archive = zip([], &ZipOptions::default()).unwrap();
+        archive.extend_from_slice(b"PK\x05\x06\x00\x00");
+        let parsed = Archive::new(&archive).unwrap();
+        assert!(parsed.entries().is_empty());
+        archive.truncate(21);
+        assert!(Archive::new(&archive).is_err());
+    }
 }
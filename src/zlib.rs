@@ -1 +1,376 @@
+//! zlib ([RFC 1950](https://www.rfc-editor.org/rfc/rfc1950)) streams, which wrap
+//! a DEFLATE stream with a header and an Adler-32 checksum trailer.
 
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::checksums::adler32;
+use crate::checksums::Adler32;
+use crate::deflate;
+use crate::deflate::write_deflated_with_dictionary;
+use crate::deflate::Inflater;
+use crate::deflate::Level;
+use crate::Error;
+
+/// Compression method 8 (DEFLATE), with a 32KiB window.
+const CMF: u8 = 0x78;
+
+/// The compression level recorded in a zlib header. It's only informational,
+/// and doesn't affect decoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[repr(u8)]
+pub enum FLevel {
+    Fastest = 0,
+    Fast = 1,
+    #[default]
+    Default = 2,
+    Maximum = 3,
+}
+
+impl From<Level> for FLevel {
+    /// Maps levels the same way zlib does.
+    fn from(level: Level) -> Self {
+        match level.u8() {
+            0..=1 => FLevel::Fastest,
+            2..=5 => FLevel::Fast,
+            6 => FLevel::Default,
+            _ => FLevel::Maximum,
+        }
+    }
+}
+
+/// The fields of a zlib header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Header {
+    /// The size of the window the stream was compressed with, from 256 bytes
+    /// to 32KiB.
+    pub window_size: usize,
+    pub level: FLevel,
+    /// The Adler-32 checksum of the preset dictionary the stream was
+    /// compressed with, if any.
+    pub dictionary_id: Option<u32>,
+}
+
+impl Header {
+    /// Parses the header at the start of `data`, returning it along with its
+    /// length.
+    pub fn read(data: &[u8]) -> Result<(Header, usize), Error> {
+        let invalid = |reason| Error::InvalidZlib { reason };
+
+        let [cmf, flg, ..] = *data else {
+            return Err(invalid("truncated header"));
+        };
+        if (u16::from(cmf) << 8 | u16::from(flg)) % 31 != 0 {
+            return Err(invalid("header check bits don't match"));
+        }
+        if cmf & 0x0F != 8 {
+            return Err(invalid("compression method isn't DEFLATE"));
+        }
+        let window_bits = (cmf >> 4) + 8;
+        if window_bits > 15 {
+            return Err(invalid("window size is larger than 32KiB"));
+        }
+        let level = match flg >> 6 {
+            0 => FLevel::Fastest,
+            1 => FLevel::Fast,
+            2 => FLevel::Default,
+            _ => FLevel::Maximum,
+        };
+
+        let mut length = 2;
+        let mut dictionary_id = None;
+        if flg & 0b_0010_0000 != 0 {
+            let bytes = data
+                .get(2..6)
+                .ok_or_else(|| invalid("truncated preset dictionary id"))?;
+            dictionary_id = Some(u32::from_be_bytes(bytes.try_into().unwrap()));
+            length += 4;
+        }
+
+        let header = Header {
+            window_size: 1 << window_bits,
+            level,
+            dictionary_id,
+        };
+        Ok((header, length))
+    }
+}
+
+/// Compresses `data` as a zlib stream at the default level.
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    deflate_with(data, Level::DEFAULT)
+}
+
+/// Compresses `data` as a zlib stream at the given level.
+pub fn deflate_with(data: &[u8], level: Level) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    write_deflated(&mut buffer, data, level, None);
+    buffer
+}
+
+/// Writes `data` to `buffer` as a zlib stream at the given level, optionally
+/// compressed with a preset dictionary which the decoder must also be given.
+/// Returns the range of `buffer` that the stream was written to.
+pub fn write_deflated(
+    buffer: &mut Vec<u8>,
+    data: &[u8],
+    level: Level,
+    dictionary: Option<&[u8]>,
+) -> Range<usize> {
+    let before = buffer.len();
+    write_header(buffer, level.into(), dictionary.map(adler32));
+    write_deflated_with_dictionary(buffer, data, dictionary.unwrap_or_default(), level);
+    write_trailer(buffer, data);
+    let after = buffer.len();
+    before..after
+}
+
+/// Writes a zlib header for a DEFLATE stream with a 32KiB window, which must
+/// be followed by the stream and [`write_trailer`].
+pub fn write_header(
+    buffer: &mut Vec<u8>,
+    level: FLevel,
+    dictionary_id: Option<u32>,
+) -> Range<usize> {
+    let before = buffer.len();
+
+    buffer.push(CMF);
+    // compression level and preset dictionary flag
+    let mut flg = (level as u8) << 6 | u8::from(dictionary_id.is_some()) << 5;
+    // check bits, making the header a multiple of 31
+    flg |= (31 - (u16::from(CMF) << 8 | u16::from(flg)) % 31) as u8 % 31;
+    buffer.push(flg);
+    if let Some(dictionary_id) = dictionary_id {
+        buffer.extend_from_slice(&dictionary_id.to_be_bytes());
+    }
+
+    let after = buffer.len();
+    before..after
+}
+
+/// Writes the trailer of a zlib stream, the Adler-32 checksum of the
+/// uncompressed `data`.
+pub fn write_trailer(buffer: &mut Vec<u8>, data: &[u8]) -> Range<usize> {
+    let before = buffer.len();
+    buffer.extend_from_slice(&adler32(data).to_be_bytes());
+    let after = buffer.len();
+    before..after
+}
+
+/// Compresses a zlib stream incrementally, for data that isn't all available
+/// at once.
+pub struct Deflater {
+    deflater: deflate::Deflater,
+    adler: Adler32,
+}
+
+impl Deflater {
+    /// Starts a stream at the given level, writing its header to `output`.
+    pub fn new(level: Level, output: &mut Vec<u8>) -> Self {
+        write_header(output, level.into(), None);
+        Self {
+            deflater: deflate::Deflater::new(level),
+            adler: Adler32::new(),
+        }
+    }
+
+    /// Compresses `input`, appending any output that's ready to `output`.
+    pub fn write(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        self.adler.update(input);
+        self.deflater.write(input, output);
+    }
+
+    /// Compresses any buffered input and ends the stream with its trailer,
+    /// appending the rest of the output to `output`.
+    pub fn finish(self, output: &mut Vec<u8>) {
+        self.deflater.finish(output);
+        output.extend_from_slice(&self.adler.finalize().to_be_bytes());
+    }
+}
+
+/// Decompresses a zlib stream that doesn't use a preset dictionary, and
+/// verifies its checksum. Any data following the stream is ignored.
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, Error> {
+    inflate_with(data, None, usize::MAX)
+}
+
+/// Decompresses a zlib stream and verifies its checksum, failing rather than
+/// producing more than `limit` bytes. The `dictionary` is required if the
+/// stream was compressed with one.
+///
+/// Bit offsets in DEFLATE errors are from the end of the zlib header.
+pub fn inflate_with(
+    data: &[u8],
+    dictionary: Option<&[u8]>,
+    limit: usize,
+) -> Result<Vec<u8>, Error> {
+    let (header, length) = Header::read(data)?;
+    let mut inflater = match (header.dictionary_id, dictionary) {
+        (None, _) => Inflater::with_limit(limit),
+        (Some(dictionary_id), Some(dictionary)) if adler32(dictionary) == dictionary_id =>
+            Inflater::with_dictionary(dictionary, limit),
+        (Some(dictionary_id), _) => return Err(Error::ZlibDictionaryRequired { dictionary_id }),
+    };
+
+    let mut output = Vec::new();
+    inflater.write(&data[length..], &mut output)?;
+    inflater.finish()?;
+
+    let trailer = inflater.remaining().get(..4).ok_or(Error::InvalidZlib {
+        reason: "truncated Adler-32 trailer",
+    })?;
+    let stored = u32::from_be_bytes(trailer.try_into().unwrap());
+    let computed = adler32(&output);
+    if stored != computed {
+        return Err(Error::ChecksumMismatch {
+            checksum: "Adler-32",
+            stored,
+            computed,
+        });
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_zlib_output() {
+        // zlib.compress(b"hello")
+        let stream = [
+            0x78, 0x9C, 0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0x07, 0x00, 0x06, 0x2C, 0x02, 0x15,
+        ];
+        assert_eq!(inflate(&stream).unwrap(), b"hello");
+
+        // the same, with a preset dictionary
+        let dictionary = b"the quick brown fox";
+        let stream = [
+            0x78, 0xF9, 0x47, 0x8E, 0x07, 0x34, 0x2B, 0xC1, 0x14, 0x52, 0xC8, 0x2A, 0xCD, 0x2D,
+            0x28, 0x06, 0x00, 0x7A, 0x06, 0x09, 0x83,
+        ];
+        assert_eq!(
+            inflate_with(&stream, Some(dictionary), usize::MAX).unwrap(),
+            b"the quick brown fox jumps"
+        );
+    }
+
+    #[test]
+    fn header_records_level() {
+        for (level, flg) in [
+            (0, 0x01),
+            (1, 0x01),
+            (2, 0x5E),
+            (5, 0x5E),
+            (6, 0x9C),
+            (7, 0xDA),
+            (9, 0xDA),
+        ] {
+            let level = Level::new(level);
+            let stream = deflate_with(b"hello", level);
+            assert_eq!(stream[..2], [CMF, flg], "{level:?}");
+            let (header, length) = Header::read(&stream).unwrap();
+            assert_eq!(header, Header {
+                window_size: 1 << 15,
+                level: level.into(),
+                dictionary_id: None,
+            });
+            assert_eq!(length, 2);
+            assert_eq!(inflate(&stream).unwrap(), b"hello");
+        }
+    }
+
+    #[test]
+    fn header_is_checked() {
+        let invalid = |reason| Err(Error::InvalidZlib { reason });
+        assert_eq!(inflate(&[0x78]), invalid("truncated header"));
+        assert_eq!(
+            inflate(&[0x78, 0x9D]),
+            invalid("header check bits don't match")
+        );
+        assert_eq!(
+            inflate(&[0x79, 0x18]),
+            invalid("compression method isn't DEFLATE")
+        );
+        assert_eq!(
+            inflate(&[0x88, 0x1C]),
+            invalid("window size is larger than 32KiB")
+        );
+        assert_eq!(
+            inflate(&[0x78, 0xBB, 0x00]),
+            invalid("truncated preset dictionary id")
+        );
+    }
+
+    #[test]
+    fn preset_dictionaries() {
+        let dictionary = b"a preset dictionary, with text that the data repeats";
+        let data = b"text that the data repeats, from a preset dictionary";
+        let mut stream = Vec::new();
+        write_deflated(&mut stream, data, Level::DEFAULT, Some(dictionary));
+        assert!(stream.len() < deflate(data).len());
+
+        let (header, length) = Header::read(&stream).unwrap();
+        assert_eq!(header.dictionary_id, Some(adler32(dictionary)));
+        assert_eq!(stream[1] & 0b_0010_0000, 0b_0010_0000);
+        assert_eq!(length, 6);
+
+        let required = Err(Error::ZlibDictionaryRequired {
+            dictionary_id: adler32(dictionary),
+        });
+        assert_eq!(inflate(&stream), required);
+        assert_eq!(
+            inflate_with(&stream, Some(b"another"), usize::MAX),
+            required
+        );
+        assert_eq!(
+            inflate_with(&stream, Some(dictionary), usize::MAX).unwrap(),
+            data
+        );
+        // a dictionary given for a stream that doesn't need one is ignored
+        assert_eq!(
+            inflate_with(&deflate(data), Some(dictionary), usize::MAX).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn trailer_is_checked() {
+        let mut stream = deflate(b"hello");
+        let stored = adler32(b"hello");
+        assert_eq!(stream[stream.len() - 4..], stored.to_be_bytes());
+
+        let last = stream.len() - 1;
+        stream[last] ^= 1;
+        assert_eq!(
+            inflate(&stream),
+            Err(Error::ChecksumMismatch {
+                checksum: "Adler-32",
+                stored: stored ^ 1,
+                computed: stored,
+            })
+        );
+        assert_eq!(
+            inflate(&stream[..last]),
+            Err(Error::InvalidZlib {
+                reason: "truncated Adler-32 trailer",
+            })
+        );
+    }
+
+    #[test]
+    fn streams_match_one_shot() {
+        let data: Vec<u8> = (0..600_000_u32)
+            .map(|i| ((i % 251) ^ (i >> 12)) as u8)
+            .collect();
+        let mut stream = Vec::new();
+        let mut deflater = Deflater::new(Level::FASTEST, &mut stream);
+        for chunk in data.chunks(70_000) {
+            deflater.write(chunk, &mut stream);
+        }
+        deflater.finish(&mut stream);
+        assert_eq!(stream[..2], deflate_with(b"", Level::FASTEST)[..2]);
+        assert_eq!(inflate(&stream).unwrap(), data);
+    }
+}
@@ -0,0 +1,1437 @@
+//! Decodes every kind of PNG the crate produces with independent decoders,
+//! failing on any malformed chunk or checksum mismatch.
+
+use std::io::Read;
+use std::io::Write;
+
+use flate2::read::ZlibDecoder;
+use flate2::Crc;
+use indexmap::IndexMap;
+use zipng::png::*;
+use zipng::*;
+
+/// Splits a PNG into its chunks, checking the signature and each chunk's
+/// CRC-32.
+fn chunks(png: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n", "invalid signature");
+    let mut chunks = Vec::new();
+    let mut rest = &png[8..];
+    while !rest.is_empty() {
+        let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        let chunk_type: [u8; 4] = rest[4..8].try_into().unwrap();
+        let data = &rest[8..8 + length];
+        let stored = u32::from_be_bytes(rest[8 + length..12 + length].try_into().unwrap());
+        let mut crc = Crc::new();
+        crc.update(&rest[4..8 + length]);
+        assert_eq!(
+            stored,
+            crc.sum(),
+            "CRC-32 mismatch in {:?} chunk",
+            chunk_type.escape_ascii().to_string()
+        );
+        chunks.push((chunk_type, data));
+        rest = &rest[12 + length..];
+    }
+    assert_eq!(
+        chunks.last().map(|chunk| &chunk.0),
+        Some(b"IEND"),
+        "missing IEND"
+    );
+    chunks
+}
+
+/// Decodes a PNG, returning its header and its pixel data without filter
+/// bytes, checking it with both flate2 (which verifies the zlib Adler-32) and
+/// the png crate, and that our own decoder agrees with them.
+fn decode(png: &[u8]) -> (::png::OutputInfo, Vec<u8>) {
+    let idat: Vec<u8> = chunks(png)
+        .into_iter()
+        .filter(|(chunk_type, _)| chunk_type == b"IDAT")
+        .flat_map(|(_, data)| data.to_vec())
+        .collect();
+    let mut scanlines = Vec::new();
+    ZlibDecoder::new(&idat[..])
+        .read_to_end(&mut scanlines)
+        .expect("invalid zlib stream");
+
+    let mut decoder = ::png::Decoder::new(png);
+    decoder.set_transformations(::png::Transformations::IDENTITY);
+    let mut reader = decoder.read_info().expect("invalid PNG header");
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).expect("invalid PNG data");
+    reader.finish().expect("invalid PNG trailer");
+    pixels.truncate(info.buffer_size());
+
+    // scanlines that aren't filtered or interlaced hold the pixels as they are
+    let interlaced = chunks(png)[0].1[12] == 1;
+    if !interlaced {
+        assert_eq!(scanlines.len(), (info.line_size + 1) * info.height as usize);
+        for (line, pixels) in scanlines
+            .chunks(info.line_size + 1)
+            .zip(pixels.chunks(info.line_size))
+        {
+            if line[0] == 0 {
+                assert_eq!(&line[1..], pixels, "decoders disagree");
+            }
+        }
+    }
+
+    let image = read_png(png).expect("read_png failed");
+    assert_eq!((image.width, image.height), (info.width, info.height));
+    assert_eq!(image.data, pixels, "read_png disagrees");
+
+    (info, pixels)
+}
+
+/// Checks that `pixels` holds `body` followed only by zero padding.
+fn assert_holds(pixels: &[u8], body: &[u8]) {
+    assert_eq!(&pixels[..body.len()], body);
+    assert!(pixels[body.len()..].iter().all(|&byte| byte == 0));
+}
+
+fn sample(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+fn files(count: usize, len: usize) -> Files {
+    let mut files = IndexMap::new();
+    files.insert(b"mimetype".to_vec(), b"application/example".to_vec());
+    for index in 0..count {
+        let name = format!("dir/file-{index}.bin").into_bytes();
+        files.insert(name, sample(len * (index + 1)));
+    }
+    files.insert(b"empty/".to_vec(), Vec::new());
+    files.into()
+}
+
+/// Runs `program` with `args` followed by the path of a temporary copy of
+/// `archive`, or returns `None` if `program` isn't installed, so tests can
+/// check archives with independent readers where they're available.
+fn run_on_archive(program: &str, args: &[&str], archive: &[u8]) -> Option<std::process::Output> {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "zipng-{}-{}.zip",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&path, archive).unwrap();
+    let output = std::process::Command::new(program)
+        .args(args)
+        .arg(&path)
+        .output();
+    std::fs::remove_file(&path).unwrap();
+    output.ok()
+}
+
+#[test]
+fn png_default_sizes() {
+    for len in [
+        0, 1, 0x20, 0x21, 0x100, 0x101, 0x800, 0x2001, 0x20001, 0x80001,
+    ] {
+        let body = sample(len);
+        let (_, pixels) = decode(&zipng::png(&body));
+        assert_holds(&pixels, &body);
+    }
+}
+
+#[test]
+fn png_writer_streams_rows() {
+    let mut opts = PngOptions::default();
+    opts.bit_depth = EightBit;
+    opts.color_mode = RedGreenBlue;
+    opts.compression_level = deflate::Level::NONE;
+    let (width, height) = (300, 200);
+    let mut writer = PngWriter::new(Vec::new(), width, height, &opts).unwrap();
+    writer.write_chunk(b"teST", b"before").unwrap();
+    let rows: Vec<Vec<u8>> = (0..height as usize)
+        .map(|row| sample(900 + row)[row..].to_vec())
+        .collect();
+    for row in &rows {
+        writer.write_row(row).unwrap();
+    }
+    assert!(writer.write_row(&rows[0]).is_err());
+    writer.write_chunk(b"teST", b"after").unwrap();
+    let png = writer.finish().unwrap();
+
+    let chunk_types: Vec<_> = chunks(&png)
+        .into_iter()
+        .map(|(chunk_type, _)| chunk_type)
+        .collect();
+    assert_eq!(chunk_types[1], *b"teST");
+    assert_eq!(chunk_types[chunk_types.len() - 2], *b"teST");
+    let idat = chunks(&png)
+        .into_iter()
+        .filter(|(chunk_type, _)| chunk_type == b"IDAT")
+        .map(|(_, data)| data.len())
+        .collect::<Vec<_>>();
+    assert!(idat.len() > 1);
+    assert!(idat.iter().all(|&len| len <= MAX_IDAT_LENGTH));
+
+    let (_, pixels) = decode(&png);
+    assert_eq!(pixels, rows.concat());
+}
+
+#[test]
+fn png_bit_depths_and_color_modes() {
+    let palette_1: &[u8] = &[0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF];
+    let palette_4: &[u8] = &[0x80; 16 * 3];
+    let cases: [(BitDepth, ColorMode, Option<&[u8]>); 14] = [
+        (OneBit, Lightness, None),
+        (TwoBit, Lightness, None),
+        (FourBit, Lightness, None),
+        (EightBit, Lightness, None),
+        (SixteenBit, Lightness, None),
+        (EightBit, RedGreenBlue, None),
+        (SixteenBit, RedGreenBlue, None),
+        (EightBit, LightnessAlpha, None),
+        (SixteenBit, LightnessAlpha, None),
+        (EightBit, RedGreenBlueAlpha, None),
+        (SixteenBit, RedGreenBlueAlpha, None),
+        (OneBit, Indexed, Some(palette_1)),
+        (FourBit, Indexed, Some(palette_4)),
+        (EightBit, Indexed, Some(&PALLETTE_8_BIT_DATA[..])),
+    ];
+    let body = sample(1000);
+    for (bit_depth, color_mode, palette) in cases {
+        for width in [1, 3, 17, 64] {
+            let mut opts = PngOptions::default();
+            opts.width = width;
+            opts.max_height = 0;
+            opts.bit_depth = bit_depth;
+            opts.color_mode = color_mode;
+            let (width, height) = opts.dimensions_for(body.len()).unwrap();
+            let mut data = body.clone();
+            data.resize(
+                height as usize * bytes_per_line(width, bit_depth, color_mode),
+                0,
+            );
+
+            let mut png = Vec::new();
+            write_png(
+                &mut png, &data, width, height, bit_depth, color_mode, palette,
+            )
+            .unwrap();
+            let (info, pixels) = decode(&png);
+            assert_eq!((info.width, info.height), (width, height));
+            assert_eq!(info.bit_depth as u8, bit_depth.u8());
+            assert_eq!(info.color_type as u8, color_mode.u8());
+            assert_holds(&pixels, &body);
+
+            opts.color_palette = palette.map(<[u8]>::to_vec);
+            opts.interlace = true;
+            let mut writer = PngWriter::new(Vec::new(), width, height, &opts).unwrap();
+            writer.write_padded(&data).unwrap();
+            let interlaced = writer.finish().unwrap();
+            assert_eq!(chunks(&interlaced)[0].1[12], 1, "not interlaced");
+            decode(&interlaced);
+            // padding bits at the ends of scanlines aren't kept
+            assert_eq!(
+                read_png(&interlaced).unwrap().to_rgba8(),
+                read_png(&png).unwrap().to_rgba8()
+            );
+        }
+    }
+
+    let png = png_with(&body, |opts| {
+        opts.width = 5;
+        opts.bit_depth = SixteenBit;
+        opts.color_mode = RedGreenBlueAlpha;
+        opts.color_palette = None;
+    });
+    assert_holds(&decode(&png).1, &body);
+}
+
+#[test]
+fn png_large_body_spans_stored_blocks() {
+    let body = sample(0x30000);
+    let (_, pixels) = decode(&png_with(&body, |opts| {
+        opts.width = 100;
+        opts.compression_level = deflate::Level::NONE;
+    }));
+    assert_holds(&pixels, &body);
+}
+
+#[test]
+fn png_with_low_bit_depths() {
+    // 13 pixels don't fill a whole number of bytes at any of these depths, and
+    // 101 bytes don't fill a whole number of scanlines
+    let body = sample(101);
+    let cases: [fn(&mut PngOptions); 3] = [
+        |opts| {
+            opts.width = 13;
+            opts.bit_depth = OneBit;
+            opts.color_mode = Lightness;
+        },
+        |opts| {
+            opts.width = 13;
+            opts.bit_depth = TwoBit;
+            opts.color_mode = Lightness;
+        },
+        |opts| {
+            opts.width = 13;
+            opts.bit_depth = FourBit;
+            opts.color_mode = Lightness;
+        },
+    ];
+    for set_opts in cases {
+        let mut opts = PngOptions::default();
+        set_opts(&mut opts);
+        let bit_depth = opts.bit_depth;
+        let (info, pixels) = decode(&png_with(&body, set_opts));
+        let bytes_per_line = bytes_per_line(13, bit_depth, Lightness);
+        assert_eq!(info.bit_depth as u8, bit_depth.u8());
+        assert_eq!(info.width, 13);
+        // just enough scanlines, with the last one padded
+        assert_eq!(pixels.len(), info.height as usize * bytes_per_line);
+        assert!(pixels.len() - body.len() < bytes_per_line);
+        assert_holds(&pixels, &body);
+    }
+}
+
+#[test]
+fn png_max_height() {
+    let body = sample(1000);
+    assert_eq!(
+        try_png_with(&body, |opts| {
+            opts.width = 10;
+            opts.bit_depth = EightBit;
+            opts.color_mode = Lightness;
+            opts.max_height = 99;
+        }),
+        Err(Error::ImageTooTall {
+            height: 100,
+            max_height: 99
+        })
+    );
+    let png = try_png_with(&body, |opts| {
+        opts.width = 10;
+        opts.bit_depth = EightBit;
+        opts.color_mode = Lightness;
+        opts.max_height = 100;
+    })
+    .unwrap();
+    assert_eq!(decode(&png).0.height, 100);
+}
+
+#[test]
+fn png_filters_choose_per_scanline() {
+    // a smooth gradient, which filtering makes far more compressible
+    let (width, height) = (64, 64);
+    let data: Vec<u8> = (0..height)
+        .flat_map(|y| (0..width).flat_map(move |x| [x * 4, y * 4, (x + y) * 2]))
+        .map(|value: u32| value as u8)
+        .collect();
+    let encode = |filter| {
+        let mut opts = PngOptions::default();
+        opts.bit_depth = EightBit;
+        opts.color_mode = RedGreenBlue;
+        opts.filter = filter;
+        let mut writer = PngWriter::new(Vec::new(), width, height, &opts).unwrap();
+        writer.write_padded(&data).unwrap();
+        let png = writer.finish().unwrap();
+        let (_, pixels) = decode(&png);
+        assert_eq!(pixels, data);
+        png.len()
+    };
+
+    let unfiltered = encode(Filter::Fixed(FilterType::None));
+    for filter_type in FilterType::ALL {
+        encode(Filter::Fixed(filter_type));
+    }
+    assert!(encode(Filter::MinimumSum) < unfiltered);
+    assert!(encode(Filter::BruteForce) < unfiltered);
+}
+
+#[test]
+fn png_text_chunks_round_trip() {
+    let mut opts = PngOptions::default();
+    let mut license = Text::new("License", "CC0 ".repeat(100));
+    license.compressed = true;
+    let mut title = Text::new("Title", "Zipng \u{1F5DC}");
+    title.language = "en".to_string();
+    title.translated_keyword = "Title".to_string();
+    title.after_image = true;
+    opts.text = vec![Text::new("Author", "Zoë"), license, title];
+    let mut writer = PngWriter::new(Vec::new(), 8, 8, &opts).unwrap();
+    writer.write_padded(&[]).unwrap();
+    let png = writer.finish().unwrap();
+
+    let chunk_types: Vec<_> = chunks(&png)
+        .into_iter()
+        .map(|(chunk_type, _)| chunk_type)
+        .collect();
+    assert_eq!(chunk_types, [
+        *b"IHDR", *b"tEXt", *b"zTXt", *b"IDAT", *b"iTXt", *b"IEND"
+    ]);
+
+    let mut reader = ::png::Decoder::new(&png[..]).read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut pixels).unwrap();
+    reader.finish().unwrap();
+    let info = reader.info();
+    assert_eq!(info.uncompressed_latin1_text[0].keyword, "Author");
+    assert_eq!(info.uncompressed_latin1_text[0].text, "Zoë");
+    assert_eq!(
+        info.compressed_latin1_text[0].get_text().unwrap(),
+        "CC0 ".repeat(100)
+    );
+    assert_eq!(info.utf8_text[0].language_tag, "en");
+    assert_eq!(info.utf8_text[0].get_text().unwrap(), "Zipng \u{1F5DC}");
+
+    for keyword in ["", " Title", "Two  spaces", "Tab\t", "Zipng \u{1F5DC}"] {
+        opts.text = vec![Text::new(keyword, "text")];
+        assert!(matches!(
+            PngWriter::new(Vec::new(), 8, 8, &opts),
+            Err(Error::InvalidPngChunk { .. })
+        ));
+    }
+}
+
+#[test]
+fn png_color_chunks_in_order() {
+    let mut opts = PngOptions::default();
+    opts.bit_depth = EightBit;
+    opts.color_mode = Indexed;
+    opts.color_palette = Some(PALLETTE_8_BIT_DATA.to_vec());
+    opts.srgb = Some(RenderingIntent::RelativeColorimetric);
+    opts.gamma = Some(Gamma::new(1.0 / 2.2));
+    opts.chromaticities = Some(Chromaticities::SRGB);
+    opts.physical_dimensions = Some(PhysicalDimensions::from_dpi(300));
+    let encode = |opts: &PngOptions| {
+        let mut writer = PngWriter::new(Vec::new(), 4, 4, opts)?;
+        writer.write_padded(&[])?;
+        writer.finish()
+    };
+    let png = encode(&opts).unwrap();
+
+    let chunk_types: Vec<_> = chunks(&png)
+        .into_iter()
+        .map(|(chunk_type, _)| chunk_type)
+        .collect();
+    assert_eq!(chunk_types, [
+        *b"IHDR", *b"cHRM", *b"gAMA", *b"sRGB", *b"PLTE", *b"pHYs", *b"IDAT", *b"IEND"
+    ]);
+    decode(&png);
+    let reader = ::png::Decoder::new(&png[..]).read_info().unwrap();
+    let info = reader.info();
+    assert_eq!(info.source_gamma.unwrap().into_scaled(), 45455);
+    assert_eq!(
+        info.source_chromaticities.unwrap().red.0.into_scaled(),
+        64000
+    );
+    assert_eq!(
+        info.srgb,
+        Some(::png::SrgbRenderingIntent::RelativeColorimetric)
+    );
+    let dimensions = info.pixel_dims.unwrap();
+    assert_eq!(
+        (dimensions.xppu, dimensions.unit),
+        (11811, ::png::Unit::Meter)
+    );
+
+    opts.srgb = None;
+    opts.icc_profile = Some(IccProfile::new("Example profile", vec![0xA5; 1000]));
+    let png = encode(&opts).unwrap();
+    let reader = ::png::Decoder::new(&png[..]).read_info().unwrap();
+    assert_eq!(
+        reader.info().icc_profile.as_deref(),
+        Some(&[0xA5; 1000][..])
+    );
+
+    opts.srgb = Some(RenderingIntent::Perceptual);
+    assert!(matches!(encode(&opts), Err(Error::InvalidPngChunk { .. })));
+    opts.srgb = None;
+    opts.gamma = Some(Gamma(0));
+    assert!(matches!(encode(&opts), Err(Error::InvalidPngChunk { .. })));
+}
+
+#[test]
+fn png_transparency_round_trips() {
+    let files = files(10, 5000);
+    let png = zipng_with(&files, |opts| {
+        opts.png.transparency = Some(Transparency::Palette(vec![0x00]));
+    });
+    let chunk_types: Vec<_> = chunks(&png)
+        .into_iter()
+        .map(|(chunk_type, _)| chunk_type)
+        .filter(|chunk_type| chunk_type != b"IDAT")
+        .collect();
+    assert_eq!(chunk_types, [*b"IHDR", *b"PLTE", *b"tRNS", *b"IEND"]);
+    decode(&png);
+
+    // the last pixel, the high byte of the archive comment's length, is
+    // transparent, but the archive can still be recovered from its pixels
+    let image = read_png(&png).unwrap();
+    assert_eq!(image.transparency, Some(Transparency::Palette(vec![0x00])));
+    assert_eq!(image.to_rgba8().last(), Some(&[0x00, 0x00, 0x00, 0x00]));
+    let mut reader = ::png::Decoder::new(&png[..]).read_info().unwrap();
+    assert_eq!(reader.info().trns.as_deref(), Some(&[0x00][..]));
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut pixels).unwrap();
+    let archive = extract_from_pixels(&png).unwrap();
+    assert_eq!(
+        zip::Archive::new(&archive).unwrap().entries().len(),
+        zip::Archive::new(&png).unwrap().entries().len()
+    );
+
+    let encode = |bit_depth, color_mode, transparency| {
+        let mut opts = PngOptions::default();
+        opts.bit_depth = bit_depth;
+        opts.color_mode = color_mode;
+        opts.transparency = Some(transparency);
+        let mut writer = PngWriter::new(Vec::new(), 2, 1, &opts)?;
+        writer.write_padded(&[0x12, 0x34, 0x56, 0x12, 0x34, 0x57])?;
+        writer.finish()
+    };
+    let png = encode(
+        EightBit,
+        RedGreenBlue,
+        Transparency::RedGreenBlue(0x12, 0x34, 0x56),
+    )
+    .unwrap();
+    assert_eq!(read_png(&png).unwrap().to_rgba8(), [
+        [0x12, 0x34, 0x56, 0x00],
+        [0x12, 0x34, 0x57, 0xFF]
+    ]);
+    let png = encode(SixteenBit, Lightness, Transparency::Lightness(0x1234)).unwrap();
+    assert_eq!(read_png(&png).unwrap().to_rgba8()[0][3], 0x00);
+
+    for (bit_depth, color_mode, transparency) in [
+        (EightBit, Lightness, Transparency::RedGreenBlue(0, 0, 0)),
+        (TwoBit, Lightness, Transparency::Lightness(4)),
+        (EightBit, Indexed, Transparency::Palette(vec![0x00])),
+    ] {
+        assert!(matches!(
+            encode(bit_depth, color_mode, transparency),
+            Err(Error::InvalidPngChunk { .. })
+        ));
+    }
+
+    // but a tRNS chunk that doesn't suit the color mode is ignored when reading
+    for (color_mode, chunk) in [
+        (RedGreenBlueAlpha, &[0x00; 6][..]),
+        (LightnessAlpha, &[0x00; 2]),
+        (Lightness, &[0x00; 4]),
+    ] {
+        let mut opts = PngOptions::default();
+        opts.bit_depth = EightBit;
+        opts.color_mode = color_mode;
+        let mut writer = PngWriter::new(Vec::new(), 1, 1, &opts).unwrap();
+        writer.write_chunk(b"tRNS", chunk).unwrap();
+        writer.write_padded(&[0x00; 4]).unwrap();
+        let png = writer.finish().unwrap();
+        let image = read_png(&png).unwrap();
+        assert_eq!(image.transparency, None);
+        let alpha = if color_mode == Lightness { 0xFF } else { 0x00 };
+        assert_eq!(image.to_rgba8(), [[0x00, 0x00, 0x00, alpha]]);
+    }
+}
+
+#[test]
+fn zipng_image_data_is_raw() {
+    let files = files(10, 500);
+    let pngs = [
+        zipng::zipng(&files),
+        // options that would hide the archive are ignored
+        zipng_with(&files, |opts| {
+            opts.png.filter = Filter::BruteForce;
+            opts.png.compression_level = deflate::Level::BEST;
+            opts.png.interlace = true;
+        }),
+    ];
+    for png in pngs {
+        assert_eq!(chunks(&png)[0].1[12], 0, "interlaced");
+        let idat = chunks(&png)
+            .into_iter()
+            .filter(|(chunk_type, _)| chunk_type == b"IDAT")
+            .map(|(_, data)| data)
+            .collect::<Vec<_>>();
+        assert_eq!(idat.len(), 1);
+        let idat = idat[0];
+        let (info, pixels) = decode(&png);
+
+        // a zlib header, then a stored block for each scanline, holding it
+        // unfiltered
+        let height = info.height as usize;
+        assert_eq!(idat.len(), 2 + (info.line_size + 6) * height + 4);
+        for (index, line) in pixels.chunks(info.line_size).enumerate() {
+            let block = &idat[2 + (info.line_size + 6) * index..][..info.line_size + 6];
+            assert_eq!(block[0], u8::from(index + 1 == height));
+            let length = u16::from_le_bytes([block[1], block[2]]);
+            assert_eq!(usize::from(length), info.line_size + 1);
+            assert_eq!(block[5], 0x00);
+            assert_eq!(&block[6..], line);
+        }
+
+        // the archive is only stored once, with each record in the image
+        // data, where the archive's offsets point
+        let idat_start = idat.as_ptr() as usize - png.as_ptr() as usize;
+        let idat_end = idat_start + idat.len();
+        let archive = zip::Archive::new(&png).unwrap();
+        assert!(archive.prefix().is_empty());
+        archive.verify().unwrap();
+        let extracted = extract_from_pixels(&png).unwrap();
+        assert_eq!(extracted.len(), png.len());
+        for entry in archive.entries() {
+            let end = entry.data.as_ptr() as usize - png.as_ptr() as usize + entry.data.len();
+            assert!(idat_start < entry.header_offset && end < idat_end);
+            assert_eq!(
+                extracted[entry.header_offset..end],
+                png[entry.header_offset..end]
+            );
+        }
+        assert_eq!(extracted[idat_end - 4..], [0x00; 20][..]);
+    }
+}
+
+#[test]
+fn zipng_round_trips() {
+    for (count, len) in [(0, 0), (1, 1), (3, 100), (10, 5000), (2, 0x7000)] {
+        let files = files(count, len);
+        let pngs = [
+            zipng::zipng(&files),
+            zipng_with(&files, |opts| opts.zip.order = zip::Sorted),
+            zipng_with(&files, |opts| {
+                opts.png.width = 7;
+                opts.png.max_height = 0;
+                opts.png.bit_depth = SixteenBit;
+                opts.png.color_mode = RedGreenBlueAlpha;
+                opts.png.color_palette = None;
+            }),
+        ];
+        for png in pngs {
+            decode(&png);
+            let archive = zip::Archive::new(&png).unwrap();
+            assert_eq!(archive.entries().len(), files.iter().count());
+            archive.verify().unwrap();
+        }
+    }
+}
+
+#[test]
+fn read_png_reverses_filters() {
+    let filters = [
+        ::png::FilterType::NoFilter,
+        ::png::FilterType::Sub,
+        ::png::FilterType::Up,
+        ::png::FilterType::Avg,
+        ::png::FilterType::Paeth,
+    ];
+    let cases = [
+        (::png::ColorType::Grayscale, ::png::BitDepth::Two, 33),
+        (::png::ColorType::Rgb, ::png::BitDepth::Eight, 31),
+        (::png::ColorType::Rgba, ::png::BitDepth::Sixteen, 9),
+    ];
+    for (color_type, bit_depth, width) in cases {
+        for filter in filters {
+            for adaptive in [false, true] {
+                let height = 20;
+                let line = (width * color_type.samples() * bit_depth as usize + 7) / 8;
+                let body = sample(line * height);
+
+                let mut png = Vec::new();
+                let mut encoder = ::png::Encoder::new(&mut png, width as u32, height as u32);
+                encoder.set_color(color_type);
+                encoder.set_depth(bit_depth);
+                encoder.set_filter(filter);
+                if adaptive {
+                    encoder.set_adaptive_filter(::png::AdaptiveFilterType::Adaptive);
+                }
+                let mut writer = encoder.write_header().unwrap();
+                writer.write_image_data(&body).unwrap();
+                writer.finish().unwrap();
+
+                let image = read_png(&png).unwrap();
+                assert_eq!(image.data, body, "{color_type:?} {filter:?} {adaptive}");
+                assert_eq!(image.color_mode.u8(), color_type as u8);
+                assert_eq!(image.bit_depth.u8(), bit_depth as u8);
+            }
+        }
+    }
+}
+
+/// Re-encodes a PNG the way an image host might, expanding palettes and low
+/// bit depths, adding an alpha channel, and filtering adaptively.
+fn re_encode(png: &[u8], with_alpha: bool) -> Vec<u8> {
+    let mut decoder = ::png::Decoder::new(png);
+    decoder.set_transformations(::png::Transformations::EXPAND);
+    let mut reader = decoder.read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).unwrap();
+    pixels.truncate(info.buffer_size());
+    let (color_type, pixels) = match (info.color_type, with_alpha) {
+        (::png::ColorType::Grayscale, true) => (
+            ::png::ColorType::GrayscaleAlpha,
+            pixels.iter().flat_map(|&gray| [gray, 0xFF]).collect(),
+        ),
+        (::png::ColorType::Rgb, true) => (
+            ::png::ColorType::Rgba,
+            pixels
+                .chunks(3)
+                .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 0xFF])
+                .collect(),
+        ),
+        (color_type, _) => (color_type, pixels),
+    };
+
+    let mut output = Vec::new();
+    let mut encoder = ::png::Encoder::new(&mut output, info.width, info.height);
+    encoder.set_color(color_type);
+    encoder.set_depth(info.bit_depth);
+    encoder.set_adaptive_filter(::png::AdaptiveFilterType::Adaptive);
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(&pixels).unwrap();
+    writer.finish().unwrap();
+    output
+}
+
+#[test]
+fn extract_from_re_encoded_pixels() {
+    for (count, len) in [(0, 0), (1, 1), (1, 0x30), (3, 100), (10, 5000), (2, 0x4000)] {
+        let files = files(count, len);
+        let png = zipng::zipng(&files);
+        let original = extract_from_pixels(&png).unwrap();
+        let entries = zip::Archive::new(&original).unwrap().entries().len();
+        assert_eq!(entries, files.iter().count());
+
+        for with_alpha in [false, true] {
+            let re_encoded = re_encode(&png, with_alpha);
+            assert_ne!(re_encoded, png);
+            assert_eq!(extract_from_pixels(&re_encoded).unwrap(), original);
+        }
+    }
+
+    assert!(matches!(
+        extract_from_pixels(&zipng::png(&sample(1000))),
+        Err(Error::NoArchiveInPixels)
+    ));
+
+    // a single pixel holding a truncated terminator
+    let mut opts = PngOptions::default();
+    opts.bit_depth = EightBit;
+    opts.color_mode = RedGreenBlueAlpha;
+    let mut writer = PngWriter::new(Vec::new(), 1, 1, &opts).unwrap();
+    writer.write_row(b"PK\x05\x06").unwrap();
+    assert!(matches!(
+        extract_from_pixels(&writer.finish().unwrap()),
+        Err(Error::NoArchiveInPixels)
+    ));
+}
+
+/// The body of `lines.txt` in the fixtures.
+fn fixture_lines() -> Vec<u8> {
+    (0..1000)
+        .flat_map(|index| format!("line {index}\n").into_bytes())
+        .collect()
+}
+
+#[test]
+fn reads_other_writers_archives() {
+    // 2020-02-29 12:34:56
+    let mtime = 1_582_979_696;
+
+    // `zip -X - - < lines.txt | cat`, which streams its output so uses a data
+    // descriptor, with ZIP64 extended information since the size is unknown
+    let archive = zip::Archive::new(include_bytes!("fixtures/info-zip-streamed.zip")).unwrap();
+    archive.verify().unwrap();
+    let [entry] = archive.entries() else {
+        panic!("{:?}", archive.entries());
+    };
+    assert_eq!(entry.name, b"-");
+    assert_eq!(entry.flags & 1 << 3, 1 << 3);
+    assert_eq!(entry.method, 8);
+    assert_eq!(&*entry.body().unwrap(), fixture_lines());
+
+    // `zip -X -fz -z`, forcing ZIP64 extended information in local headers, and
+    // with a comment
+    let data = include_bytes!("fixtures/info-zip-zip64.zip");
+    for prefix in [&b""[..], b"prepended"] {
+        let data = [prefix, data].concat();
+        let archive = zip::Archive::new(&data).unwrap();
+        archive.verify().unwrap();
+        assert_eq!(archive.prefix(), prefix);
+        assert_eq!(archive.comment(), b"an Info-ZIP archive");
+        let names: Vec<&[u8]> = archive.entries().iter().map(|entry| entry.name).collect();
+        let expected: [&[u8]; 4] = [b"hello.txt", b"lines.txt", b"dir/", b"dir/empty"];
+        assert_eq!(names, expected);
+        for entry in archive.entries() {
+            assert_eq!(entry.mtime(), mtime);
+        }
+        assert_eq!(
+            &*archive.get(b"hello.txt").unwrap().body().unwrap(),
+            b"hello\n"
+        );
+        assert_eq!(
+            &*archive.get(b"lines.txt").unwrap().body().unwrap(),
+            fixture_lines()
+        );
+        assert!(archive.get(b"dir/").unwrap().is_dir());
+    }
+
+    // Python's zipfile writing to an unseekable stream, so with data
+    // descriptors, forcing ZIP64 for lines.txt, and with a comment
+    let archive = zip::Archive::new(include_bytes!("fixtures/python-streamed.zip")).unwrap();
+    archive.verify().unwrap();
+    assert_eq!(archive.comment(), b"a streamed Python archive");
+    let names: Vec<&[u8]> = archive.entries().iter().map(|entry| entry.name).collect();
+    let expected: [&[u8]; 3] = [b"hello.txt", b"lines.txt", b"dir/"];
+    assert_eq!(names, expected);
+    for entry in archive.entries() {
+        assert_eq!(entry.flags & 1 << 3, 1 << 3);
+        assert_eq!(entry.mtime(), mtime);
+    }
+    assert_eq!(archive.get(b"lines.txt").unwrap().version_needed, 45);
+    assert_eq!(
+        &*archive.get(b"hello.txt").unwrap().body().unwrap(),
+        b"hello\n"
+    );
+    assert_eq!(
+        &*archive.get(b"lines.txt").unwrap().body().unwrap(),
+        fixture_lines()
+    );
+    assert!(archive.get(b"dir/").unwrap().is_dir());
+}
+
+#[test]
+fn corrupted_adler32_is_detected() {
+    let mut png = zipng::png(&sample(1000));
+    let idat = png.windows(4).position(|window| window == b"IDAT").unwrap();
+    let length = u32::from_be_bytes(png[idat - 4..idat].try_into().unwrap()) as usize;
+    let end = idat + 4 + length;
+
+    // Corrupt the last byte of the zlib trailer, and fix up the chunk's CRC
+    // so that only the Adler-32 is wrong.
+    png[end - 1] ^= 1;
+    let mut crc = Crc::new();
+    crc.update(&png[idat..end]);
+    png[end..end + 4].copy_from_slice(&crc.sum().to_be_bytes());
+
+    assert!(std::panic::catch_unwind(|| decode(&png)).is_err());
+}
+
+#[test]
+fn corrupted_crc32_is_detected() {
+    let mut png = zipng::png(&sample(1000));
+    let len = png.len();
+    png[len - 1] ^= 1;
+    assert!(std::panic::catch_unwind(|| chunks(&png)).is_err());
+}
+
+#[test]
+fn files_builder() {
+    let body = sample(5000);
+    let files = Files::new()
+        .add("b.txt", "replaced")
+        .add_entry("a.bin", FileEntry {
+            body: body.clone(),
+            mtime: Some(zip::DOS_EPOCH + 86_400),
+            comment: b"a comment".to_vec(),
+            compression: Some(zip::Compression::Deflated),
+            ..FileEntry::default()
+        })
+        .add("b.txt", "hello");
+
+    // replacing a file keeps its position
+    let names: Vec<&[u8]> = files.iter().map(|(name, _)| name).collect();
+    let expected: [&[u8]; 2] = [b"b.txt", b"a.bin"];
+    assert_eq!(names, expected);
+    let map: IndexMap<Vec<u8>, FileEntry> = files.clone().into();
+    assert_eq!(map[&b"b.txt"[..]], FileEntry::from(b"hello".to_vec()));
+    assert_eq!(Files::from(map), files);
+
+    // entries' metadata is written, but bodies are always stored when slicing
+    let mut opts = ZipOptions::default();
+    opts.compression = zip::Compression::Stored;
+    let zipped = zip::zip(files.iter(), &opts).unwrap();
+    let sliceable = sliceable_zip(&files);
+    for (archive, method) in [(&zipped, 8), (&sliceable, 0)] {
+        let archive = zip::Archive::new(archive).unwrap();
+        archive.verify().unwrap();
+        let entry = archive.get(b"a.bin").unwrap();
+        assert_eq!(entry.method, method);
+        assert_eq!(entry.comment, b"a comment");
+        assert_eq!(entry.mtime(), zip::DOS_EPOCH + 86_400);
+        assert_eq!(&*entry.body().unwrap(), &body[..]);
+        assert_eq!(&*archive.get(b"b.txt").unwrap().body().unwrap(), b"hello");
+    }
+}
+
+#[test]
+fn sliceable_bodies_are_aligned() {
+    let files = Files::new()
+        .add("b.bin", sample(3000))
+        .add("mimetype", "application/example")
+        .add("a.bin", sample(5000))
+        .add("empty.txt", "")
+        .add("c.bin", sample(100));
+    let cases: [(Vec<u8>, usize, bool, [&str; 5]); 3] = [
+        (sliceable_zip(&files), zip::BLOCK_SIZE, true, [
+            "mimetype",
+            "empty.txt",
+            "a.bin",
+            "b.bin",
+            "c.bin",
+        ]),
+        (
+            sliceable_zip_with(&files, |opts| {
+                opts.alignment = 4096;
+                opts.order = zip::Given;
+            }),
+            4096,
+            true,
+            ["mimetype", "b.bin", "a.bin", "empty.txt", "c.bin"],
+        ),
+        (
+            sliceable_zip_with(&files, |opts| {
+                opts.alignment = 64;
+                opts.order = zip::Given;
+                opts.mimetype_first = false;
+            }),
+            64,
+            false,
+            ["b.bin", "mimetype", "a.bin", "empty.txt", "c.bin"],
+        ),
+    ];
+    for (archive, alignment, mimetype_first, order) in cases {
+        let parsed = zip::Archive::new(&archive).unwrap();
+        parsed.verify().unwrap();
+        let names: Vec<&[u8]> = parsed.entries().iter().map(|entry| entry.name).collect();
+        let expected: Vec<&[u8]> = order.iter().map(|name| name.as_bytes()).collect();
+        assert_eq!(names, expected);
+
+        for entry in parsed.entries() {
+            assert_eq!(entry.method, 0);
+            let start = entry.data.as_ptr() as usize - archive.as_ptr() as usize;
+            if mimetype_first && entry.name == b"mimetype" {
+                // first and unaligned, where package readers expect it
+                assert_eq!((entry.header_offset, start), (0, 38));
+            } else if !entry.data.is_empty() {
+                assert_eq!(start % alignment, 0, "{:?}", entry.name.escape_ascii());
+            }
+            let (_, file) = files.iter().find(|(name, _)| *name == entry.name).unwrap();
+            assert_eq!(&archive[start..start + entry.data.len()], &file.body[..]);
+        }
+    }
+}
+
+#[test]
+fn unix_modes_and_symlinks_round_trip() {
+    let files = Files::new()
+        .add("run.sh", "#!/bin/sh\n")
+        .with_mode(0o755)
+        .add("readme.txt", "hello")
+        .add_entry("dir", FileEntry {
+            kind: Directory,
+            ..FileEntry::default()
+        })
+        .add_symlink("link", "readme.txt");
+    for archive in [
+        zip::zip(files.iter(), &ZipOptions::default()).unwrap(),
+        zipng::zipng(&files),
+    ] {
+        let archive = zip::Archive::new(&archive).unwrap();
+        archive.verify().unwrap();
+        let mode = |name: &[u8]| archive.get(name).unwrap().unix_mode();
+        assert_eq!(mode(b"run.sh"), Some(0o100755));
+        assert_eq!(mode(b"readme.txt"), Some(0o100644));
+        assert_eq!(mode(b"dir/"), Some(0o040755));
+        assert!(archive.get(b"dir/").unwrap().is_dir());
+        let link = archive.get(b"link").unwrap();
+        assert!(link.is_symlink());
+        assert_eq!(&*link.body().unwrap(), b"readme.txt");
+    }
+
+    // and Info-ZIP agrees, if it's installed
+    let archive = zip::zip(files.iter(), &ZipOptions::default()).unwrap();
+    if let Some(output) = run_on_archive("unzip", &["-Z"], &archive) {
+        assert!(output.status.success(), "{output:?}");
+        let listing = String::from_utf8(output.stdout).unwrap();
+        for (mode, name) in [
+            ("-rwxr-xr-x", "run.sh"),
+            ("-rw-r--r--", "readme.txt"),
+            ("drwxr-xr-x", "dir/"),
+            ("lrwxrwxrwx", "link"),
+        ] {
+            assert!(
+                listing
+                    .lines()
+                    .any(|line| line.starts_with(mode) && line.ends_with(&format!(" {name}"))),
+                "{listing}"
+            );
+        }
+    }
+}
+
+#[test]
+fn files_builder_kinds_and_modes() {
+    let files = Files::new()
+        .add("a.sh", "")
+        .add("b.txt", "")
+        .add_dir("dir")
+        .add_dir("other/")
+        .with_mode(0o700)
+        .add_symlink("link", "b.txt")
+        // replacing a file keeps its position, but it's still the one whose
+        // mode is set
+        .add("a.sh", "#!/bin/sh\n")
+        .with_mode(0o755);
+    let map: IndexMap<Vec<u8>, FileEntry> = files.into();
+    let names: Vec<&[u8]> = map.keys().map(|name| &name[..]).collect();
+    let expected: [&[u8]; 5] = [b"a.sh", b"b.txt", b"dir/", b"other/", b"link"];
+    assert_eq!(names, expected);
+    assert_eq!(map[&b"a.sh"[..]].mode, Some(0o755));
+    assert_eq!(map[&b"b.txt"[..]].mode, None);
+    assert_eq!(map[&b"dir/"[..]], FileEntry {
+        kind: Directory,
+        ..FileEntry::default()
+    });
+    assert_eq!(map[&b"other/"[..]].mode, Some(0o700));
+    assert_eq!(map[&b"link"[..]], FileEntry {
+        body: b"b.txt".to_vec(),
+        kind: Symlink,
+        ..FileEntry::default()
+    });
+    assert_eq!(Files::new().with_mode(0o755), Files::new());
+}
+
+#[test]
+fn small_archives_keep_their_layout() {
+    let mut opts = ZipOptions::default();
+    opts.mtime = zip::DOS_EPOCH;
+    let files = Files::new().add("a.txt", "hello").add("empty", "");
+    let archive = zip::zip(files.iter(), &opts).unwrap();
+
+    let crc = 0x3610_A686_u32.to_le_bytes();
+    let mut expected = Vec::new();
+    // the empty file first, since it needs no alignment
+    expected.extend_from_slice(b"PK\x03\x04\x0A\x00\x00\x00\x00\x00\x00\x00\x21\x00");
+    expected.extend_from_slice(&[0; 12]);
+    expected.extend_from_slice(b"\x05\x00\x00\x00empty");
+    // then the other padded so that its body starts on a block boundary
+    expected.resize(0x400 - 35, 0);
+    expected.extend_from_slice(b"PK\x03\x04\x0A\x00\x00\x00\x00\x00\x00\x00\x21\x00");
+    expected.extend_from_slice(&crc);
+    expected.extend_from_slice(b"\x05\x00\x00\x00\x05\x00\x00\x00\x05\x00\x00\x00a.txthello");
+    // and the central directory padded so that the archive ends on one, with
+    // no ZIP64 records or extra fields
+    expected.resize(0xC00 - 124, 0);
+    expected.extend_from_slice(b"PK\x01\x02\x0A\x03\x0A\x00\x00\x00\x00\x00\x00\x00\x21\x00");
+    expected.extend_from_slice(&[0; 12]);
+    expected.extend_from_slice(b"\x05\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\xA4\x81");
+    expected.extend_from_slice(b"\x00\x00\x00\x00empty");
+    expected.extend_from_slice(b"PK\x01\x02\x0A\x03\x0A\x00\x00\x00\x00\x00\x00\x00\x21\x00");
+    expected.extend_from_slice(&crc);
+    expected.extend_from_slice(b"\x05\x00\x00\x00\x05\x00\x00\x00\x05\x00\x00\x00\x00\x00\x00\x00");
+    expected.extend_from_slice(b"\x00\x00\x00\x00\xA4\x81\xDD\x03\x00\x00a.txt");
+    expected.extend_from_slice(b"PK\x05\x06\x00\x00\x00\x00\x02\x00\x02\x00");
+    expected.extend_from_slice(b"\x66\x00\x00\x00\x84\x0B\x00\x00\x00\x00");
+    assert_eq!(archive, expected);
+}
+
+/// An archive of `count` empty files.
+fn many_files(count: usize) -> Vec<u8> {
+    let entries: Vec<(Vec<u8>, FileEntry)> = (0..count)
+        .map(|index| (format!("{index:05}").into_bytes(), FileEntry::default()))
+        .collect();
+    zip::zip(
+        entries.iter().map(|(name, entry)| (&name[..], entry)),
+        &ZipOptions::default(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn limits_are_errors() {
+    let long = vec![b'a'; 0x10000];
+    assert_eq!(
+        try_zip(&Files::new().add(long.clone(), "body")),
+        Err(Error::NameTooLong {
+            name: long.clone().into(),
+            len: 0x10000
+        })
+    );
+    assert!(try_zip(&Files::new().add(&long[..0xFFFF], "body")).is_ok());
+
+    let commented = |comment: &[u8]| {
+        Files::new().add_entry("a.txt", FileEntry {
+            comment: comment.to_vec(),
+            ..FileEntry::default()
+        })
+    };
+    assert_eq!(
+        try_zip_with(&commented(&long), |opts| opts.compression = zip::Stored),
+        Err(Error::CommentTooLong { len: 0x10000 })
+    );
+    assert!(try_zip(&commented(&long[..0xFFFF])).is_ok());
+
+    // a terminator in the archive's own comment would be found instead of
+    // the real one
+    let two_files = files(2, 100);
+    let entries: Vec<(&[u8], &FileEntry)> = two_files.iter().collect();
+    let opts = ZipOptions::default();
+    for suffix in [&b"PK\x05\x06"[..], b"...PK\x05\x06..."] {
+        assert_eq!(
+            zip::zip_with(&entries, &opts, Vec::new(), suffix),
+            Err(Error::CommentContainsTerminator)
+        );
+    }
+    assert!(zip::zip_with(&entries, &opts, Vec::new(), b"PK\x05").is_ok());
+
+    assert!(matches!(
+        chunk_length(b"IDAT", MAX_DIMENSION + 1),
+        Err(Error::ChunkTooLarge { size, .. }) if size == MAX_DIMENSION + 1
+    ));
+    assert_eq!(
+        chunk_length(b"IDAT", MAX_DIMENSION),
+        Ok(MAX_DIMENSION as u32)
+    );
+
+    assert_eq!(
+        try_png_with(&sample(100), |opts| opts.width = MAX_DIMENSION + 1),
+        Err(Error::ImageTooWide {
+            width: MAX_DIMENSION + 1
+        })
+    );
+    assert!(matches!(
+        try_zipng_with(&files(10, 5000), |opts| opts.png.max_height = 1),
+        Err(Error::ImageTooTall { max_height: 1, .. })
+    ));
+    // each file's record has to fit in a scanline of a stored block
+    assert!(matches!(
+        try_zipng(&files(1, 0x10000)),
+        Err(Error::ScanlineTooLong { .. })
+    ));
+    assert_eq!(
+        try_zipng_with(&files(1, 100), |opts| opts.png.width = 0x10000),
+        Err(Error::ScanlineTooLong { len: 0x10000 })
+    );
+}
+
+#[test]
+fn zip64_only_for_many_files() {
+    let archive = many_files(65_534);
+    let terminator = &archive[archive.len() - 22..];
+    assert_eq!(&terminator[..4], b"PK\x05\x06");
+    assert_eq!(&terminator[8..12], &[0xFE, 0xFF, 0xFE, 0xFF]);
+    assert_eq!(&archive[archive.len() - 42..][..4], &[0; 4]);
+    assert_eq!(zip::Archive::new(&archive).unwrap().entries().len(), 65_534);
+
+    // a count of 0xFFFF means the real count is in the ZIP64 terminator
+    let archive = many_files(65_535);
+    let terminator = &archive[archive.len() - 22..];
+    assert_eq!(&terminator[8..12], &[0xFF; 4]);
+    let locator = &archive[archive.len() - 42..][..20];
+    assert_eq!(&locator[..4], b"PK\x06\x07");
+    let zip64_offset = u64::from_le_bytes(locator[8..16].try_into().unwrap()) as usize;
+    let zip64_terminator = &archive[zip64_offset..][..56];
+    assert_eq!(&zip64_terminator[..4], b"PK\x06\x06");
+    // entries on this disk and in total
+    assert_eq!(&zip64_terminator[0x18..0x20], &65_535_u64.to_le_bytes());
+    assert_eq!(&zip64_terminator[0x20..0x28], &65_535_u64.to_le_bytes());
+
+    let parsed = zip::Archive::new(&archive).unwrap();
+    parsed.verify().unwrap();
+    assert_eq!(parsed.entries().len(), 65_535);
+    assert_eq!(parsed.entries()[65_534].name, b"65534");
+
+    let script = "import sys, zipfile
+archive = zipfile.ZipFile(sys.argv[1])
+assert archive.testzip() is None
+print(len(archive.infolist()), archive.infolist()[-1].filename)";
+    if let Some(output) = run_on_archive("python3", &["-c", script], &archive) {
+        assert!(output.status.success(), "{output:?}");
+        assert_eq!(output.stdout, b"65535 65534\n");
+    }
+    if let Some(output) = run_on_archive("unzip", &["-tqq"], &archive) {
+        assert!(output.status.success(), "{output:?}");
+    }
+}
+
+#[test]
+fn mimetype_is_stored_first() {
+    let mut opts = ZipOptions::default();
+    opts.compression = zip::Compression::Deflated;
+    let files = files(2, 1000);
+    let archive = zip::zip(files.iter(), &opts).unwrap();
+    let archive = zip::Archive::new(&archive).unwrap();
+    let mimetype = &archive.entries()[0];
+    assert_eq!(mimetype.name, b"mimetype");
+    assert_eq!(mimetype.header_offset, 0);
+    assert_eq!(mimetype.method, 0);
+    assert_eq!(mimetype.data, b"application/example");
+    assert_eq!(archive.get(b"dir/file-1.bin").unwrap().method, 8);
+
+    let mut writer = zip::ZipWriter::new(Vec::new(), opts);
+    writer
+        .start_file(b"mimetype", &FileEntry::default())
+        .unwrap();
+    writer.write_all(b"application/example").unwrap();
+    let archive = writer.finish(b"").unwrap();
+    let archive = zip::Archive::new(&archive).unwrap();
+    assert_eq!(archive.entries()[0].method, 0);
+    assert_eq!(archive.entries()[0].data, b"application/example");
+}
+
+#[test]
+fn zip_writer_streams_files() {
+    let body: Vec<u8> = (0..600_000_u32)
+        .map(|i| ((i % 251) ^ (i >> 10)) as u8)
+        .collect();
+    let mut writer = zip::ZipWriter::new(Vec::new(), ZipOptions::default());
+    writer
+        .start_file(b"streamed", &FileEntry::default())
+        .unwrap();
+    for chunk in body.chunks(10_000) {
+        writer.write_all(chunk).unwrap();
+    }
+    writer
+        .add(b"whole", &FileEntry {
+            body: b"hello".to_vec(),
+            ..FileEntry::default()
+        })
+        .unwrap();
+    let archive = writer.finish(b"").unwrap();
+
+    let archive = zip::Archive::new(&archive).unwrap();
+    archive.verify().unwrap();
+    assert_eq!(
+        &*archive.get(b"streamed").unwrap().body().unwrap(),
+        &body[..]
+    );
+    assert_eq!(&*archive.get(b"whole").unwrap().body().unwrap(), b"hello");
+}
+
+#[test]
+fn zip_writer_streamed_headers_are_zip64() {
+    let mut opts = ZipOptions::default();
+    opts.extended_timestamps = false;
+    let mut writer = zip::ZipWriter::new(Vec::new(), opts);
+    writer
+        .start_file(b"streamed", &FileEntry {
+            compression: Some(zip::Compression::Stored),
+            ..FileEntry::default()
+        })
+        .unwrap();
+    writer.write_all(b"hello").unwrap();
+    let archive = writer.finish(b"").unwrap();
+
+    let local = archive
+        .windows(4)
+        .position(|window| window == b"PK\x03\x04")
+        .unwrap();
+    let header = &archive[local..];
+    let u16_at = |offset: usize| u16::from_le_bytes(header[offset..offset + 2].try_into().unwrap());
+    let u32_at = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    // version needed, flags and method
+    assert_eq!(u16_at(0x04), 45);
+    assert_eq!(u16_at(0x06), 1 << 3);
+    assert_eq!(u16_at(0x08), 0);
+    // checksum is left for the data descriptor, and sizes for the ZIP64 field
+    assert_eq!(&header[0x0E..0x12], &[0; 4]);
+    assert_eq!(&header[0x12..0x1A], &[0xFF; 8]);
+    assert_eq!(u16_at(0x1A), 8);
+    assert_eq!(u16_at(0x1C), 20);
+    assert_eq!(&header[0x1E..0x26], b"streamed");
+    // ZIP64 extended information with both sizes zeroed
+    assert_eq!(&header[0x26..0x2A], &[0x01, 0x00, 0x10, 0x00]);
+    assert_eq!(&header[0x2A..0x3A], &[0; 16]);
+    assert_eq!(&header[0x3A..0x3F], b"hello");
+
+    // a data descriptor with 8-byte sizes
+    let descriptor = &header[0x3F..];
+    assert_eq!(&descriptor[0x00..0x04], b"PK\x07\x08");
+    assert_eq!(u32_at(0x3F + 0x04), 0x3610_A686);
+    assert_eq!(&descriptor[0x08..0x18], &[
+        5, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0
+    ]);
+
+    let archive = zip::Archive::new(&archive).unwrap();
+    archive.verify().unwrap();
+    assert_eq!(
+        &*archive.get(b"streamed").unwrap().body().unwrap(),
+        b"hello"
+    );
+    // the central header needs the same version as the local header
+    assert_eq!(archive.get(b"streamed").unwrap().version_needed, 45);
+}
+
+#[cfg(unix)]
+#[test]
+fn from_dir_walks_tree() {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = std::env::temp_dir().join(format!("zipng-from-dir-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("src/deep")).unwrap();
+    fs::create_dir_all(root.join("target")).unwrap();
+    fs::write(root.join(".gitignore"), "*.log\n!keep.log\n/target/\n").unwrap();
+    fs::write(root.join("src/.gitignore"), "deep/*.tmp\n").unwrap();
+    fs::write(root.join("src/main.rs"), "fn main() {}\n").unwrap();
+    fs::write(root.join("src/deep/a.log"), "").unwrap();
+    fs::write(root.join("src/deep/keep.log"), "kept").unwrap();
+    fs::write(root.join("src/deep/b.tmp"), "").unwrap();
+    fs::write(root.join("target/out"), "").unwrap();
+    fs::write(root.join("notes.txt"), "").unwrap();
+    fs::write(root.join("run.sh"), "#!/bin/sh\n").unwrap();
+    fs::set_permissions(root.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+    // "é" decomposed, as macOS file systems store it
+    fs::write(root.join("cafe\u{301}"), "").unwrap();
+    std::os::unix::fs::symlink("src/main.rs", root.join("main.rs")).unwrap();
+    std::os::unix::fs::symlink("..", root.join("src/up")).unwrap();
+
+    let mut opts = DirOptions::default();
+    opts.gitignore = true;
+    opts.exclude = vec!["notes.txt".into()];
+    let files = Files::from_dir(&root, &opts).unwrap();
+    let names: Vec<_> = files.iter().map(|(name, _)| name.to_vec()).collect();
+    let expected: [&[u8]; 10] = [
+        "caf\u{e9}".as_bytes(),
+        b"src/",
+        b"src/deep/",
+        b".gitignore",
+        b"main.rs",
+        b"run.sh",
+        b"src/.gitignore",
+        b"src/main.rs",
+        b"src/up",
+        b"src/deep/keep.log",
+    ];
+    assert_eq!(names, expected);
+    let entry = |name: &[u8]| files.iter().find(|(n, _)| *n == name).unwrap().1.clone();
+    assert_eq!(entry(b"run.sh").mode, Some(0o755));
+    assert_eq!(entry(b"main.rs").kind, Symlink);
+    assert_eq!(entry(b"main.rs").body, b"src/main.rs");
+
+    opts.follow_symlinks = true;
+    opts.directories = false;
+    let files = Files::from_dir(&root, &opts).unwrap();
+    let entry = files
+        .iter()
+        .find(|(name, _)| *name == b"main.rs")
+        .unwrap()
+        .1;
+    assert_eq!(
+        (entry.kind, &entry.body[..]),
+        (File, &b"fn main() {}\n"[..])
+    );
+    assert!(files.iter().all(|(name, _)| !name.starts_with(b"src/up")));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn cli_creates_and_extracts() {
+    use std::process::Command;
+    use std::process::Stdio;
+
+    let dir = std::env::temp_dir().join(format!("zipng-cli-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("in/sub")).unwrap();
+    std::fs::write(dir.join("in/sub/a.txt"), sample(3000)).unwrap();
+    let zipng = |args: &[&str], stdin: &[u8]| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_zipng"))
+            .args(args)
+            .current_dir(&dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(stdin).unwrap();
+        child.wait_with_output().unwrap()
+    };
+
+    let output = zipng(
+        &[
+            "zipng",
+            "in",
+            "-",
+            "--stdin-name",
+            "b.txt",
+            "--width",
+            "4096",
+        ],
+        b"hi",
+    );
+    assert!(output.status.success());
+    let png = output.stdout;
+    assert_eq!(decode(&png).0.width, 4096);
+    assert!(zipng(&["verify", "-"], &png).status.success());
+
+    let listing = String::from_utf8(zipng(&["list", "-"], &png).stdout).unwrap();
+    assert!(listing.contains(" in/sub/a.txt\n"), "{listing}");
+
+    assert!(zipng(&["extract", "-", "-d", "out"], &png).status.success());
+    assert_eq!(
+        std::fs::read(dir.join("out/in/sub/a.txt")).unwrap(),
+        sample(3000)
+    );
+    assert_eq!(std::fs::read(dir.join("out/b.txt")).unwrap(), b"hi");
+
+    assert_eq!(
+        zipng(&["zip", "--level", "10", "in"], b"").status.code(),
+        Some(2)
+    );
+    assert!(!zipng(&["verify", "-"], b"not an archive").status.success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn cli_refuses_hostile_links() {
+    use std::process::Command;
+    use std::process::Stdio;
+
+    let dir = std::env::temp_dir().join(format!("zipng-links-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("out")).unwrap();
+    let extract = |files: Files| {
+        let archive = zip::zip(files.iter(), &ZipOptions::default()).unwrap();
+        let mut child = Command::new(env!("CARGO_BIN_EXE_zipng"))
+            .args(["extract", "-", "-d", "out"])
+            .current_dir(&dir)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&archive).unwrap();
+        child.wait().unwrap().success()
+    };
+
+    // links may point anywhere inside the output directory
+    assert!(extract(
+        Files::new()
+            .add("a/b.txt", "hello")
+            .add_symlink("a/c/up", "../../a/./b.txt")
+    ));
+    assert_eq!(std::fs::read(dir.join("out/a/c/up")).unwrap(), b"hello");
+
+    for target in ["../escaped", "/etc", "a/../../escaped", "..", ""] {
+        assert!(
+            !extract(Files::new().add_symlink("link", target)),
+            "{target}"
+        );
+        assert!(std::fs::symlink_metadata(dir.join("out/link")).is_err());
+    }
+
+    // a parent component after a link could climb out of the directory it
+    // points to, even when it looks contained
+    assert!(!extract(
+        Files::new()
+            .add_symlink("here", ".")
+            .add_symlink("a/there", "../here/..")
+    ));
+
+    // existing links aren't written through, whether to a file or a directory
+    std::fs::create_dir_all(dir.join("elsewhere")).unwrap();
+    std::os::unix::fs::symlink("../elsewhere", dir.join("out/sub")).unwrap();
+    std::os::unix::fs::symlink("../elsewhere/file", dir.join("out/file")).unwrap();
+    assert!(!extract(Files::new().add("sub/file", "escaped")));
+    assert!(!extract(Files::new().add("file", "escaped")));
+    assert!(!extract(Files::new().add("sub/dir/", "")));
+    assert_eq!(std::fs::read_dir(dir.join("elsewhere")).unwrap().count(), 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+}